@@ -0,0 +1,356 @@
+use std::path::{Component, PathBuf};
+
+use reqwest::{Client, Method};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::{
+    fs,
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    error::{Error, Result},
+    hasher::bytes_to_hex,
+    parser::Parser,
+    session::{AddTorrentOptions, Session},
+    storage::FileSelection,
+};
+
+pub const DEFAULT_API_PORT: u16 = 6880;
+const MAX_BODY_LENGTH: usize = 1 << 20;
+
+#[derive(Debug, Deserialize)]
+struct AddRequest {
+    /// Path to a .torrent file on the daemon's host.
+    torrent: Option<String>,
+    magnet: Option<String>,
+    output: Option<PathBuf>,
+    #[serde(default)]
+    paused: bool,
+    #[serde(default)]
+    sequential: bool,
+    #[serde(flatten)]
+    files: FileSelection,
+}
+
+#[derive(Debug, Deserialize)]
+struct LimitsRequest {
+    /// Bytes per second; missing means unlimited.
+    download: Option<u64>,
+    upload: Option<u64>,
+    /// Limit one torrent instead of the whole session.
+    info_hash: Option<String>,
+}
+
+struct ApiError {
+    status: u16,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: u16, message: impl ToString) -> Self {
+        ApiError {
+            status,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<Error> for ApiError {
+    fn from(e: Error) -> Self {
+        let status = match e {
+            Error::UnknownTorrent(_) => 404,
+            Error::DuplicateTorrent(_) => 409,
+            _ => 400,
+        };
+        ApiError::new(status, e)
+    }
+}
+
+/// Serves the JSON control API for `session` on `listener`. Torrents are
+/// written inside the session's download directory: `output` is relative to
+/// it and defaults to the torrent's name.
+///
+/// | Method   | Path                          | Body                                        |
+/// |----------|-------------------------------|---------------------------------------------|
+/// | `GET`    | `/api/torrents`               |                                             |
+/// | `POST`   | `/api/torrents`               | `{"torrent"\|"magnet", "output"?, "paused"?}` |
+/// | `POST`   | `/api/torrents/{hash}/pause`  |                                             |
+/// | `POST`   | `/api/torrents/{hash}/resume` |                                             |
+/// | `DELETE` | `/api/torrents/{hash}`        |                                             |
+/// | `GET`    | `/api/stats`                  |                                             |
+/// | `PUT`    | `/api/limits`                 | `{"download"?, "upload"?, "info_hash"?}`    |
+///
+/// Adding a torrent also takes `"sequential"` and the glob lists of
+/// [`FileSelection`]: `only`, `exclude`, `high` and `low`.
+pub async fn serve(session: Session, listener: TcpListener) {
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+        let session = session.clone();
+        tokio::spawn(async move {
+            // Bad requests are answered with an error; a client that hung up
+            // has nobody left to tell
+            let _ = handle_connection(stream, &session).await;
+        });
+    }
+}
+
+async fn handle_connection(stream: TcpStream, session: &Session) -> Result<()> {
+    let mut stream = BufReader::new(stream);
+
+    let result = match read_request(&mut stream).await {
+        Ok((method, path, body)) => route(session, &method, &path, &body).await,
+        Err(e) => Err(e.into()),
+    };
+    let (status, response) = match result {
+        Ok(response) => (200, response),
+        Err(e) => (e.status, json!({ "error": e.message })),
+    };
+    let response = response.to_string();
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        409 => "Conflict",
+        _ => "Error",
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        reason,
+        response.len()
+    );
+    let stream = stream.get_mut();
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Reads the method, path and body of one request.
+async fn read_request(stream: &mut BufReader<TcpStream>) -> Result<(String, String, Vec<u8>)> {
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).await?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err(Error::Api("Malformed request line".into()));
+    };
+    let (method, path) = (method.to_string(), path.to_string());
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        stream.read_line(&mut header).await?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| Error::Api("Bad Content-Length".into()))?;
+            }
+        }
+    }
+    if content_length > MAX_BODY_LENGTH {
+        return Err(Error::Api("Request body is too large".into()));
+    }
+    let mut body = vec![0; content_length];
+    stream.read_exact(&mut body).await?;
+    Ok((method, path, body))
+}
+
+async fn route(
+    session: &Session,
+    method: &str,
+    path: &str,
+    body: &[u8],
+) -> Result<Value, ApiError> {
+    let segments = path
+        .strip_prefix("/api/")
+        .map(|route| route.trim_end_matches('/').split('/').collect::<Vec<_>>())
+        .unwrap_or_default();
+    match (method, segments.as_slice()) {
+        ("GET", ["torrents"]) => Ok(json!(session.torrents())),
+        ("POST", ["torrents"]) => {
+            let request = parse_body::<AddRequest>(body)?;
+            let output = request.output.unwrap_or_default();
+            if !output
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+            {
+                return Err(ApiError::new(
+                    400,
+                    format!("{} is not inside the download directory", output.display()),
+                ));
+            }
+            let torrent_file = match (&request.torrent, &request.magnet) {
+                (Some(path), None) => {
+                    let contents = fs::read(path)
+                        .await
+                        .map_err(|e| ApiError::new(400, format!("Can't read {}: {}", path, e)))?;
+                    Parser::parse_torrent_file(&contents)?
+                }
+                (None, Some(magnet_link)) => session.resolve_magnet(magnet_link).await?,
+                _ => {
+                    return Err(ApiError::new(400, "Give exactly one of torrent or magnet"));
+                }
+            };
+            let file_priorities = request.files.priorities(&torrent_file.info)?;
+            let info_hash = session.add_torrent(
+                torrent_file,
+                AddTorrentOptions {
+                    output,
+                    start_paused: request.paused,
+                    sequential: request.sequential,
+                    file_priorities,
+                    ..Default::default()
+                },
+            )?;
+            Ok(json!({ "info_hash": bytes_to_hex(&info_hash) }))
+        }
+        ("POST", ["torrents", info_hash, action]) => {
+            let info_hash = find_torrent(session, info_hash)?;
+            match *action {
+                "pause" => session.pause(&info_hash)?,
+                "resume" => session.resume(&info_hash)?,
+                _ => return Err(ApiError::new(404, "Unknown action")),
+            }
+            Ok(json!({}))
+        }
+        ("DELETE", ["torrents", info_hash]) => {
+            let info_hash = find_torrent(session, info_hash)?;
+            session.remove(&info_hash)?;
+            Ok(json!({}))
+        }
+        ("GET", ["stats"]) => Ok(json!(session.stats())),
+        ("PUT", ["limits"]) => {
+            let request = parse_body::<LimitsRequest>(body)?;
+            match &request.info_hash {
+                Some(info_hash) => {
+                    let info_hash = find_torrent(session, info_hash)?;
+                    session.set_torrent_limits(&info_hash, request.download, request.upload)?;
+                }
+                None => session.set_global_limits(request.download, request.upload),
+            }
+            Ok(json!({}))
+        }
+        _ => Err(ApiError::new(
+            404,
+            format!("No route for {} {}", method, path),
+        )),
+    }
+}
+
+fn parse_body<'a, T: Deserialize<'a>>(body: &'a [u8]) -> Result<T, ApiError> {
+    serde_json::from_slice(body).map_err(|e| ApiError::new(400, e))
+}
+
+fn find_torrent(session: &Session, info_hash: &str) -> Result<[u8; 20], ApiError> {
+    let info_hash = hex::decode(info_hash)
+        .ok()
+        .and_then(|info_hash| <[u8; 20]>::try_from(info_hash).ok())
+        .ok_or_else(|| ApiError::new(400, "Invalid info hash"))?;
+    if !session.contains(&info_hash) {
+        return Err(Error::UnknownTorrent(hex::encode(info_hash)).into());
+    }
+    Ok(info_hash)
+}
+
+/// Talks to a daemon's control API.
+pub struct ApiClient {
+    base_url: String,
+    client: Client,
+}
+
+impl ApiClient {
+    pub fn new(base_url: &str) -> Self {
+        ApiClient {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            client: Client::new(),
+        }
+    }
+
+    pub async fn request(&self, method: Method, path: &str, body: Option<Value>) -> Result<Value> {
+        let mut request = self
+            .client
+            .request(method, format!("{}/api/{}", self.base_url, path));
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        let unreachable = |e| Error::Api(format!("Daemon at {} unreachable: {}", self.base_url, e));
+        let response = request.send().await.map_err(unreachable)?;
+        let status = response.status();
+        let value = response.json::<Value>().await.map_err(unreachable)?;
+        if !status.is_success() {
+            let message = value["error"].as_str().unwrap_or("unknown error");
+            return Err(Error::Api(format!("{} ({})", message, status)));
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::SessionConfig;
+
+    #[tokio::test]
+    async fn test_api_roundtrip() {
+        let session = Session::new(SessionConfig {
+            listen_port: 0,
+            local_discovery: false,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(session, listener));
+        let client = ApiClient::new(&format!("http://{}", address));
+
+        let limits = json!({ "download": 2048, "upload": 1024 });
+        client
+            .request(Method::PUT, "limits", Some(limits))
+            .await
+            .unwrap();
+        let stats = client.request(Method::GET, "stats", None).await.unwrap();
+        assert_eq!(stats["torrents"], 0);
+        assert_eq!(stats["download_limit"], 2048);
+        assert_eq!(stats["upload_limit"], 1024);
+
+        let torrents = client.request(Method::GET, "torrents", None).await.unwrap();
+        assert_eq!(torrents, json!([]));
+
+        let missing = "00".repeat(20);
+        let error = client
+            .request(Method::POST, &format!("torrents/{}/pause", missing), None)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("No torrent"));
+
+        for output in ["../escaped", "/tmp/escaped"] {
+            let add = json!({ "torrent": "missing.torrent", "output": output });
+            let error = client
+                .request(Method::POST, "torrents", Some(add))
+                .await
+                .unwrap_err();
+            assert!(error
+                .to_string()
+                .contains("not inside the download directory"));
+        }
+
+        // Requests that don't parse still get an answer
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(b"nonsense\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+        assert!(response.contains("Malformed request line"));
+    }
+}
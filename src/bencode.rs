@@ -0,0 +1,443 @@
+use std::{collections::BTreeMap, ops::Range};
+
+use thiserror::Error;
+
+pub const DEFAULT_MAX_DEPTH: usize = 128;
+
+pub type Spans = BTreeMap<Vec<u8>, Range<usize>>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BencodeValue {
+    Bytes(Vec<u8>),
+    Integer(i64),
+    List(Vec<BencodeValue>),
+    Dict(BTreeMap<Vec<u8>, BencodeValue>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("{kind} at byte {position}")]
+pub struct BencodeError {
+    pub position: usize,
+    pub kind: BencodeErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum BencodeErrorKind {
+    #[error("unexpected end of input")]
+    UnexpectedEof,
+    #[error("unexpected byte {0:#04x}")]
+    UnexpectedByte(u8),
+    #[error("invalid integer")]
+    InvalidInteger,
+    #[error("invalid string length")]
+    InvalidLength,
+    #[error("duplicate dictionary key")]
+    DuplicateKey,
+    #[error("nesting deeper than {0} levels")]
+    TooDeep(usize),
+    #[error("trailing data after value")]
+    TrailingData,
+}
+
+/// Decodes exactly one value spanning the whole of `input`.
+pub fn decode(input: &[u8]) -> Result<BencodeValue, BencodeError> {
+    let mut decoder = Decoder::new(input);
+    let value = decoder.decode_value()?;
+    decoder.finish()?;
+    Ok(value)
+}
+
+/// Like [`decode`] for a top-level dictionary, but also returns the exact byte
+/// span each value occupied in `input`. Used where the raw encoding matters,
+/// such as hashing the metainfo `info` dictionary.
+pub fn decode_dict_with_spans(input: &[u8]) -> Result<(BencodeValue, Spans), BencodeError> {
+    let mut decoder = Decoder::new(input);
+    let byte = decoder.peek()?;
+    if byte != b'd' {
+        return Err(decoder.error(BencodeErrorKind::UnexpectedByte(byte)));
+    }
+    let mut spans = BTreeMap::new();
+    let value = decoder.dict(0, Some(&mut spans))?;
+    decoder.finish()?;
+    Ok((value, spans))
+}
+
+pub fn encode(value: &BencodeValue) -> Vec<u8> {
+    let mut out = Vec::new();
+    value.encode_to(&mut out);
+    out
+}
+
+/// Single-pass bencode decoder over a byte slice.
+pub struct Decoder<'a> {
+    input: &'a [u8],
+    position: usize,
+    max_depth: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(input: &'a [u8]) -> Self {
+        Decoder {
+            input,
+            position: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn decode_value(&mut self) -> Result<BencodeValue, BencodeError> {
+        self.value(0)
+    }
+
+    /// Fails unless the whole input has been consumed.
+    pub fn finish(&self) -> Result<(), BencodeError> {
+        if self.position < self.input.len() {
+            return Err(self.error(BencodeErrorKind::TrailingData));
+        }
+        Ok(())
+    }
+
+    fn value(&mut self, depth: usize) -> Result<BencodeValue, BencodeError> {
+        match self.peek()? {
+            b'0'..=b'9' => self
+                .bytes()
+                .map(|bytes| BencodeValue::Bytes(bytes.to_vec())),
+            b'i' => self.integer().map(BencodeValue::Integer),
+            b'l' => {
+                let depth = self.descend(depth)?;
+                self.position += 1;
+                let mut list = Vec::new();
+                while self.peek()? != b'e' {
+                    list.push(self.value(depth)?);
+                }
+                self.position += 1;
+                Ok(BencodeValue::List(list))
+            }
+            b'd' => self.dict(depth, None),
+            byte => Err(self.error(BencodeErrorKind::UnexpectedByte(byte))),
+        }
+    }
+
+    fn dict(
+        &mut self,
+        depth: usize,
+        mut spans: Option<&mut Spans>,
+    ) -> Result<BencodeValue, BencodeError> {
+        let depth = self.descend(depth)?;
+        self.position += 1;
+        let mut dict = BTreeMap::new();
+        while self.peek()? != b'e' {
+            let key_position = self.position;
+            if !self.peek()?.is_ascii_digit() {
+                return Err(self.error(BencodeErrorKind::UnexpectedByte(self.peek()?)));
+            }
+            let key = self.bytes()?.to_vec();
+            let value_start = self.position;
+            let value = self.value(depth)?;
+            if let Some(spans) = spans.as_deref_mut() {
+                spans.insert(key.clone(), value_start..self.position);
+            }
+            if dict.insert(key, value).is_some() {
+                return Err(BencodeError {
+                    position: key_position,
+                    kind: BencodeErrorKind::DuplicateKey,
+                });
+            }
+        }
+        self.position += 1;
+        Ok(BencodeValue::Dict(dict))
+    }
+
+    fn descend(&self, depth: usize) -> Result<usize, BencodeError> {
+        if depth >= self.max_depth {
+            return Err(self.error(BencodeErrorKind::TooDeep(self.max_depth)));
+        }
+        Ok(depth + 1)
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], BencodeError> {
+        let start = self.position;
+        let digits = self.take_until(b':')?;
+        let length = parse_length(digits).ok_or(BencodeError {
+            position: start,
+            kind: BencodeErrorKind::InvalidLength,
+        })?;
+
+        let data_start = self.position;
+        if self.input.len() - data_start < length {
+            return Err(BencodeError {
+                position: self.input.len(),
+                kind: BencodeErrorKind::UnexpectedEof,
+            });
+        }
+        self.position += length;
+        Ok(&self.input[data_start..self.position])
+    }
+
+    fn integer(&mut self) -> Result<i64, BencodeError> {
+        let start = self.position;
+        self.position += 1;
+        let digits = self.take_until(b'e')?;
+        parse_integer(digits).ok_or(BencodeError {
+            position: start,
+            kind: BencodeErrorKind::InvalidInteger,
+        })
+    }
+
+    /// Returns the bytes before the next `delimiter` and moves past it.
+    fn take_until(&mut self, delimiter: u8) -> Result<&'a [u8], BencodeError> {
+        let start = self.position;
+        let offset = self.input[start..]
+            .iter()
+            .position(|&byte| byte == delimiter)
+            .ok_or(BencodeError {
+                position: self.input.len(),
+                kind: BencodeErrorKind::UnexpectedEof,
+            })?;
+        self.position = start + offset + 1;
+        Ok(&self.input[start..start + offset])
+    }
+
+    fn peek(&self) -> Result<u8, BencodeError> {
+        self.input
+            .get(self.position)
+            .copied()
+            .ok_or(self.error(BencodeErrorKind::UnexpectedEof))
+    }
+
+    fn error(&self, kind: BencodeErrorKind) -> BencodeError {
+        BencodeError {
+            position: self.position,
+            kind,
+        }
+    }
+}
+
+fn parse_length(digits: &[u8]) -> Option<usize> {
+    if digits.is_empty() || (digits.len() > 1 && digits[0] == b'0') {
+        return None;
+    }
+    digits.iter().try_fold(0usize, |acc, &byte| {
+        if !byte.is_ascii_digit() {
+            return None;
+        }
+        acc.checked_mul(10)?.checked_add((byte - b'0') as usize)
+    })
+}
+
+fn parse_integer(digits: &[u8]) -> Option<i64> {
+    let unsigned = digits.strip_prefix(b"-").unwrap_or(digits);
+    let malformed = unsigned.is_empty()
+        || !unsigned.iter().all(u8::is_ascii_digit)
+        || (unsigned.len() > 1 && unsigned[0] == b'0')
+        || (unsigned == b"0" && unsigned.len() != digits.len());
+    if malformed {
+        return None;
+    }
+    std::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+impl BencodeValue {
+    pub fn encode_to(&self, out: &mut Vec<u8>) {
+        match self {
+            BencodeValue::Bytes(bytes) => encode_bytes(bytes, out),
+            BencodeValue::Integer(integer) => {
+                out.push(b'i');
+                out.extend_from_slice(integer.to_string().as_bytes());
+                out.push(b'e');
+            }
+            BencodeValue::List(list) => {
+                out.push(b'l');
+                for value in list {
+                    value.encode_to(out);
+                }
+                out.push(b'e');
+            }
+            BencodeValue::Dict(dict) => {
+                out.push(b'd');
+                for (key, value) in dict {
+                    encode_bytes(key, out);
+                    value.encode_to(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&BencodeValue> {
+        self.as_dict()?.get(key.as_bytes())
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            BencodeValue::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        std::str::from_utf8(self.as_bytes()?).ok()
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            BencodeValue::Integer(integer) => Some(*integer),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[BencodeValue]> {
+        match self {
+            BencodeValue::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<Vec<u8>, BencodeValue>> {
+        match self {
+            BencodeValue::Dict(dict) => Some(dict),
+            _ => None,
+        }
+    }
+
+    /// JSON view used by the `decode` command; byte strings are shown as (lossy) UTF-8.
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            BencodeValue::Bytes(bytes) => {
+                serde_json::Value::String(String::from_utf8_lossy(bytes).into_owned())
+            }
+            BencodeValue::Integer(integer) => serde_json::Value::Number((*integer).into()),
+            BencodeValue::List(list) => {
+                serde_json::Value::Array(list.iter().map(BencodeValue::to_json).collect())
+            }
+            BencodeValue::Dict(dict) => serde_json::Value::Object(
+                dict.iter()
+                    .map(|(key, value)| {
+                        (String::from_utf8_lossy(key).into_owned(), value.to_json())
+                    })
+                    .collect(),
+            ),
+        }
+    }
+}
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(bytes.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend_from_slice(bytes);
+}
+
+impl From<&str> for BencodeValue {
+    fn from(value: &str) -> Self {
+        BencodeValue::Bytes(value.as_bytes().to_vec())
+    }
+}
+
+impl From<String> for BencodeValue {
+    fn from(value: String) -> Self {
+        BencodeValue::Bytes(value.into_bytes())
+    }
+}
+
+impl From<Vec<u8>> for BencodeValue {
+    fn from(value: Vec<u8>) -> Self {
+        BencodeValue::Bytes(value)
+    }
+}
+
+impl From<i64> for BencodeValue {
+    fn from(value: i64) -> Self {
+        BencodeValue::Integer(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_kind(input: &[u8]) -> (usize, BencodeErrorKind) {
+        let error = decode(input).unwrap_err();
+        (error.position, error.kind)
+    }
+
+    #[test]
+    fn test_decode_round_trip() {
+        let input = b"d3:bar4:spam3:fooi42e4:listl1:ai-3eee";
+
+        let value = decode(input).unwrap();
+
+        assert_eq!(
+            value.get("foo").and_then(BencodeValue::as_integer),
+            Some(42)
+        );
+        assert_eq!(
+            value.get("bar").and_then(BencodeValue::as_str),
+            Some("spam")
+        );
+        assert_eq!(encode(&value), input);
+    }
+
+    #[test]
+    fn test_decode_binary_strings() {
+        let value = decode(b"4:\xff\x00\x01\x02").unwrap();
+
+        assert_eq!(value, BencodeValue::Bytes(vec![0xff, 0x00, 0x01, 0x02]));
+    }
+
+    #[test]
+    fn test_decode_errors_report_position() {
+        assert_eq!(error_kind(b"5:abc"), (5, BencodeErrorKind::UnexpectedEof));
+        assert_eq!(error_kind(b"i03e"), (0, BencodeErrorKind::InvalidInteger));
+        assert_eq!(error_kind(b"i-0e"), (0, BencodeErrorKind::InvalidInteger));
+        assert_eq!(
+            error_kind(b"l1:ax"),
+            (4, BencodeErrorKind::UnexpectedByte(b'x'))
+        );
+        assert_eq!(error_kind(b"i1ei2e"), (3, BencodeErrorKind::TrailingData));
+        assert_eq!(
+            error_kind(b"d1:ai1e1:ai2ee"),
+            (7, BencodeErrorKind::DuplicateKey)
+        );
+        assert_eq!(
+            error_kind(b"99999999999999999999999:a"),
+            (0, BencodeErrorKind::InvalidLength)
+        );
+    }
+
+    #[test]
+    fn test_decode_dict_with_spans() {
+        let input = b"d8:announce3:url4:infod4:name1:a7:privatei1eee";
+
+        let (value, spans) = decode_dict_with_spans(input).unwrap();
+
+        assert_eq!(
+            &input[spans[&b"info".to_vec()].clone()],
+            b"d4:name1:a7:privatei1ee"
+        );
+        assert_eq!(
+            value.get("announce").and_then(BencodeValue::as_str),
+            Some("url")
+        );
+    }
+
+    #[test]
+    fn test_decode_depth_limit() {
+        let nested = [vec![b'l'; 200], vec![b'e'; 200]].concat();
+
+        assert_eq!(
+            error_kind(&nested),
+            (
+                DEFAULT_MAX_DEPTH,
+                BencodeErrorKind::TooDeep(DEFAULT_MAX_DEPTH)
+            )
+        );
+    }
+}
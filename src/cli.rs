@@ -1,50 +1,44 @@
 use std::{
-    cmp::min,
-    collections::HashMap,
     env,
-    fmt::format,
-    io::Read,
-    net::{Ipv4Addr, SocketAddrV4},
-    path::Path,
-    sync::{Arc, Mutex},
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
 };
 
+use anyhow::anyhow;
 use clap::{Parser, Subcommand};
-use reqwest::Client;
-use tokio::{
-    fs,
-    sync::{mpsc, Semaphore},
-    task::JoinSet,
-};
-
-use crate::{
-    hasher::{bytes_to_hex, hash_bytes, hash_bytes_and_hex},
+use reqwest::Method;
+use serde_json::json;
+use tokio::{fs, net::TcpListener, sync::broadcast::error::RecvError};
+
+use crate::progress::ProgressView;
+
+use codecrafters_bittorrent::{
+    api::{self, ApiClient, DEFAULT_API_PORT},
+    bencode,
+    create::{create_torrent, CreateOptions},
+    events::Event,
+    hasher::bytes_to_hex,
+    mse::EncryptionPolicy,
     parser::TorrentFile,
-    request::TrackerResponse,
-    tcp::{PeerConnection, PeerManager, PeerMessage},
-    util::{decode_bencoded_value, decode_magnet_link},
-    CHUNKSIZE,
+    request::TrackerClient,
+    session::{AddTorrentOptions, Session},
+    settings::Settings,
+    storage::{wanted_pieces, FileSelection},
+    streaming,
+    summary::TorrentSummary,
+    tcp::{Capabilities, PeerConnection},
+    transport::TransportMode,
+    util::decode_magnet_link,
 };
 
-#[derive(Debug)]
-struct Peer {
-    peer_id: (Ipv4Addr, u16),
-}
-
-impl Peer {
-    pub fn get_formatted_peer_id(&self) -> String {
-        format!("{}:{}", self.peer_id.0, self.peer_id.1)
-    }
-}
+const CREATED_BY: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
 
-#[derive(Debug)]
-pub enum PeerRequest {
-    DowloadPiece { piece_index: u32, piece_length: u32 },
-}
 #[derive(Parser, Debug)]
 pub struct Cli {
     #[command(subcommand)]
     command: Commands,
+    #[command(flatten)]
+    session: SessionArgs,
 }
 
 #[derive(Subcommand, Debug, Clone)]
@@ -55,6 +49,9 @@ enum Commands {
 
     Info {
         path: String,
+        /// Print a machine-readable JSON description instead
+        #[arg(long)]
+        json: bool,
     },
 
     Peers {
@@ -77,6 +74,24 @@ enum Commands {
         metadata: DownloadMetadata,
     },
 
+    /// Create a .torrent from a file or directory
+    Create {
+        path: String,
+        #[arg(short)]
+        output: String,
+        /// Tracker URL; repeat for more tiers, comma-separate URLs within a tier
+        #[arg(short, long = "tracker")]
+        trackers: Vec<String>,
+        #[arg(long)]
+        piece_length: Option<u32>,
+        #[arg(long)]
+        comment: Option<String>,
+        #[arg(long)]
+        private: bool,
+        #[arg(long = "web-seed")]
+        web_seeds: Vec<String>,
+    },
+
     #[command(name = "magnet_parse")]
     MagnetParse {
         magnet_link: String,
@@ -86,6 +101,61 @@ enum Commands {
     MagnetHandshake {
         magnet_link: String,
     },
+
+    /// Keep a session running, controlled over a local HTTP API
+    Daemon {
+        /// Port of the control API, bound on 127.0.0.1
+        #[arg(long)]
+        api_port: Option<u16>,
+    },
+
+    /// Control a running daemon
+    Ctl {
+        /// Base URL of the daemon's control API
+        #[arg(long, default_value_t = format!("http://127.0.0.1:{}", DEFAULT_API_PORT))]
+        api: String,
+        #[command(subcommand)]
+        command: CtlCommand,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum CtlCommand {
+    /// Add a .torrent file or a magnet link
+    Add {
+        source: String,
+        #[arg(short)]
+        output: Option<PathBuf>,
+        #[arg(long)]
+        paused: bool,
+        /// Fetch pieces in order so the file can be played while downloading
+        #[arg(long)]
+        sequential: bool,
+        #[command(flatten)]
+        files: FileArgs,
+    },
+    List,
+    Pause {
+        info_hash: String,
+    },
+    Resume {
+        info_hash: String,
+    },
+    Remove {
+        info_hash: String,
+    },
+    Stats,
+    /// Set rate limits for the session, or for one torrent with --torrent
+    Limits {
+        /// Download limit in KiB/s; unlimited if omitted
+        #[arg(long)]
+        download_limit: Option<u64>,
+        /// Upload limit in KiB/s; unlimited if omitted
+        #[arg(long)]
+        upload_limit: Option<u64>,
+        #[arg(long)]
+        torrent: Option<String>,
+    },
 }
 
 #[derive(Debug, Parser, Clone)]
@@ -94,55 +164,148 @@ struct DownloadMetadata {
     output: String,
     file_path: String,
     piece: Option<u32>,
+    /// Fetch pieces in order so the file can be played while downloading
+    #[arg(long)]
+    sequential: bool,
+    /// Serve the files over HTTP on this local port while downloading, and
+    /// keep serving until interrupted
+    #[arg(long)]
+    serve: Option<u16>,
+    #[command(flatten)]
+    files: FileArgs,
+}
+
+/// Globs over the paths of a torrent's files, e.g. `*.mkv` or `extras/**`.
+#[derive(Debug, Parser, Clone)]
+struct FileArgs {
+    /// Download only files matching this glob; repeatable
+    #[arg(long)]
+    only: Vec<String>,
+    /// Skip files matching this glob; repeatable
+    #[arg(long)]
+    exclude: Vec<String>,
+    /// Fetch files matching this glob first
+    #[arg(long)]
+    high: Vec<String>,
+    /// Fetch files matching this glob last
+    #[arg(long)]
+    low: Vec<String>,
+}
+
+impl From<FileArgs> for FileSelection {
+    fn from(args: FileArgs) -> Self {
+        FileSelection {
+            only: args.only,
+            exclude: args.exclude,
+            high: args.high,
+            low: args.low,
+        }
+    }
+}
+
+/// Options for every command that talks to peers or trackers, accepted
+/// before or after the subcommand. Each one overrides the config file and the
+/// environment.
+#[derive(Debug, Parser, Clone)]
+struct SessionArgs {
+    /// Config file to read instead of the default one
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    #[arg(long, global = true)]
+    listen_port: Option<u16>,
+    /// Where downloads with a relative or no output path are written
+    #[arg(long, global = true)]
+    download_dir: Option<PathBuf>,
+    /// Don't look for peers on the local network (BEP 14)
+    #[arg(long, global = true)]
+    no_lsd: bool,
+    /// Whether peer connections use Message Stream Encryption
+    #[arg(long, value_enum, global = true)]
+    encryption: Option<EncryptionPolicy>,
+    /// Which transport to reach peers over
+    #[arg(long, value_enum, global = true)]
+    transport: Option<TransportMode>,
+    /// Download limit in KiB/s
+    #[arg(long, global = true)]
+    download_limit: Option<u64>,
+    /// Upload limit in KiB/s
+    #[arg(long, global = true)]
+    upload_limit: Option<u64>,
+    /// Maximum number of peers connected at once per torrent
+    #[arg(long, global = true)]
+    max_peers: Option<usize>,
+    /// Maximum number of peers connected at once across all torrents
+    #[arg(long, global = true)]
+    max_peers_global: Option<usize>,
+}
+
+impl SessionArgs {
+    fn settings(&self) -> anyhow::Result<Settings> {
+        let mut settings = Settings::load(self.config.as_deref())?;
+        if let Some(listen_port) = self.listen_port {
+            settings.listen_port = listen_port;
+        }
+        if let Some(download_dir) = &self.download_dir {
+            settings.download_dir = download_dir.clone();
+        }
+        if self.no_lsd {
+            settings.local_discovery = false;
+        }
+        settings.encryption = self.encryption.unwrap_or(settings.encryption);
+        settings.transport = self.transport.unwrap_or(settings.transport);
+        settings.download_limit = self.download_limit.or(settings.download_limit);
+        settings.upload_limit = self.upload_limit.or(settings.upload_limit);
+        settings.max_peers = self.max_peers.unwrap_or(settings.max_peers);
+        settings.max_peers_global = self.max_peers_global.unwrap_or(settings.max_peers_global);
+        settings.validate()?;
+        Ok(settings)
+    }
 }
 
 impl Cli {
     pub async fn execute(self) -> anyhow::Result<()> {
+        let settings = self.session.settings()?;
+        // One peer id for every announce and handshake this run makes
+        let config = settings.session_config();
         match self.command {
             Commands::Decode { value: string } => {
-                let (decoded_value, _) = decode_bencoded_value(&string, 0);
-                println!("{}", decoded_value.to_string());
+                let decoded_value = bencode::decode(string.as_bytes())?;
+                println!("{}", decoded_value.to_json());
             }
-            Commands::Info { path } => {
+            Commands::Info { path, json } => {
                 let torrent_file = TorrentFile::parse_file_from_path(&path)?;
+                let summary = TorrentSummary::new(&torrent_file);
 
-                println!("Tracker URL: {}", torrent_file.announce);
-                println!("Length: {}", torrent_file.info.length);
-
-                println!(
-                    "Info Hash: {}",
-                    hash_bytes_and_hex(&serde_bencode::to_bytes(&torrent_file.info)?)
-                );
-
-                println!("Piece Length: {}", torrent_file.info.piece_length);
-
-                println!("Piece Hashes:");
-
-                for piece in torrent_file.info.pieces.chunks(20) {
-                    println!("{}", bytes_to_hex(piece));
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&summary)?);
+                } else {
+                    summary.print_text();
                 }
             }
             Commands::Peers { path } => {
                 let torrent_file = TorrentFile::parse_file_from_path(&path)?;
-                println!("Torrent File: {:?}", torrent_file);
 
-                let peers = torrent_file.discover_peers().await?;
+                let tracker = TrackerClient::new(config.peer_id, config.listen_port);
+                let peers = torrent_file.discover_peers(&tracker).await?;
 
                 for peer in peers {
-                    println!("{}:{}", peer.0, peer.1);
+                    println!("{}", peer);
                 }
 
                 // let mut peers: Vec<String> = Vec::new();
             }
             Commands::Handshake { path, url } => {
                 let torrent_file = TorrentFile::parse_file_from_path(&path)?;
-                let infohash = hash_bytes(&serde_bencode::to_bytes(&torrent_file.info)?);
+                let infohash = torrent_file.info_hash();
 
                 let (temp_tx, _) = tokio::sync::mpsc::channel(1000);
-                let mut connection = PeerConnection::new(url, temp_tx).await;
-                let peer_id = connection.handshake(Arc::new(infohash), None).await;
+                let mut connection =
+                    PeerConnection::new(url.parse()?, config.peer_id, temp_tx).await?;
+                let handshake = connection
+                    .handshake(&infohash, Capabilities::default())
+                    .await?;
 
-                println!("Peer ID: {}", peer_id);
+                println!("Peer ID: {}", bytes_to_hex(&handshake.peer_id));
             }
 
             Commands::Download { metadata } | Commands::DownloadPiece { metadata } => {
@@ -150,128 +313,231 @@ impl Cli {
                     output,
                     file_path,
                     piece,
+                    sequential,
+                    serve,
+                    files,
                 } = metadata;
 
-                let torrent_file = TorrentFile::parse_file_from_path(&file_path)?;
-
-                let peers = torrent_file.discover_peers().await?;
-
-                let peers = if piece.is_some() {
-                    vec![peers[0]]
-                } else {
-                    peers
-                };
+                let session = Session::new(config).await?;
+                if let Some(e) = session.listen_error() {
+                    eprintln!("Not accepting incoming peers: {}", e);
+                }
 
-                let piece_index_and_length = if piece.is_some() {
-                    let piece_length = {
-                        let start_byte = piece.unwrap() as u32 * torrent_file.info.piece_length;
-                        let end_byte = std::cmp::min(
-                            start_byte + torrent_file.info.piece_length,
-                            torrent_file.info.length,
-                        );
-                        end_byte - start_byte
-                    };
-                    vec![(piece.unwrap(), piece_length)]
-                } else {
-                    torrent_file.piece_and_length()
+                let torrent_file = TorrentFile::parse_file_from_path(&file_path)?;
+                let file_priorities = FileSelection::from(files).priorities(&torrent_file.info)?;
+                let wanted = match piece {
+                    Some(piece) => vec![(piece, torrent_file.piece_length(piece))],
+                    None => wanted_pieces(&torrent_file, &file_priorities),
                 };
+                let mut view = ProgressView::new(
+                    &torrent_file.info.name,
+                    torrent_file.info.piece_count(),
+                    &wanted,
+                );
 
-                let infohash = Arc::new(hash_bytes(&serde_bencode::to_bytes(&torrent_file.info)?));
-
-                let (peer_request_tx, peer_request_rx) = tokio::sync::mpsc::channel(1000);
-                let (peer_response_tx, mut peer_response_rx) = tokio::sync::mpsc::channel(1000);
-
-                let peer_manager = PeerManager::new(peer_request_rx, peer_response_tx).await;
-
-                let peer_addresses = peers
-                    .iter()
-                    .map(|(ip, val)| format!("{}:{}", ip, val))
-                    .collect::<Vec<_>>();
-                peer_manager
-                    .spawn_peers(peer_addresses, infohash.clone())
-                    .await;
-
-                let total_pieces = piece_index_and_length.len();
-
-                for (piece_index, piece_length) in piece_index_and_length {
-                    let peer_request = PeerRequest::DowloadPiece {
-                        piece_index,
-                        piece_length,
-                    };
-                    peer_request_tx.send(peer_request).await.unwrap();
+                let urls = streaming::file_urls(&torrent_file.info);
+
+                // Subscribe first so no event is missed
+                let mut events = session.subscribe();
+                let info_hash = session.add_torrent(
+                    torrent_file,
+                    AddTorrentOptions {
+                        output: output.into(),
+                        piece,
+                        sequential,
+                        file_priorities,
+                        ..Default::default()
+                    },
+                )?;
+                if let Some(port) = serve {
+                    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).await?;
+                    let address = listener.local_addr()?;
+                    for url in urls {
+                        println!("Serving http://{}{}", address, url);
+                    }
+                    tokio::spawn(streaming::serve_files(session.clone(), info_hash, listener));
                 }
-
-                // Close the request channel so peer workers know when to exit
-                drop(peer_request_tx);
-
-                // Collect responses - we know exactly how many to expect
-                let mut pieces = Vec::new();
-                for _ in 0..total_pieces {
-                    if let Some(data) = peer_response_rx.recv().await {
-                        println!("Received piece: {:?}", data.piece);
-                        pieces.push(data);
+                loop {
+                    match events.recv().await {
+                        Ok(Event::Completed { .. }) => break,
+                        Ok(Event::Error { message, .. }) => {
+                            view.finish();
+                            return Err(anyhow!(message));
+                        }
+                        Ok(event) => view.handle(&event),
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => break,
                     }
                 }
-
-                // Sort pieces by index to maintain correct order
-                pieces.sort_by(|a, b| a.piece.cmp(&b.piece));
-
-                let mut contents = Vec::new();
-                for piece in pieces {
-                    contents.extend(piece.data);
+                view.finish();
+                if serve.is_some() {
+                    println!("Download complete; still serving until interrupted");
+                    tokio::signal::ctrl_c().await?;
                 }
+            }
+            Commands::Create {
+                path,
+                output,
+                trackers,
+                piece_length,
+                comment,
+                private,
+                web_seeds,
+            } => {
+                let options = CreateOptions {
+                    trackers: trackers
+                        .iter()
+                        .map(|tier| tier.split(',').map(str::to_string).collect())
+                        .collect(),
+                    piece_length,
+                    comment,
+                    created_by: Some(CREATED_BY.to_string()),
+                    private,
+                    web_seeds,
+                };
+                let torrent_file =
+                    tokio::task::spawn_blocking(move || create_torrent(Path::new(&path), &options))
+                        .await??;
 
-                fs::write(env::current_dir()?.join(output), contents).await?;
+                fs::write(env::current_dir()?.join(output), torrent_file.to_bytes()).await?;
+                println!("Info Hash: {}", bytes_to_hex(&torrent_file.info_hash()));
             }
             Commands::MagnetParse { magnet_link } => {
-                // magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165&dn=magnet1.gif&tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce
-                //magnet:?xt={info_hash}&dn={file_name}&tr={tracker_url}
-
-                let (tracker_decoded, info_hash, _) = decode_magnet_link(&magnet_link);
-                // let (value, _) = decode_bencoded_value(tracker, 0);
-                println!("Tracker URL: {}", tracker_decoded);
-                println!("Info Hash: {}", info_hash);
+                let magnet = decode_magnet_link(&magnet_link)?;
+                println!(
+                    "Tracker URL: {}",
+                    magnet.trackers.first().map_or("", String::as_str)
+                );
+                println!("Info Hash: {}", bytes_to_hex(&magnet.info_hash));
+                for web_seed in &magnet.web_seeds {
+                    println!("Web Seed: {}", web_seed);
+                }
             }
             Commands::MagnetHandshake { magnet_link } => {
-                // magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165&dn=magnet1.gif&tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce
-                //magnet:?xt={info_hash}&dn={file_name}&tr={tracker_url}
-                let (tracker_url, info_hash, file_name) = decode_magnet_link(&magnet_link);
-
-                let info_hash: [u8; 20] = info_hash
-                    .as_bytes()
-                    .try_into()
-                    .expect("Info hash must be 20 bytes");
-
-                let torrent_file = TorrentFile::parse_file_from_path(&file_name).unwrap();
-                println!("annoce: {}", torrent_file.announce);
-
-                let client = Client::new();
-
-                let req = client.get(&tracker_url).build().unwrap();
-                let response = client.execute(req).await?.bytes().await?;
-
-                let tracker_response = serde_bencode::from_bytes::<TrackerResponse>(&response)?;
-
-                let mut peers = Vec::<(Ipv4Addr, u16)>::new();
-                for peer in tracker_response.peers.chunks(6) {
-                    let mut ip = [0u8; 4];
-                    ip.copy_from_slice(&peer[..4]);
-                    let port = u16::from_be_bytes([peer[4], peer[5]]);
-                    peers.push((Ipv4Addr::from(ip), port));
-                }
-                let peer_address = format!("{}:{}", peers[0].0, peers[0].1);
+                let magnet = decode_magnet_link(&magnet_link)?;
+                let info_hash = magnet.info_hash;
+                let tracker_url = magnet
+                    .trackers
+                    .first()
+                    .ok_or_else(|| anyhow::anyhow!("Magnet link has no tracker"))?;
+
+                let tracker = TrackerClient::new(config.peer_id, config.listen_port);
+                // The size is unknown until we have the metadata
+                let peer_address = *tracker
+                    .announce(tracker_url, &info_hash, 1)
+                    .await?
+                    .first()
+                    .ok_or_else(|| anyhow::anyhow!("Tracker returned no peers"))?;
                 let (temp_tx, _) = tokio::sync::mpsc::channel(1000);
-                let mut connection = PeerConnection::new(peer_address, temp_tx).await;
-                let peer_id = connection.handshake(Arc::new(info_hash), Some(true)).await;
+                let mut connection =
+                    PeerConnection::new(peer_address, config.peer_id, temp_tx).await?;
+                let capabilities = Capabilities {
+                    extension: true,
+                    ..Default::default()
+                };
+                let handshake = connection.handshake(&info_hash, capabilities).await?;
 
-                println!("Peer ID: {}", peer_id);
+                println!("Peer ID: {}", bytes_to_hex(&handshake.peer_id));
+            }
+            Commands::Daemon { api_port } => {
+                let api_port = api_port.unwrap_or(settings.api_port);
+                let session = Session::new(config).await?;
+                if let Some(e) = session.listen_error() {
+                    eprintln!("Not accepting incoming peers: {}", e);
+                }
+                let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, api_port)).await?;
+                println!("Control API listening on http://{}", listener.local_addr()?);
+                api::serve(session, listener).await;
+            }
+            Commands::Ctl { api, command } => {
+                let client = ApiClient::new(&api);
+                execute_ctl(&client, command).await?;
             }
         }
 
         Ok(())
     }
+}
+
+fn kib_to_bytes(limit: u64) -> anyhow::Result<u64> {
+    limit
+        .checked_mul(1024)
+        .ok_or_else(|| anyhow!("Rate limit of {} KiB/s is too large", limit))
+}
 
-    pub fn test(&self) {
-        unimplemented!("Do nonthingß");
+async fn execute_ctl(client: &ApiClient, command: CtlCommand) -> anyhow::Result<()> {
+    match command {
+        CtlCommand::Add {
+            source,
+            output,
+            paused,
+            sequential,
+            files,
+        } => {
+            // The daemon resolves torrent paths against its own working
+            // directory, and outputs against its download directory
+            let current_dir = env::current_dir()?;
+            let mut body = json!({
+                "output": output,
+                "paused": paused,
+                "sequential": sequential,
+                "only": files.only,
+                "exclude": files.exclude,
+                "high": files.high,
+                "low": files.low,
+            });
+            if source.starts_with("magnet:") {
+                body["magnet"] = json!(source);
+            } else {
+                body["torrent"] = json!(current_dir.join(source));
+            }
+            let response = client.request(Method::POST, "torrents", Some(body)).await?;
+            println!(
+                "Info Hash: {}",
+                response["info_hash"].as_str().unwrap_or_default()
+            );
+        }
+        CtlCommand::List => {
+            let torrents = client.request(Method::GET, "torrents", None).await?;
+            for torrent in torrents.as_array().into_iter().flatten() {
+                println!(
+                    "{} {:<11} {}/{} {}",
+                    torrent["info_hash"].as_str().unwrap_or_default(),
+                    torrent["state"].as_str().unwrap_or_default(),
+                    torrent["pieces_completed"],
+                    torrent["pieces_total"],
+                    torrent["name"].as_str().unwrap_or_default()
+                );
+            }
+        }
+        CtlCommand::Pause { info_hash } => {
+            let path = format!("torrents/{}/pause", info_hash);
+            client.request(Method::POST, &path, None).await?;
+        }
+        CtlCommand::Resume { info_hash } => {
+            let path = format!("torrents/{}/resume", info_hash);
+            client.request(Method::POST, &path, None).await?;
+        }
+        CtlCommand::Remove { info_hash } => {
+            let path = format!("torrents/{}", info_hash);
+            client.request(Method::DELETE, &path, None).await?;
+        }
+        CtlCommand::Stats => {
+            let stats = client.request(Method::GET, "stats", None).await?;
+            println!("{}", serde_json::to_string_pretty(&stats)?);
+        }
+        CtlCommand::Limits {
+            download_limit,
+            upload_limit,
+            torrent,
+        } => {
+            let body = json!({
+                "download": download_limit.map(kib_to_bytes).transpose()?,
+                "upload": upload_limit.map(kib_to_bytes).transpose()?,
+                "info_hash": torrent,
+            });
+            client.request(Method::PUT, "limits", Some(body)).await?;
+        }
     }
+    Ok(())
 }
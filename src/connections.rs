@@ -0,0 +1,168 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+pub const DEFAULT_MAX_PEERS_PER_TORRENT: usize = 50;
+pub const DEFAULT_MAX_PEERS_GLOBAL: usize = 200;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+const MAX_FAILURES: u32 = 6;
+
+#[derive(Default)]
+struct PeerState {
+    failures: u32,
+    /// Hex peer id while connected.
+    peer_id: Option<String>,
+}
+
+#[derive(Default)]
+struct PeerTable {
+    peers: HashMap<SocketAddr, PeerState>,
+    banned: HashSet<IpAddr>,
+}
+
+/// Tracks every peer address known for one torrent: enforces the per-torrent
+/// and global connection caps, decides when failed peers are retried, and
+/// remembers who is banned.
+#[derive(Clone)]
+pub struct ConnectionManager {
+    table: Arc<Mutex<PeerTable>>,
+    torrent_slots: Arc<Semaphore>,
+    global_slots: Arc<Semaphore>,
+}
+
+impl ConnectionManager {
+    /// `global_slots` is shared by all torrents; see [`global_limit`].
+    pub fn new(max_peers: usize, global_slots: Arc<Semaphore>) -> Self {
+        ConnectionManager {
+            table: Arc::default(),
+            torrent_slots: Arc::new(Semaphore::new(max_peers)),
+            global_slots,
+        }
+    }
+
+    /// Registers a newly discovered address. Returns false for addresses that
+    /// are already known or banned.
+    pub fn add(&self, peer_address: SocketAddr) -> bool {
+        let mut table = self.table.lock().unwrap();
+        if table.banned.contains(&peer_address.ip()) || table.peers.contains_key(&peer_address) {
+            return false;
+        }
+        table.peers.insert(peer_address, PeerState::default());
+        true
+    }
+
+    /// Waits for a free connection slot under both caps.
+    pub async fn acquire(&self) -> (OwnedSemaphorePermit, OwnedSemaphorePermit) {
+        let torrent = self.torrent_slots.clone().acquire_owned().await.unwrap();
+        let global = self.global_slots.clone().acquire_owned().await.unwrap();
+        (torrent, global)
+    }
+
+    /// A slot under the per-torrent cap for an incoming peer, which already
+    /// holds a global one; `None` if the torrent is at its cap.
+    pub fn try_acquire_torrent_slot(&self) -> Option<OwnedSemaphorePermit> {
+        self.torrent_slots.clone().try_acquire_owned().ok()
+    }
+
+    /// Records a completed handshake, which also forgives earlier failures.
+    /// Returns false if another connection is already talking to the same
+    /// peer id.
+    pub fn connected(&self, peer_address: SocketAddr, peer_id: &str) -> bool {
+        let mut table = self.table.lock().unwrap();
+        if table.peers.iter().any(|(address, state)| {
+            *address != peer_address && state.peer_id.as_deref() == Some(peer_id)
+        }) {
+            return false;
+        }
+        let state = table.peers.entry(peer_address).or_default();
+        state.peer_id = Some(peer_id.to_string());
+        state.failures = 0;
+        true
+    }
+
+    /// Records that a connection ended. Returns how long to wait before
+    /// retrying, or `None` if the peer should be given up on.
+    ///
+    /// Only failed connections are retried, with a backoff that grows with
+    /// each failure in a row. A connection ends cleanly only when the
+    /// download has no more requests, the peer was banned or it turned out
+    /// to be a peer we're already connected to, and none of these is helped
+    /// by reconnecting.
+    pub fn disconnected(&self, peer_address: SocketAddr, failed: bool) -> Option<Duration> {
+        let mut table = self.table.lock().unwrap();
+        if table.banned.contains(&peer_address.ip()) {
+            return None;
+        }
+        let state = table.peers.entry(peer_address).or_default();
+        state.peer_id = None;
+        if !failed {
+            return None;
+        }
+        state.failures += 1;
+        if state.failures > MAX_FAILURES {
+            return None;
+        }
+        Some((INITIAL_BACKOFF * 2u32.pow(state.failures - 1)).min(MAX_BACKOFF))
+    }
+
+    /// Bans every port on the peer's IP, e.g. after it sent a piece that
+    /// failed its hash check.
+    pub fn ban(&self, peer_address: SocketAddr) {
+        self.table.lock().unwrap().banned.insert(peer_address.ip());
+    }
+
+    pub fn is_banned(&self, peer_address: SocketAddr) -> bool {
+        self.table
+            .lock()
+            .unwrap()
+            .banned
+            .contains(&peer_address.ip())
+    }
+}
+
+/// The connection cap shared by every torrent in the process.
+pub fn global_limit(max_peers: usize) -> Arc<Semaphore> {
+    Arc::new(Semaphore::new(max_peers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_dedup_and_ban() {
+        let manager = ConnectionManager::new(2, global_limit(10));
+        let first: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let second: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+
+        assert!(manager.add(first));
+        assert!(!manager.add(first));
+        assert!(manager.add(second));
+
+        assert!(manager.connected(first, "aa"));
+        assert!(!manager.connected(second, "aa"));
+
+        assert_eq!(manager.disconnected(first, true), Some(INITIAL_BACKOFF));
+        assert_eq!(manager.disconnected(first, true), Some(INITIAL_BACKOFF * 2));
+        // A good session in between starts the backoff over
+        assert!(manager.connected(first, "aa"));
+        assert_eq!(manager.disconnected(first, true), Some(INITIAL_BACKOFF));
+        for _ in 1..MAX_FAILURES {
+            assert!(manager.disconnected(first, true).is_some());
+        }
+        assert_eq!(manager.disconnected(first, true), None);
+        assert!(manager.connected(second, "bb"));
+        assert_eq!(manager.disconnected(second, false), None);
+
+        manager.ban("10.0.0.1:7000".parse().unwrap());
+        assert!(manager.is_banned(first));
+        assert_eq!(manager.disconnected(first, true), None);
+    }
+}
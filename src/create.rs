@@ -0,0 +1,278 @@
+use std::{
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    error::{Error, Result},
+    hasher::hash_bytes,
+    parser::{FileEntry, TorrentFile, TorrentInfo},
+};
+
+const MIN_PIECE_LENGTH: u32 = 16 * 1024;
+const MAX_PIECE_LENGTH: u32 = 16 * 1024 * 1024;
+const TARGET_PIECE_COUNT: u64 = 1500;
+
+#[derive(Debug, Default, Clone)]
+pub struct CreateOptions {
+    /// Tracker tiers; the first URL of the first tier becomes `announce`.
+    pub trackers: Vec<Vec<String>>,
+    /// Defaults to [`default_piece_length`] when unset.
+    pub piece_length: Option<u32>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    pub private: bool,
+    pub web_seeds: Vec<String>,
+}
+
+struct SourceFile {
+    path: PathBuf,
+    components: Vec<String>,
+    length: u64,
+}
+
+/// Picks a power-of-two piece length giving roughly `TARGET_PIECE_COUNT` pieces.
+pub fn default_piece_length(total_length: u64) -> u32 {
+    let ideal = (total_length / TARGET_PIECE_COUNT)
+        .max(1)
+        .next_power_of_two();
+    ideal.clamp(MIN_PIECE_LENGTH as u64, MAX_PIECE_LENGTH as u64) as u32
+}
+
+/// Builds a metainfo for the file or directory at `path`, hashing its pieces
+/// on all available cores.
+pub fn create_torrent(path: &Path, options: &CreateOptions) -> Result<TorrentFile> {
+    let path = path
+        .canonicalize()
+        .map_err(|e| Error::Storage(format!("Cannot read {}: {}", path.display(), e)))?;
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| Error::Storage(format!("{} has no usable file name", path.display())))?
+        .to_string();
+
+    let is_dir = path.is_dir();
+    let files = if is_dir {
+        let mut files = Vec::new();
+        collect_files(&path, &mut Vec::new(), &mut files)?;
+        if files.is_empty() {
+            return Err(Error::Storage(format!(
+                "{} contains no files",
+                path.display()
+            )));
+        }
+        files
+    } else {
+        vec![SourceFile {
+            length: fs::metadata(&path)?.len(),
+            path: path.clone(),
+            components: vec![name.clone()],
+        }]
+    };
+
+    let length = files.iter().map(|file| file.length).sum();
+    let piece_length = match options.piece_length {
+        Some(piece_length) => {
+            if piece_length < MIN_PIECE_LENGTH || !piece_length.is_power_of_two() {
+                return Err(Error::Storage(format!(
+                    "Piece length must be a power of two of at least {} bytes",
+                    MIN_PIECE_LENGTH
+                )));
+            }
+            piece_length
+        }
+        None => default_piece_length(length),
+    };
+
+    let info = TorrentInfo {
+        length,
+        name,
+        piece_length,
+        pieces: hash_pieces(&files, length, piece_length)?,
+        files: if is_dir {
+            files
+                .iter()
+                .map(|file| FileEntry {
+                    length: file.length,
+                    path: file.components.clone(),
+                    attr: None,
+                })
+                .collect()
+        } else {
+            Vec::new()
+        },
+        private: options.private,
+        ..Default::default()
+    };
+
+    let announce = options
+        .trackers
+        .first()
+        .and_then(|tier| tier.first())
+        .cloned()
+        .unwrap_or_default();
+    let mut torrent_file = TorrentFile::new(announce, info);
+    if options.trackers.iter().flatten().count() > 1 {
+        torrent_file.announce_list = options.trackers.clone();
+    }
+    torrent_file.comment = options.comment.clone();
+    torrent_file.created_by = options.created_by.clone();
+    torrent_file.creation_date = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|elapsed| elapsed.as_secs() as i64);
+    torrent_file.url_list = options.web_seeds.clone();
+
+    Ok(torrent_file)
+}
+
+/// Walks `dir` depth-first in name order so the file list is deterministic.
+fn collect_files(dir: &Path, prefix: &mut Vec<String>, files: &mut Vec<SourceFile>) -> Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let file_name = entry
+            .file_name()
+            .into_string()
+            .map_err(|name| Error::Storage(format!("Non UTF-8 file name: {:?}", name)))?;
+        let file_type = entry.file_type()?;
+        prefix.push(file_name);
+        if file_type.is_dir() {
+            collect_files(&entry.path(), prefix, files)?;
+        } else if file_type.is_file() || (file_type.is_symlink() && entry.path().is_file()) {
+            files.push(SourceFile {
+                path: entry.path(),
+                components: prefix.clone(),
+                length: fs::metadata(entry.path())?.len(),
+            });
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+fn hash_pieces(files: &[SourceFile], length: u64, piece_length: u32) -> Result<Vec<u8>> {
+    let piece_count = length.div_ceil(piece_length as u64) as usize;
+    if piece_count == 0 {
+        return Ok(Vec::new());
+    }
+    let workers = thread::available_parallelism()
+        .map(|workers| workers.get())
+        .unwrap_or(1)
+        .min(piece_count);
+    let pieces_per_worker = piece_count.div_ceil(workers);
+
+    thread::scope(|scope| {
+        let handles = (0..workers)
+            .map(|worker| {
+                let first = (worker * pieces_per_worker).min(piece_count);
+                let last = ((worker + 1) * pieces_per_worker).min(piece_count);
+                scope.spawn(move || {
+                    let mut reader = ContentReader::new(files);
+                    let mut buffer = vec![0u8; piece_length as usize];
+                    let mut hashes = Vec::with_capacity((last - first) * 20);
+                    for piece_index in first..last {
+                        let offset = piece_index as u64 * piece_length as u64;
+                        let size = (length - offset).min(piece_length as u64) as usize;
+                        reader.read_at(offset, &mut buffer[..size])?;
+                        hashes.extend(hash_bytes(&buffer[..size]));
+                    }
+                    Ok::<_, Error>(hashes)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut pieces = Vec::with_capacity(piece_count * 20);
+        for handle in handles {
+            let hashes = handle
+                .join()
+                .map_err(|_| Error::Storage("Hashing thread panicked".into()))??;
+            pieces.extend(hashes);
+        }
+        Ok(pieces)
+    })
+}
+
+/// Reads byte ranges of the concatenated content, keeping the last file open.
+struct ContentReader<'a> {
+    files: &'a [SourceFile],
+    open: Option<(usize, File)>,
+}
+
+impl<'a> ContentReader<'a> {
+    fn new(files: &'a [SourceFile]) -> Self {
+        ContentReader { files, open: None }
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let mut file_start = 0;
+        let mut filled = 0;
+        for (index, file) in self.files.iter().enumerate() {
+            let file_end = file_start + file.length;
+            let position = offset + filled as u64;
+            if filled < buf.len() && position < file_end {
+                let within = position - file_start;
+                let take = ((file_end - position) as usize).min(buf.len() - filled);
+                if self.open.as_ref().map(|(open, _)| *open) != Some(index) {
+                    let handle = File::open(&file.path).map_err(|e| {
+                        Error::Storage(format!("Cannot open {}: {}", file.path.display(), e))
+                    })?;
+                    self.open = Some((index, handle));
+                }
+                let (_, handle) = self.open.as_mut().unwrap();
+                handle.seek(SeekFrom::Start(within))?;
+                handle
+                    .read_exact(&mut buf[filled..filled + take])
+                    .map_err(|e| {
+                        Error::Storage(format!(
+                            "{} changed while hashing: {}",
+                            file.path.display(),
+                            e
+                        ))
+                    })?;
+                filled += take;
+            }
+            file_start = file_end;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    #[test]
+    fn test_create_directory_torrent() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("release");
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join("a.bin"), vec![1u8; 20_000]).unwrap();
+        fs::write(root.join("docs/readme.txt"), b"hello").unwrap();
+
+        let options = CreateOptions {
+            trackers: vec![vec![String::from("http://tracker/announce")]],
+            private: true,
+            ..Default::default()
+        };
+        let torrent_file = create_torrent(&root, &options).unwrap();
+        let parsed = Parser::parse_torrent_file(&torrent_file.to_bytes()).unwrap();
+
+        let mut content = vec![1u8; 20_000];
+        content.extend(b"hello");
+        let expected_pieces = content
+            .chunks(MIN_PIECE_LENGTH as usize)
+            .flat_map(hash_bytes)
+            .collect::<Vec<_>>();
+        assert_eq!(parsed.info.pieces, expected_pieces);
+        assert_eq!(parsed.info.files.len(), 2);
+        assert_eq!(parsed.info.files[1].path, vec!["docs", "readme.txt"]);
+        assert_eq!(parsed.info_hash(), torrent_file.info_hash());
+        assert!(parsed.info.private);
+    }
+}
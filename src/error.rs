@@ -0,0 +1,42 @@
+use std::io;
+
+use thiserror::Error;
+
+use crate::bencode::BencodeError;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Everything that can go wrong in the library, grouped by where it happened.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// A `.torrent` file, info dictionary or magnet link is malformed.
+    #[error("Invalid metainfo: {0}")]
+    Metainfo(String),
+    #[error("Invalid bencode: {0}")]
+    Bencode(#[from] BencodeError),
+    /// The tracker couldn't be reached or refused the announce.
+    #[error("Tracker error: {0}")]
+    Tracker(String),
+    /// A peer or web seed broke the protocol, sent bad data or went away.
+    #[error("Peer error: {0}")]
+    Peer(String),
+    /// Reading or writing downloaded data failed.
+    #[error("Storage error: {0}")]
+    Storage(String),
+    #[error("No torrent {0}")]
+    UnknownTorrent(String),
+    #[error("Torrent {0} is already added")]
+    DuplicateTorrent(String),
+    /// The daemon's control API failed or rejected a request.
+    #[error("API error: {0}")]
+    Api(String),
+    /// We dialled our own listener, e.g. an address a tracker or the local
+    /// network reported for us.
+    #[error("Connected to ourselves")]
+    SelfConnection,
+    /// A config file, environment variable or flag holds an invalid setting.
+    #[error("Invalid config: {0}")]
+    Config(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
@@ -0,0 +1,131 @@
+use std::net::SocketAddr;
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Events buffered per subscriber before it starts missing them.
+const EVENT_CAPACITY: usize = 1024;
+
+/// Something that happened while downloading. `info_hash` is hex, as in
+/// [`TorrentStatus`](crate::session::TorrentStatus).
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    TrackerAnnounce {
+        info_hash: String,
+        tracker: String,
+        /// Number of peers returned, or why the announce failed.
+        result: Result<usize, String>,
+    },
+    PeerConnected {
+        info_hash: String,
+        peer: SocketAddr,
+    },
+    PeerDisconnected {
+        info_hash: String,
+        peer: SocketAddr,
+        /// `None` when the connection ended because there was no more work.
+        error: Option<String>,
+    },
+    PieceVerified {
+        info_hash: String,
+        piece: u32,
+        /// `None` for web seeds.
+        peer: Option<SocketAddr>,
+    },
+    PieceFailed {
+        info_hash: String,
+        piece: u32,
+        /// `None` for web seeds.
+        peer: Option<SocketAddr>,
+        reason: PieceFailure,
+    },
+    /// Sent about once a second with the torrent's running totals.
+    Transferred {
+        info_hash: String,
+        downloaded: u64,
+        uploaded: u64,
+    },
+    Completed {
+        info_hash: String,
+    },
+    /// Something went wrong that the torrent carries on without, such as a
+    /// web seed failing a piece.
+    Warning {
+        info_hash: String,
+        message: String,
+    },
+    Error {
+        info_hash: String,
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PieceFailure {
+    /// The connection failed or the peer was too slow.
+    Download,
+    /// The data didn't match the piece hash; the peer is banned.
+    HashMismatch,
+}
+
+/// A broadcast channel of [`Event`]s. Every subscriber sees every event sent
+/// after it subscribed; one that falls more than 1024 events behind gets
+/// [`broadcast::error::RecvError::Lagged`] and skips ahead.
+#[derive(Clone)]
+pub struct EventBus {
+    tx: broadcast::Sender<Event>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        EventBus {
+            tx: broadcast::channel(EVENT_CAPACITY).0,
+        }
+    }
+}
+
+impl EventBus {
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.tx.subscribe()
+    }
+
+    pub fn emit(&self, event: Event) {
+        // Nobody listening is fine
+        let _ = self.tx.send(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_subscribers_receive_events_as_json() {
+        let events = EventBus::default();
+        events.emit(Event::Completed {
+            info_hash: "00".into(),
+        });
+
+        let mut subscriber = events.subscribe();
+        let event = Event::PieceFailed {
+            info_hash: "ab".into(),
+            piece: 3,
+            peer: Some("127.0.0.1:6881".parse().unwrap()),
+            reason: PieceFailure::HashMismatch,
+        };
+        events.emit(event.clone());
+        assert_eq!(subscriber.recv().await.unwrap(), event);
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({
+                "event": "piece_failed",
+                "info_hash": "ab",
+                "piece": 3,
+                "peer": "127.0.0.1:6881",
+                "reason": "hash_mismatch",
+            })
+        );
+    }
+}
@@ -1,4 +1,5 @@
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
 
 pub fn hash_bytes_and_hex(bytes: &[u8]) -> String {
     let mut hasher = Sha1::default();
@@ -12,20 +13,25 @@ pub fn hash_bytes(bytes: &[u8]) -> [u8; 20] {
     let hash = hasher.finalize();
     hash.into()
 }
+
+pub fn hash_bytes_sha256(bytes: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(bytes);
+    hasher.finalize().into()
+}
+
 pub fn bytes_to_hex(bytes: &[u8]) -> String {
     let mut result: String = String::new();
     for byte in bytes {
         result.push_str(format!("{:02x}", byte).as_str());
     }
-    return result;
+    result
 }
 
 pub fn bytes_to_hex_url_encoded(bytes: &[u8]) -> String {
-    let mut hasher = Sha1::default();
-    hasher.update(bytes);
     let mut result: String = String::new();
-    for byte in hasher.finalize() {
+    for byte in bytes {
         result.push_str(format!("%{:02x}", byte).as_str());
     }
-    return result;
+    result
 }
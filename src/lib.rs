@@ -0,0 +1,100 @@
+//! A BitTorrent client library.
+//!
+//! The main entry points are:
+//!
+//! - [`TorrentFile`] for reading and writing `.torrent` metainfo (v1, v2 and
+//!   hybrid), and [`decode_magnet_link`] for magnet URIs;
+//! - [`TrackerClient`] for asking HTTP trackers for peers;
+//! - [`PeerConnection`] for talking the peer wire protocol to a single peer;
+//! - [`Session`] for downloading and seeding any number of torrents, with
+//!   [`Session::subscribe`] streaming [`Event`]s about their progress and
+//!   [`Session::open_file`] reading a file while it downloads.
+//!
+//! ```no_run
+//! use codecrafters_bittorrent::{AddTorrentOptions, Parser, Session, SessionConfig};
+//!
+//! # async fn run() -> codecrafters_bittorrent::Result<()> {
+//! let torrent_file = Parser::parse_torrent_file(&std::fs::read("sample.torrent")?)?;
+//! let session = Session::new(SessionConfig::default()).await?;
+//! let info_hash = session.add_torrent(
+//!     torrent_file,
+//!     AddTorrentOptions {
+//!         output: "sample.txt".into(),
+//!         ..Default::default()
+//!     },
+//! )?;
+//! session.wait_until_complete(&info_hash).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+/// The local HTTP/JSON control API served by the daemon, and its client.
+pub mod api;
+/// Bencode decoding and encoding.
+pub mod bencode;
+/// Per-torrent peer bookkeeping: connection caps, retry backoff and bans.
+pub mod connections;
+/// Building new torrents from files on disk.
+pub mod create;
+/// The error type shared by the whole library.
+pub mod error;
+/// Download progress events.
+pub mod events;
+/// SHA-1/SHA-256 helpers and hex formatting.
+pub mod hasher;
+/// Local Service Discovery (BEP 14).
+pub mod lsd;
+/// Merkle trees over 16 KiB blocks for v2 torrents (BEP 52).
+pub mod merkle;
+/// Fetching the info dictionary of a magnet link from peers (BEP 9).
+pub mod metadata;
+/// Message Stream Encryption.
+pub mod mse;
+/// Metainfo (`.torrent`) parsing.
+pub mod parser;
+/// Choosing which piece to request next, in order or around a playhead.
+pub mod picker;
+/// Token-bucket bandwidth limits.
+pub mod ratelimit;
+/// Reading a torrent's files while they download.
+pub mod reader;
+/// The HTTP tracker client.
+pub mod request;
+/// Multi-torrent sessions.
+pub mod session;
+/// Client settings from a config file, the environment and flags.
+pub mod settings;
+/// Choosing which files to download and writing pieces into them.
+pub mod storage;
+/// Serving a torrent's files over local HTTP while they download.
+pub mod streaming;
+/// Human- and machine-readable summaries of a torrent.
+pub mod summary;
+/// The peer wire protocol and the workers that download pieces.
+pub mod tcp;
+/// Opening peer connections over TCP or uTP.
+pub mod transport;
+/// Magnet links and formatting helpers.
+pub mod util;
+/// The Micro Transport Protocol (BEP 29).
+pub mod utp;
+/// HTTP/FTP web seeds (BEP 19).
+pub mod webseed;
+
+pub use bencode::{decode, encode, BencodeError, BencodeValue};
+pub use error::{Error, Result};
+pub use events::{Event, EventBus};
+pub use parser::{Parser, TorrentFile, TorrentInfo};
+pub use reader::FileReader;
+pub use request::{TrackerClient, TrackerResponse};
+pub use session::{
+    AddTorrentOptions, Session, SessionConfig, SessionStats, TorrentState, TorrentStatus,
+};
+pub use settings::Settings;
+pub use tcp::{ConnectionConfig, PeerConnection};
+pub use util::{decode_magnet_link, MagnetLink};
+
+/// Size of the blocks pieces are requested in.
+pub const CHUNKSIZE: u32 = 16 * 1024;
+/// Default port we listen on and announce to trackers.
+pub const PORT: u16 = 6881;
@@ -0,0 +1,257 @@
+use std::{
+    collections::HashSet,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+    time::Duration,
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc::{self, Receiver, Sender},
+    time,
+};
+
+use crate::{
+    error::Result,
+    events::{Event, EventBus},
+    hasher::bytes_to_hex,
+};
+
+// BEP 14: Local Service Discovery
+pub const LSD_MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+pub const LSD_PORT: u16 = 6771;
+
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const MAX_DATAGRAM_SIZE: usize = 1400;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LsdAnnounce {
+    pub port: u16,
+    pub info_hashes: Vec<[u8; 20]>,
+    pub cookie: Option<String>,
+}
+
+impl LsdAnnounce {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut message = String::from("BT-SEARCH * HTTP/1.1\r\n");
+        message.push_str(&format!("Host: {}:{}\r\n", LSD_MULTICAST_ADDR, LSD_PORT));
+        message.push_str(&format!("Port: {}\r\n", self.port));
+        for info_hash in &self.info_hashes {
+            message.push_str(&format!("Infohash: {}\r\n", bytes_to_hex(info_hash)));
+        }
+        if let Some(cookie) = &self.cookie {
+            message.push_str(&format!("cookie: {}\r\n", cookie));
+        }
+        message.push_str("\r\n\r\n");
+        message.into_bytes()
+    }
+
+    /// Parses a `BT-SEARCH` datagram, returning `None` for anything that isn't a
+    /// well-formed announce.
+    pub fn parse(message: &[u8]) -> Option<LsdAnnounce> {
+        let message = std::str::from_utf8(message).ok()?;
+        let mut lines = message.split("\r\n");
+        if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+            return None;
+        }
+
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+        for line in lines {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = value.parse::<u16>().ok(),
+                "infohash" => {
+                    let bytes = hex::decode(value).ok()?;
+                    info_hashes.push(bytes.try_into().ok()?);
+                }
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+
+        if info_hashes.is_empty() {
+            return None;
+        }
+        Some(LsdAnnounce {
+            port: port?,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+pub struct LocalServiceDiscovery {
+    socket: Arc<UdpSocket>,
+    listen_port: u16,
+    cookie: String,
+}
+
+impl LocalServiceDiscovery {
+    /// Binds the LSD multicast socket. `listen_port` is the port we accept peer
+    /// connections on and is what gets advertised to the LAN.
+    pub fn bind(listen_port: u16) -> Result<Self> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, LSD_PORT)).into())?;
+        socket.join_multicast_v4(&LSD_MULTICAST_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+        socket.set_multicast_loop_v4(true)?;
+
+        let socket = UdpSocket::from_std(socket.into())?;
+        let cookie = format!("{:016x}", rand::random::<u64>());
+
+        Ok(LocalServiceDiscovery {
+            socket: Arc::new(socket),
+            listen_port,
+            cookie,
+        })
+    }
+
+    /// Starts announcing `info_hash` on the LAN and returns a channel of peers
+    /// that announced the same torrent. Both tasks stop once the receiver is dropped.
+    /// Network errors are reported to `events` as warnings.
+    pub fn spawn(self, info_hash: [u8; 20], events: EventBus) -> Receiver<SocketAddrV4> {
+        let (peer_tx, peer_rx) = mpsc::channel(100);
+
+        let announce = LsdAnnounce {
+            port: self.listen_port,
+            info_hashes: vec![info_hash],
+            cookie: Some(self.cookie.clone()),
+        };
+        tokio::spawn(announce_loop(
+            self.socket.clone(),
+            announce.to_bytes(),
+            info_hash,
+            peer_tx.clone(),
+            events.clone(),
+        ));
+        tokio::spawn(listen_loop(
+            self.socket,
+            self.cookie,
+            info_hash,
+            peer_tx,
+            events,
+        ));
+
+        peer_rx
+    }
+}
+
+async fn announce_loop(
+    socket: Arc<UdpSocket>,
+    message: Vec<u8>,
+    info_hash: [u8; 20],
+    peer_tx: Sender<SocketAddrV4>,
+    events: EventBus,
+) {
+    let mut interval = time::interval(ANNOUNCE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = peer_tx.closed() => return,
+        }
+        if let Err(e) = socket
+            .send_to(&message, (LSD_MULTICAST_ADDR, LSD_PORT))
+            .await
+        {
+            events.emit(Event::Warning {
+                info_hash: bytes_to_hex(&info_hash),
+                message: format!("LSD announce failed: {}", e),
+            });
+        }
+    }
+}
+
+async fn listen_loop(
+    socket: Arc<UdpSocket>,
+    cookie: String,
+    info_hash: [u8; 20],
+    peer_tx: Sender<SocketAddrV4>,
+    events: EventBus,
+) {
+    let mut seen = HashSet::new();
+    let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        let received = tokio::select! {
+            received = socket.recv_from(&mut buf) => received,
+            // Nobody wants peers any more, even if the LAN stays quiet
+            _ = peer_tx.closed() => return,
+        };
+        let (len, from) = match received {
+            Ok(received) => received,
+            Err(e) => {
+                events.emit(Event::Warning {
+                    info_hash: bytes_to_hex(&info_hash),
+                    message: format!("LSD receive failed: {}", e),
+                });
+                return;
+            }
+        };
+        let SocketAddr::V4(from) = from else {
+            continue;
+        };
+        let Some(announce) = LsdAnnounce::parse(&buf[..len]) else {
+            continue;
+        };
+        if announce.cookie.as_deref() == Some(cookie.as_str())
+            || !announce.info_hashes.contains(&info_hash)
+        {
+            continue;
+        }
+
+        let peer = SocketAddrV4::new(*from.ip(), announce.port);
+        if seen.insert(peer) && peer_tx.send(peer).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_announce_round_trip() {
+        let announce = LsdAnnounce {
+            port: 6881,
+            info_hashes: vec![[0xab; 20], [0x01; 20]],
+            cookie: Some(String::from("deadbeef")),
+        };
+
+        let parsed = LsdAnnounce::parse(&announce.to_bytes()).unwrap();
+
+        assert_eq!(announce, parsed);
+    }
+
+    #[test]
+    fn test_parse_rejects_other_requests() {
+        let message = b"M-SEARCH * HTTP/1.1\r\nPort: 6881\r\n\r\n\r\n";
+
+        assert_eq!(LsdAnnounce::parse(message), None);
+    }
+
+    #[tokio::test]
+    async fn test_listener_stops_with_its_receiver() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let (peer_tx, peer_rx) = mpsc::channel(1);
+        let listener = tokio::spawn(listen_loop(
+            socket,
+            String::new(),
+            [0; 20],
+            peer_tx,
+            EventBus::default(),
+        ));
+        drop(peer_rx);
+        // No datagram ever arrives
+        time::timeout(Duration::from_secs(5), listener)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
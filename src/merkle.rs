@@ -0,0 +1,86 @@
+use crate::hasher::hash_bytes_sha256;
+
+/// BEP 52 merkle trees are built over 16 KiB blocks of each file.
+pub const BLOCK_SIZE: usize = 16 * 1024;
+
+pub type Hash = [u8; 32];
+
+/// Leaf hashes for `data`, one per 16 KiB block; the last block may be short.
+pub fn block_hashes(data: &[u8]) -> Vec<Hash> {
+    data.chunks(BLOCK_SIZE).map(hash_bytes_sha256).collect()
+}
+
+pub fn hash_pair(left: &Hash, right: &Hash) -> Hash {
+    let mut both = [0u8; 64];
+    both[..32].copy_from_slice(left);
+    both[32..].copy_from_slice(right);
+    hash_bytes_sha256(&both)
+}
+
+/// Root of a subtree of `leaf_count` zero leaves, used to pad upper layers.
+pub fn zero_subtree(leaf_count: usize) -> Hash {
+    let mut hash = [0u8; 32];
+    let mut width = 1;
+    while width < leaf_count {
+        hash = hash_pair(&hash, &hash);
+        width *= 2;
+    }
+    hash
+}
+
+/// Root of a tree whose bottom layer is `hashes` padded with `pad` up to
+/// `width` entries. `width` must be a power of two no smaller than `hashes.len()`.
+pub fn root(hashes: &[Hash], width: usize, pad: Hash) -> Hash {
+    debug_assert!(width.is_power_of_two() && width >= hashes.len());
+    let mut layer = hashes.to_vec();
+    let mut pad = pad;
+    let mut width = width;
+    while width > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pad)))
+            .collect();
+        pad = hash_pair(&pad, &pad);
+        width /= 2;
+    }
+    layer.first().copied().unwrap_or(pad)
+}
+
+/// Hash of one piece as stored in `piece layers`: the root of its
+/// `piece_length / 16 KiB` block subtree, zero-padded past the data.
+pub fn piece_root(data: &[u8], piece_length: u32) -> Hash {
+    let leaves = (piece_length as usize / BLOCK_SIZE).max(1);
+    root(&block_hashes(data), leaves, [0u8; 32])
+}
+
+/// `pieces root` of a file no longer than one piece, computed from its data.
+pub fn file_root(data: &[u8]) -> Hash {
+    let leaves = block_hashes(data);
+    root(&leaves, leaves.len().next_power_of_two(), [0u8; 32])
+}
+
+/// `pieces root` of a larger file computed from its piece layer.
+pub fn file_root_from_layer(layer: &[Hash], piece_length: u32) -> Hash {
+    let pad = zero_subtree(piece_length as usize / BLOCK_SIZE);
+    root(layer, layer.len().next_power_of_two(), pad)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_piece_layer_matches_block_tree() {
+        let piece_length = 2 * BLOCK_SIZE as u32;
+        let data = (0..5 * BLOCK_SIZE + 100)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+
+        let layer = data
+            .chunks(piece_length as usize)
+            .map(|piece| piece_root(piece, piece_length))
+            .collect::<Vec<_>>();
+
+        assert_eq!(file_root_from_layer(&layer, piece_length), file_root(&data));
+    }
+}
@@ -0,0 +1,284 @@
+use std::{collections::BTreeMap, net::SocketAddr, time::Duration};
+
+use tokio::{sync::mpsc, time::timeout};
+
+use crate::{
+    bencode::{self, BencodeValue, Decoder},
+    error::{Error, Result},
+    hasher::{hash_bytes, hash_bytes_sha256},
+    parser::TorrentFile,
+    request::TrackerClient,
+    tcp::{Capabilities, ConnectionConfig, PeerConnection, PeerMessage},
+    util::MagnetLink,
+};
+
+/// Metadata is exchanged in pieces of this size (BEP 9).
+const METADATA_PIECE_SIZE: usize = 16 * 1024;
+const MAX_METADATA_SIZE: usize = 16 << 20;
+/// The id we ask peers to use for `ut_metadata` messages sent to us.
+const UT_METADATA_ID: u8 = 1;
+const EXTENDED_HANDSHAKE_ID: u8 = 0;
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Fetches the `info` dictionary of a magnet link from the swarm (BEP 9) and
+/// turns it into a torrent carrying the link's trackers and web seeds. If no
+/// peer sends it, the error lists what went wrong with each source.
+pub async fn fetch_torrent(
+    magnet: &MagnetLink,
+    tracker_client: &TrackerClient,
+    config: &ConnectionConfig,
+) -> Result<TorrentFile> {
+    let mut peers = Vec::new();
+    let mut failures = Vec::new();
+    for tracker in &magnet.trackers {
+        // The size is unknown until we have the metadata
+        match tracker_client.announce(tracker, &magnet.info_hash, 1).await {
+            Ok(found) => peers.extend(found),
+            Err(e) => failures.push(format!("tracker {} failed: {}", tracker, e)),
+        }
+    }
+
+    for peer in peers {
+        match timeout(PEER_TIMEOUT, fetch_info(peer, magnet, config)).await {
+            Ok(Ok(raw_info)) => return torrent_from_info(magnet, &raw_info),
+            Ok(Err(e)) => failures.push(format!("peer {} failed: {}", peer, e)),
+            Err(_) => failures.push(format!("peer {} timed out", peer)),
+        }
+    }
+    let mut message = String::from("No peer sent the torrent metadata");
+    if !failures.is_empty() {
+        message.push_str(&format!(" ({})", failures.join("; ")));
+    }
+    Err(Error::Peer(message))
+}
+
+async fn fetch_info(
+    peer_address: SocketAddr,
+    magnet: &MagnetLink,
+    config: &ConnectionConfig,
+) -> Result<Vec<u8>> {
+    let (response_tx, _) = mpsc::channel(1);
+    let mut connection =
+        PeerConnection::connect(peer_address, response_tx, &magnet.info_hash, config).await?;
+    let capabilities = Capabilities {
+        extension: true,
+        ..Default::default()
+    };
+    let remote = connection
+        .handshake(&magnet.info_hash, capabilities)
+        .await?;
+    if !remote.capabilities.extension {
+        return Err(Error::Peer(
+            "Peer doesn't support the extension protocol".into(),
+        ));
+    }
+
+    let handshake = BencodeValue::Dict(BTreeMap::from([(
+        b"m".to_vec(),
+        BencodeValue::Dict(BTreeMap::from([(
+            b"ut_metadata".to_vec(),
+            BencodeValue::from(UT_METADATA_ID as i64),
+        )])),
+    )]));
+    send_extended(&mut connection, EXTENDED_HANDSHAKE_ID, &handshake, &[]).await?;
+
+    let handshake = read_extended(&mut connection, EXTENDED_HANDSHAKE_ID).await?;
+    let handshake = bencode::decode(&handshake)?;
+    let peer_metadata_id = handshake
+        .get("m")
+        .and_then(|m| m.get("ut_metadata"))
+        .and_then(BencodeValue::as_integer)
+        .filter(|&id| id > 0 && id < 256)
+        .ok_or_else(|| Error::Peer("Peer doesn't support ut_metadata".into()))?
+        as u8;
+    let metadata_size = handshake
+        .get("metadata_size")
+        .and_then(BencodeValue::as_integer)
+        .filter(|&size| size > 0 && size as usize <= MAX_METADATA_SIZE)
+        .ok_or_else(|| Error::Peer("Peer sent no usable metadata_size".into()))?
+        as usize;
+
+    let mut raw_info = Vec::with_capacity(metadata_size);
+    for piece in 0..metadata_size.div_ceil(METADATA_PIECE_SIZE) {
+        let request = metadata_message(0, piece);
+        send_extended(&mut connection, peer_metadata_id, &request, &[]).await?;
+
+        let payload = read_extended(&mut connection, UT_METADATA_ID).await?;
+        let mut decoder = Decoder::new(&payload);
+        let header = decoder.decode_value()?;
+        match header.get("msg_type").and_then(BencodeValue::as_integer) {
+            Some(1) => {}
+            Some(2) => {
+                return Err(Error::Peer(format!(
+                    "Peer rejected metadata piece {}",
+                    piece
+                )))
+            }
+            _ => return Err(Error::Peer("Unexpected ut_metadata message".into())),
+        }
+        if header.get("piece").and_then(BencodeValue::as_integer) != Some(piece as i64) {
+            return Err(Error::Peer("Peer sent the wrong metadata piece".into()));
+        }
+        raw_info.extend_from_slice(&payload[decoder.position()..]);
+    }
+
+    if raw_info.len() != metadata_size {
+        return Err(Error::Peer(format!(
+            "Metadata is {} bytes, expected {}",
+            raw_info.len(),
+            metadata_size
+        )));
+    }
+    if !matches_info_hash(magnet, &raw_info) {
+        return Err(Error::Peer("Metadata doesn't match the info hash".into()));
+    }
+    Ok(raw_info)
+}
+
+fn metadata_message(msg_type: i64, piece: usize) -> BencodeValue {
+    BencodeValue::Dict(BTreeMap::from([
+        (b"msg_type".to_vec(), BencodeValue::from(msg_type)),
+        (b"piece".to_vec(), BencodeValue::from(piece as i64)),
+    ]))
+}
+
+async fn send_extended(
+    connection: &mut PeerConnection,
+    id: u8,
+    header: &BencodeValue,
+    data: &[u8],
+) -> Result<()> {
+    let mut payload = vec![id];
+    payload.extend(bencode::encode(header));
+    payload.extend_from_slice(data);
+    connection
+        .send_message(PeerMessage::Extended, payload)
+        .await
+}
+
+/// Waits for the next extension message with the given id and returns what
+/// follows the id.
+async fn read_extended(connection: &mut PeerConnection, id: u8) -> Result<Vec<u8>> {
+    loop {
+        let payload = connection.wait(PeerMessage::Extended).await?;
+        if payload.first() == Some(&id) {
+            return Ok(payload[1..].to_vec());
+        }
+    }
+}
+
+fn matches_info_hash(magnet: &MagnetLink, raw_info: &[u8]) -> bool {
+    if let Some(info_hash_v2) = magnet.info_hash_v2 {
+        return hash_bytes_sha256(raw_info) == info_hash_v2;
+    }
+    hash_bytes(raw_info) == magnet.info_hash
+}
+
+fn torrent_from_info(magnet: &MagnetLink, raw_info: &[u8]) -> Result<TorrentFile> {
+    let value = BencodeValue::Dict(BTreeMap::from([(
+        b"info".to_vec(),
+        bencode::decode(raw_info)?,
+    )]));
+    let mut torrent_file = TorrentFile::from_bencode(&value, raw_info)?;
+    torrent_file.announce = magnet.trackers.first().cloned().unwrap_or_default();
+    torrent_file.announce_list = magnet
+        .trackers
+        .iter()
+        .map(|tracker| vec![tracker.clone()])
+        .collect();
+    torrent_file.url_list = magnet.web_seeds.clone();
+    Ok(torrent_file)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::{mse::EncryptionPolicy, tcp::PEER_ID_PREFIX, transport::Dialer};
+
+    async fn read_message(stream: &mut TcpStream) -> Vec<u8> {
+        let length = stream.read_u32().await.unwrap();
+        let mut message = vec![0; length as usize];
+        stream.read_exact(&mut message).await.unwrap();
+        message
+    }
+
+    async fn write_extended(stream: &mut TcpStream, id: u8, header: &BencodeValue, data: &[u8]) {
+        let header = bencode::encode(header);
+        let length = 2 + header.len() + data.len();
+        stream.write_u32(length as u32).await.unwrap();
+        stream.write_all(&[20, id]).await.unwrap();
+        stream.write_all(&header).await.unwrap();
+        stream.write_all(data).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fetch_metadata_from_peer() {
+        // Big enough to span two metadata pieces
+        let name = "a".repeat(METADATA_PIECE_SIZE);
+        let raw_info = format!(
+            "d6:lengthi1e4:name{}:{}12:piece lengthi16384e6:pieces20:{}e",
+            name.len(),
+            name,
+            "x".repeat(20)
+        )
+        .into_bytes();
+        let magnet = MagnetLink {
+            info_hash: hash_bytes(&raw_info),
+            trackers: vec!["http://tracker.example/announce".to_string()],
+            ..Default::default()
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer_address = listener.local_addr().unwrap();
+        let served = raw_info.clone();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0u8; 68];
+            stream.read_exact(&mut handshake).await.unwrap();
+            assert_eq!(handshake[25] & 0x10, 0x10);
+            assert_eq!(&handshake[48..56], PEER_ID_PREFIX);
+            handshake[48..].copy_from_slice(b"-XX0000-remotepeerid");
+            stream.write_all(&handshake).await.unwrap();
+
+            let message = read_message(&mut stream).await;
+            assert_eq!(&message[..2], &[20, EXTENDED_HANDSHAKE_ID]);
+            let reply = bencode::decode(
+                format!("d1:md11:ut_metadatai3ee13:metadata_sizei{}ee", served.len()).as_bytes(),
+            )
+            .unwrap();
+            write_extended(&mut stream, EXTENDED_HANDSHAKE_ID, &reply, &[]).await;
+
+            for piece in 0..served.len().div_ceil(METADATA_PIECE_SIZE) {
+                let message = read_message(&mut stream).await;
+                assert_eq!(&message[..2], &[20, 3]);
+                let start = piece * METADATA_PIECE_SIZE;
+                let end = served.len().min(start + METADATA_PIECE_SIZE);
+                let mut header = metadata_message(1, piece);
+                if let BencodeValue::Dict(header) = &mut header {
+                    header.insert(
+                        b"total_size".to_vec(),
+                        BencodeValue::from(served.len() as i64),
+                    );
+                }
+                write_extended(&mut stream, UT_METADATA_ID, &header, &served[start..end]).await;
+            }
+        });
+
+        let config = ConnectionConfig {
+            dialer: Dialer::tcp(),
+            encryption: EncryptionPolicy::Disabled,
+            ..Default::default()
+        };
+        let fetched = fetch_info(peer_address, &magnet, &config).await.unwrap();
+        assert_eq!(fetched, raw_info);
+
+        let torrent_file = torrent_from_info(&magnet, &fetched).unwrap();
+        assert_eq!(torrent_file.info_hash(), magnet.info_hash);
+        assert_eq!(torrent_file.announce, magnet.trackers[0]);
+    }
+}
@@ -0,0 +1,419 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use clap::ValueEnum;
+use num_bigint::BigUint;
+use rand::{Rng, RngCore};
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::{
+    error::{Error, Result},
+    hasher::hash_bytes,
+};
+
+/// The 768-bit safe prime from the MSE specification; the generator is 2.
+const PRIME: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const KEY_LENGTH: usize = 96;
+const MAX_PADDING: usize = 512;
+const VC: [u8; 8] = [0; 8];
+
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+
+const PROTOCOL_HEADER: &[u8; 20] = b"\x13BitTorrent protocol";
+
+/// Which connections are wrapped in Message Stream Encryption.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EncryptionPolicy {
+    /// Plaintext only; encrypted handshakes are refused.
+    Disabled,
+    /// Prefer RC4 but fall back to plaintext for peers that don't support it.
+    #[default]
+    Enabled,
+    /// Only RC4-encrypted connections are allowed.
+    Forced,
+}
+
+impl EncryptionPolicy {
+    fn crypto_provide(self) -> u32 {
+        match self {
+            EncryptionPolicy::Disabled => CRYPTO_PLAINTEXT,
+            EncryptionPolicy::Enabled => CRYPTO_PLAINTEXT | CRYPTO_RC4,
+            EncryptionPolicy::Forced => CRYPTO_RC4,
+        }
+    }
+
+    fn select(self, crypto_provide: u32) -> Option<u32> {
+        let allowed = crypto_provide & self.crypto_provide();
+        if allowed & CRYPTO_RC4 != 0 {
+            Some(CRYPTO_RC4)
+        } else if allowed & CRYPTO_PLAINTEXT != 0 {
+            Some(CRYPTO_PLAINTEXT)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    /// MSE discards the first 1024 bytes of keystream.
+    fn new(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+        for (index, value) in state.iter_mut().enumerate() {
+            *value = index as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        let mut rc4 = Rc4 { state, i: 0, j: 0 };
+        rc4.apply(&mut [0u8; 1024]);
+        rc4
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let index = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[index as usize];
+        }
+    }
+}
+
+/// A peer connection after the MSE handshake. Reads and writes pass through
+/// RC4 when it was negotiated and are untouched otherwise.
+pub struct MseStream<S> {
+    inner: S,
+    read_cipher: Option<Rc4>,
+    write_cipher: Option<Rc4>,
+    /// Already decrypted bytes received during the handshake.
+    prefix: Vec<u8>,
+}
+
+impl<S> MseStream<S> {
+    fn new(inner: S, ciphers: Option<(Rc4, Rc4)>, prefix: Vec<u8>) -> Self {
+        let (read_cipher, write_cipher) = ciphers.unzip();
+        MseStream {
+            inner,
+            read_cipher,
+            write_cipher,
+            prefix,
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.write_cipher.is_some()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MseStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.prefix.is_empty() {
+            let take = this.prefix.len().min(buf.remaining());
+            buf.put_slice(&this.prefix[..take]);
+            this.prefix.drain(..take);
+            return Poll::Ready(Ok(()));
+        }
+
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(cipher) = &mut this.read_cipher {
+            cipher.apply(&mut buf.filled_mut()[filled..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MseStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let Some(cipher) = &mut this.write_cipher else {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+
+        // Encrypt with a copy of the keystream and only advance the real one
+        // by what the socket accepted, so partial writes stay in sync.
+        let mut encrypted = buf.to_vec();
+        cipher.clone().apply(&mut encrypted);
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, &encrypted))?;
+        cipher.apply(&mut encrypted[..written]);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+struct KeyPair {
+    private: BigUint,
+    public: [u8; KEY_LENGTH],
+}
+
+impl KeyPair {
+    fn generate() -> Self {
+        let mut private = [0u8; 20];
+        rand::thread_rng().fill_bytes(&mut private);
+        let private = BigUint::from_bytes_be(&private);
+        let public = BigUint::from(2u8).modpow(&private, &prime());
+        KeyPair {
+            private,
+            public: to_key_bytes(&public),
+        }
+    }
+
+    fn shared_secret(&self, remote_public: &[u8]) -> [u8; KEY_LENGTH] {
+        let remote_public = BigUint::from_bytes_be(remote_public);
+        to_key_bytes(&remote_public.modpow(&self.private, &prime()))
+    }
+}
+
+fn prime() -> BigUint {
+    BigUint::parse_bytes(PRIME.as_bytes(), 16).unwrap()
+}
+
+fn to_key_bytes(value: &BigUint) -> [u8; KEY_LENGTH] {
+    let bytes = value.to_bytes_be();
+    let mut key = [0u8; KEY_LENGTH];
+    key[KEY_LENGTH - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    hash_bytes(&parts.concat())
+}
+
+fn random_padding() -> Vec<u8> {
+    let mut padding = vec![0u8; rand::thread_rng().gen_range(0..=MAX_PADDING)];
+    rand::thread_rng().fill_bytes(&mut padding);
+    padding
+}
+
+/// Sends our public key followed by random padding.
+async fn send_public_key<S: AsyncWrite + Unpin>(stream: &mut S) -> Result<KeyPair> {
+    let keys = KeyPair::generate();
+    let mut message = keys.public.to_vec();
+    message.extend(random_padding());
+    stream.write_all(&message).await?;
+    Ok(keys)
+}
+
+/// Skips the remote's random padding by reading until `pattern` has been seen.
+async fn synchronize<S: AsyncRead + Unpin>(stream: &mut S, pattern: &[u8]) -> Result<()> {
+    let mut seen = Vec::new();
+    while !seen.ends_with(pattern) {
+        if seen.len() >= MAX_PADDING + pattern.len() {
+            return Err(Error::Peer(
+                "Encryption handshake did not synchronize".into(),
+            ));
+        }
+        seen.push(stream.read_u8().await?);
+    }
+    Ok(())
+}
+
+async fn read_decrypted<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    cipher: &mut Rc4,
+    length: usize,
+) -> Result<Vec<u8>> {
+    let mut data = vec![0u8; length];
+    stream.read_exact(&mut data).await?;
+    cipher.apply(&mut data);
+    Ok(data)
+}
+
+/// Runs the initiating side of the MSE handshake for `info_hash`.
+pub async fn initiate<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    info_hash: &[u8; 20],
+    policy: EncryptionPolicy,
+) -> Result<MseStream<S>> {
+    if policy == EncryptionPolicy::Disabled {
+        return Err(Error::Peer("Encryption is disabled".into()));
+    }
+    let keys = send_public_key(&mut stream).await?;
+    let mut remote_public = [0u8; KEY_LENGTH];
+    stream.read_exact(&mut remote_public).await?;
+    let secret = keys.shared_secret(&remote_public);
+
+    let mut encrypt = Rc4::new(&hash(&[b"keyA", &secret, info_hash]));
+    let mut decrypt = Rc4::new(&hash(&[b"keyB", &secret, info_hash]));
+
+    let mut message = hash(&[b"req1", &secret]).to_vec();
+    let req2 = hash(&[b"req2", info_hash]);
+    let req3 = hash(&[b"req3", &secret]);
+    message.extend(req2.iter().zip(req3).map(|(a, b)| a ^ b));
+
+    let mut payload = VC.to_vec();
+    payload.extend(policy.crypto_provide().to_be_bytes());
+    payload.extend(0u16.to_be_bytes()); // len(PadC)
+    payload.extend(0u16.to_be_bytes()); // len(IA)
+    encrypt.apply(&mut payload);
+    message.extend(payload);
+    stream.write_all(&message).await?;
+
+    let mut encrypted_vc = VC;
+    decrypt.apply(&mut encrypted_vc);
+    synchronize(&mut stream, &encrypted_vc).await?;
+
+    let reply = read_decrypted(&mut stream, &mut decrypt, 6).await?;
+    let crypto_select = u32::from_be_bytes([reply[0], reply[1], reply[2], reply[3]]);
+    let padding_length = u16::from_be_bytes([reply[4], reply[5]]) as usize;
+    if padding_length > MAX_PADDING {
+        return Err(Error::Peer(format!(
+            "Peer sent {} bytes of padding",
+            padding_length
+        )));
+    }
+    read_decrypted(&mut stream, &mut decrypt, padding_length).await?;
+
+    match crypto_select {
+        CRYPTO_RC4 => Ok(MseStream::new(stream, Some((decrypt, encrypt)), Vec::new())),
+        CRYPTO_PLAINTEXT if policy != EncryptionPolicy::Forced => {
+            Ok(MseStream::new(stream, None, Vec::new()))
+        }
+        _ => Err(Error::Peer(format!(
+            "Peer selected unsupported crypto {:#x}",
+            crypto_select
+        ))),
+    }
+}
+
+/// Runs the receiving side for an incoming connection. Plaintext handshakes
+/// are passed through untouched unless encryption is forced. For encrypted
+/// connections the info hash the peer asked for is returned as well.
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    info_hashes: &[[u8; 20]],
+    policy: EncryptionPolicy,
+) -> Result<(MseStream<S>, Option<[u8; 20]>)> {
+    let mut start = [0u8; PROTOCOL_HEADER.len()];
+    stream.read_exact(&mut start).await?;
+    if &start == PROTOCOL_HEADER {
+        if policy == EncryptionPolicy::Forced {
+            return Err(Error::Peer("Refusing plaintext connection".into()));
+        }
+        return Ok((MseStream::new(stream, None, start.to_vec()), None));
+    }
+    if policy == EncryptionPolicy::Disabled {
+        return Err(Error::Peer("Refusing encrypted connection".into()));
+    }
+
+    let mut remote_public = [0u8; KEY_LENGTH];
+    remote_public[..start.len()].copy_from_slice(&start);
+    stream.read_exact(&mut remote_public[start.len()..]).await?;
+    let keys = send_public_key(&mut stream).await?;
+    let secret = keys.shared_secret(&remote_public);
+
+    synchronize(&mut stream, &hash(&[b"req1", &secret])).await?;
+    let mut obfuscated = [0u8; 20];
+    stream.read_exact(&mut obfuscated).await?;
+    let req3 = hash(&[b"req3", &secret]);
+    let info_hash = *info_hashes
+        .iter()
+        .find(|info_hash| {
+            let req2 = hash(&[b"req2", info_hash.as_slice()]);
+            req2.iter().zip(req3).map(|(a, b)| a ^ b).eq(obfuscated)
+        })
+        .ok_or_else(|| Error::Peer("Peer asked for an unknown torrent".into()))?;
+
+    let mut decrypt = Rc4::new(&hash(&[b"keyA", &secret, &info_hash]));
+    let mut encrypt = Rc4::new(&hash(&[b"keyB", &secret, &info_hash]));
+
+    let header = read_decrypted(&mut stream, &mut decrypt, 14).await?;
+    if header[..8] != VC {
+        return Err(Error::Peer("Bad verification constant".into()));
+    }
+    let crypto_provide = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+    let padding_length = u16::from_be_bytes([header[12], header[13]]) as usize;
+    if padding_length > MAX_PADDING {
+        return Err(Error::Peer(format!(
+            "Peer sent {} bytes of padding",
+            padding_length
+        )));
+    }
+    read_decrypted(&mut stream, &mut decrypt, padding_length).await?;
+    let initial_length = read_decrypted(&mut stream, &mut decrypt, 2).await?;
+    let initial_length = u16::from_be_bytes([initial_length[0], initial_length[1]]) as usize;
+    let initial_payload = read_decrypted(&mut stream, &mut decrypt, initial_length).await?;
+
+    let crypto_select = policy
+        .select(crypto_provide)
+        .ok_or_else(|| Error::Peer("No common crypto method with peer".into()))?;
+    let mut reply = VC.to_vec();
+    reply.extend(crypto_select.to_be_bytes());
+    reply.extend(0u16.to_be_bytes()); // len(PadD)
+    encrypt.apply(&mut reply);
+    stream.write_all(&reply).await?;
+
+    let ciphers = (crypto_select == CRYPTO_RC4).then_some((decrypt, encrypt));
+    Ok((
+        MseStream::new(stream, ciphers, initial_payload),
+        Some(info_hash),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_encrypted_handshake_roundtrip() {
+        let info_hash = [7u8; 20];
+        let (local, remote) = tokio::io::duplex(4096);
+
+        let incoming = tokio::spawn(async move {
+            let (mut stream, asked_for) =
+                accept(remote, &[[1u8; 20], info_hash], EncryptionPolicy::Forced)
+                    .await
+                    .unwrap();
+            let mut message = [0u8; 5];
+            stream.read_exact(&mut message).await.unwrap();
+            stream.write_all(b"world").await.unwrap();
+            (message, asked_for, stream.is_encrypted())
+        });
+
+        let mut stream = initiate(local, &info_hash, EncryptionPolicy::Enabled)
+            .await
+            .unwrap();
+        assert!(stream.is_encrypted());
+        stream.write_all(b"hello").await.unwrap();
+        let mut reply = [0u8; 5];
+        stream.read_exact(&mut reply).await.unwrap();
+
+        let (message, asked_for, encrypted) = incoming.await.unwrap();
+        assert_eq!(&message, b"hello");
+        assert_eq!(&reply, b"world");
+        assert_eq!(asked_for, Some(info_hash));
+        assert!(encrypted);
+    }
+}
@@ -1,95 +1,816 @@
-use std::{cmp::min, env::current_dir, fs::File, io::Read, net::Ipv4Addr};
+use std::{
+    cmp::min,
+    collections::BTreeMap,
+    fs,
+    net::SocketAddr,
+    path::{Component, Path, PathBuf},
+};
 
-use anyhow::{anyhow, Error, Ok, Result};
-use reqwest::Client;
-use serde::{Deserialize, Serialize};
-
-use crate::{hasher::bytes_to_hex_url_encoded, request::TrackerResponse};
+use crate::{
+    bencode::{self, BencodeValue},
+    error::{Error, Result},
+    hasher::{hash_bytes, hash_bytes_sha256},
+    merkle::{self, Hash, BLOCK_SIZE},
+    request::TrackerClient,
+};
 #[derive(Debug, Default)]
 pub struct Parser;
 
 impl Parser {
+    /// Parses the contents of a `.torrent` file.
     pub fn parse_torrent_file(input: &[u8]) -> Result<TorrentFile> {
-        serde_bencode::from_bytes(input).map_err(|e| anyhow!("Failed to parse input: {}", e))
+        let (value, spans) = bencode::decode_dict_with_spans(input)
+            .map_err(|e| Error::Metainfo(format!("Failed to parse input: {}", e)))?;
+        let info_span = spans
+            .get(b"info".as_slice())
+            .ok_or_else(|| Error::Metainfo("Missing field `info` in metainfo".into()))?;
+        TorrentFile::from_bencode(&value, &input[info_span.clone()])
     }
 }
-#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+/// A parsed metainfo file: trackers, web seeds and the `info` dictionary.
+#[derive(Debug, Default, PartialEq)]
 pub struct TorrentFile {
     pub announce: String,
+    pub announce_list: Vec<Vec<String>>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    pub creation_date: Option<i64>,
+    pub url_list: Vec<String>,
     pub info: TorrentInfo,
+    /// BEP 52 piece layers keyed by each file's `pieces root`.
+    pub piece_layers: BTreeMap<Hash, Vec<Hash>>,
+    info_hash: [u8; 20],
+    info_hash_v2: Option<Hash>,
 }
-#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Default, PartialEq)]
 pub struct TorrentInfo {
-    pub length: u32,
+    /// Total length of the content; for multi-file torrents the sum of `files`.
+    pub length: u64,
     pub name: String,
-    #[serde(rename = "piece length")]
     pub piece_length: u32,
-    #[serde(with = "serde_bytes")]
     pub pieces: Vec<u8>,
+    /// Empty for single-file torrents.
+    pub files: Vec<FileEntry>,
+    pub private: bool,
+    /// `Some(2)` for v2 and hybrid torrents (BEP 52).
+    pub meta_version: Option<i64>,
+    /// v2 `file tree` flattened in tree order; empty for v1-only torrents.
+    pub file_tree: Vec<V2File>,
+    /// [`TorrentInfo::v2_pieces`], laid out once when parsed or built.
+    pub(crate) v2_pieces: Vec<V2Piece>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FileEntry {
+    pub length: u64,
+    pub path: Vec<String>,
+    /// BEP 47 attributes; `p` marks the padding files of hybrid torrents.
+    pub attr: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct V2File {
+    pub path: Vec<String>,
+    pub length: u64,
+    /// Absent for empty files.
+    pub pieces_root: Option<Hash>,
+}
+
+/// A file as laid out on disk and in the piece space.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContentFile {
+    /// Relative path: `[name]` for single-file torrents, `[name, ..path]` otherwise.
+    pub path: Vec<String>,
+    pub length: u64,
+    /// BEP 47 padding; zeros that are never written to disk.
+    pub padding: bool,
+}
+
+/// A byte range of one file that makes up part of a piece.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileSpan {
+    pub path: Vec<String>,
+    pub offset: u64,
+    pub length: u64,
+    pub padding: bool,
+}
+
+/// Where a v2 piece lives: the file it belongs to and its index within that file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct V2Piece {
+    pub file: usize,
+    pub index_in_file: u32,
+    pub length: u32,
+}
+
+fn required<'a>(dict: &'a BencodeValue, key: &str) -> Result<&'a BencodeValue> {
+    dict.get(key)
+        .ok_or_else(|| Error::Metainfo(format!("Missing field `{}` in metainfo", key)))
+}
+
+fn required_str(dict: &BencodeValue, key: &str) -> Result<String> {
+    required(dict, key)?
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| Error::Metainfo(format!("Field `{}` must be a UTF-8 string", key)))
+}
+
+fn required_u64(dict: &BencodeValue, key: &str) -> Result<u64> {
+    required(dict, key)?
+        .as_integer()
+        .and_then(|integer| u64::try_from(integer).ok())
+        .ok_or_else(|| Error::Metainfo(format!("Field `{}` must be a non-negative integer", key)))
+}
+
+fn optional_str(dict: &BencodeValue, key: &str) -> Option<String> {
+    dict.get(key)?.as_str().map(str::to_string)
+}
+
+/// Whether `component` can be joined onto a download directory without
+/// leaving it: a single plain name, not `.` or `..`, and without separators.
+pub(crate) fn is_plain_component(component: &str) -> bool {
+    let mut components = Path::new(component).components();
+    !matches!(component, "" | "." | "..")
+        && !component.contains(['/', '\\', '\0'])
+        && matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        )
+}
+
+/// Reads a list of strings, tolerating the single-string form some tools write.
+fn string_list(value: &BencodeValue) -> Vec<String> {
+    match value.as_list() {
+        Some(list) => list
+            .iter()
+            .filter_map(|item| item.as_str().map(str::to_string))
+            .collect(),
+        None => value.as_str().map(str::to_string).into_iter().collect(),
+    }
+}
+
+fn dict(entries: Vec<(&str, BencodeValue)>) -> BencodeValue {
+    BencodeValue::Dict(
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.as_bytes().to_vec(), value))
+            .collect(),
+    )
+}
+
+fn list_of_strings(strings: &[String]) -> BencodeValue {
+    BencodeValue::List(strings.iter().cloned().map(BencodeValue::from).collect())
+}
+
+impl FileEntry {
+    fn from_bencode(value: &BencodeValue) -> Result<FileEntry> {
+        let path = required(value, "path")?
+            .as_list()
+            .ok_or_else(|| Error::Metainfo("Field `path` must be a list".into()))?
+            .iter()
+            .map(|component| {
+                component
+                    .as_str()
+                    .filter(|component| is_plain_component(component))
+                    .map(str::to_string)
+                    .ok_or_else(|| Error::Metainfo("Invalid path component in `files`".into()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if path.is_empty() {
+            return Err(Error::Metainfo("Empty path in `files`".into()));
+        }
+
+        Ok(FileEntry {
+            length: required_u64(value, "length")?,
+            path,
+            attr: optional_str(value, "attr"),
+        })
+    }
+
+    fn to_bencode(&self) -> BencodeValue {
+        let mut entries = vec![
+            ("length", BencodeValue::Integer(self.length as i64)),
+            ("path", list_of_strings(&self.path)),
+        ];
+        if let Some(attr) = &self.attr {
+            entries.push(("attr", BencodeValue::from(attr.as_str())));
+        }
+        dict(entries)
+    }
+
+    pub fn is_padding(&self) -> bool {
+        self.attr.as_deref().is_some_and(|attr| attr.contains('p'))
+    }
+}
+
+impl V2File {
+    fn collect(node: &BencodeValue, path: &mut Vec<String>, files: &mut Vec<V2File>) -> Result<()> {
+        let entries = node
+            .as_dict()
+            .ok_or_else(|| Error::Metainfo("Field `file tree` must contain dictionaries".into()))?;
+        for (name, child) in entries {
+            if name.is_empty() {
+                let length = required_u64(child, "length")?;
+                let pieces_root = match child.get("pieces root") {
+                    Some(root) => Some(
+                        root.as_bytes()
+                            .and_then(|root| Hash::try_from(root).ok())
+                            .ok_or_else(|| {
+                                Error::Metainfo("Field `pieces root` must be 32 bytes".into())
+                            })?,
+                    ),
+                    None if length > 0 => {
+                        return Err(Error::Metainfo(
+                            "Missing `pieces root` for a non-empty file".into(),
+                        ))
+                    }
+                    None => None,
+                };
+                if path.is_empty() {
+                    return Err(Error::Metainfo("File without a name in `file tree`".into()));
+                }
+                files.push(V2File {
+                    path: path.clone(),
+                    length,
+                    pieces_root,
+                });
+                continue;
+            }
+
+            let component = std::str::from_utf8(name)
+                .ok()
+                .filter(|component| is_plain_component(component))
+                .ok_or_else(|| Error::Metainfo("Invalid path component in `file tree`".into()))?;
+            path.push(component.to_string());
+            V2File::collect(child, path, files)?;
+            path.pop();
+        }
+        Ok(())
+    }
+
+    fn tree_to_bencode(files: &[V2File]) -> BencodeValue {
+        let mut tree = BencodeValue::Dict(BTreeMap::new());
+        for file in files {
+            let mut node = &mut tree;
+            for component in &file.path {
+                let BencodeValue::Dict(children) = node else {
+                    unreachable!("file tree nodes are dictionaries");
+                };
+                node = children
+                    .entry(component.as_bytes().to_vec())
+                    .or_insert_with(|| BencodeValue::Dict(BTreeMap::new()));
+            }
+            let mut leaf = vec![("length", BencodeValue::Integer(file.length as i64))];
+            if let Some(pieces_root) = file.pieces_root {
+                leaf.push(("pieces root", BencodeValue::Bytes(pieces_root.to_vec())));
+            }
+            if let BencodeValue::Dict(children) = node {
+                children.insert(Vec::new(), dict(leaf));
+            }
+        }
+        tree
+    }
+}
+
+impl TorrentInfo {
+    pub fn from_bencode(value: &BencodeValue) -> Result<TorrentInfo> {
+        let meta_version = value.get("meta version").and_then(BencodeValue::as_integer);
+        if let Some(version @ (..=0 | 3..)) = meta_version {
+            return Err(Error::Metainfo(format!(
+                "Unsupported meta version {}",
+                version
+            )));
+        }
+
+        let pieces = match value.get("pieces") {
+            Some(pieces) => pieces
+                .as_bytes()
+                .ok_or_else(|| Error::Metainfo("Field `pieces` must be a byte string".into()))?,
+            None if meta_version == Some(2) => &[],
+            None => return Err(Error::Metainfo("Missing field `pieces` in metainfo".into())),
+        };
+        if pieces.len() % 20 != 0 {
+            return Err(Error::Metainfo(
+                "Field `pieces` is not a multiple of 20 bytes".into(),
+            ));
+        }
+
+        let piece_length = u32::try_from(required_u64(value, "piece length")?)
+            .map_err(|_| Error::Metainfo("Field `piece length` is too large".into()))?;
+        if piece_length == 0 && !pieces.is_empty() {
+            return Err(Error::Metainfo(
+                "Field `piece length` must be positive".into(),
+            ));
+        }
+
+        let mut file_tree = Vec::new();
+        if meta_version == Some(2) {
+            if (piece_length as usize) < BLOCK_SIZE || !piece_length.is_power_of_two() {
+                return Err(Error::Metainfo(
+                    "v2 piece length must be a power of two of at least 16 KiB".into(),
+                ));
+            }
+            V2File::collect(
+                required(value, "file tree")?,
+                &mut Vec::new(),
+                &mut file_tree,
+            )?;
+        }
+
+        let files = match value.get("files") {
+            Some(files) => files
+                .as_list()
+                .ok_or_else(|| Error::Metainfo("Field `files` must be a list".into()))?
+                .iter()
+                .map(FileEntry::from_bencode)
+                .collect::<Result<Vec<_>>>()?,
+            None => Vec::new(),
+        };
+        let length = if !files.is_empty() {
+            files.iter().map(|file| file.length).sum()
+        } else if value.get("length").is_some() || file_tree.is_empty() {
+            required_u64(value, "length")?
+        } else {
+            file_tree.iter().map(|file| file.length).sum()
+        };
+
+        let name = required_str(value, "name")?;
+        if !is_plain_component(&name) {
+            return Err(Error::Metainfo(format!("Invalid torrent name {:?}", name)));
+        }
+
+        let mut info = TorrentInfo {
+            length,
+            name,
+            piece_length,
+            pieces: pieces.to_vec(),
+            files,
+            private: value.get("private").and_then(BencodeValue::as_integer) == Some(1),
+            meta_version,
+            file_tree,
+            v2_pieces: Vec::new(),
+        };
+        info.v2_pieces = info.layout_v2_pieces();
+        if info.has_v1() && info.has_v2() {
+            info.check_hybrid_layout()?;
+        }
+        Ok(info)
+    }
+
+    /// A hybrid torrent's v1 files must be its v2 files, each starting on a
+    /// piece boundary, so that every piece holds the same bytes in both.
+    fn check_hybrid_layout(&self) -> Result<()> {
+        let v1_files = self
+            .content_files()
+            .into_iter()
+            .filter(|file| !file.padding)
+            .map(|file| (file.path, file.length));
+        let v2_files = self.content_files_v2().into_iter();
+        let same_files = v1_files.eq(v2_files.map(|file| (file.path, file.length)));
+
+        let mut aligned = true;
+        let mut offset = 0;
+        for file in self.content_files() {
+            if !file.padding && file.length > 0 {
+                aligned &= offset % self.piece_length as u64 == 0;
+            }
+            offset += file.length;
+        }
+
+        if !same_files || !aligned || self.pieces.len() / 20 != self.v2_pieces.len() {
+            return Err(Error::Metainfo(
+                "Hybrid torrent's `files` and `file tree` disagree".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn to_bencode(&self) -> BencodeValue {
+        let mut entries = vec![
+            ("name", BencodeValue::from(self.name.as_str())),
+            (
+                "piece length",
+                BencodeValue::Integer(self.piece_length as i64),
+            ),
+        ];
+        if self.has_v2() {
+            entries.push(("meta version", BencodeValue::Integer(2)));
+            entries.push(("file tree", V2File::tree_to_bencode(&self.file_tree)));
+        }
+        if self.has_v1() {
+            entries.push(("pieces", BencodeValue::Bytes(self.pieces.clone())));
+        }
+        if !self.has_v1() {
+            // v2-only torrents describe their files in `file tree` alone
+        } else if self.files.is_empty() {
+            entries.push(("length", BencodeValue::Integer(self.length as i64)));
+        } else {
+            entries.push((
+                "files",
+                BencodeValue::List(self.files.iter().map(FileEntry::to_bencode).collect()),
+            ));
+        }
+        if self.private {
+            entries.push(("private", BencodeValue::Integer(1)));
+        }
+        dict(entries)
+    }
+
+    /// Whether the torrent carries v1 metadata (`pieces`); false only for v2-only torrents.
+    pub fn has_v1(&self) -> bool {
+        !self.has_v2() || !self.pieces.is_empty()
+    }
+
+    pub fn has_v2(&self) -> bool {
+        self.meta_version == Some(2)
+    }
+
+    pub fn piece_count(&self) -> usize {
+        if self.has_v1() {
+            self.pieces.len() / 20
+        } else {
+            self.v2_pieces().len()
+        }
+    }
+
+    /// v2 pieces in swarm order. Each non-empty file starts on a piece boundary,
+    /// so a file's last piece may be short.
+    pub fn v2_pieces(&self) -> &[V2Piece] {
+        &self.v2_pieces
+    }
+
+    fn layout_v2_pieces(&self) -> Vec<V2Piece> {
+        let piece_length = self.piece_length as u64;
+        let mut pieces = Vec::new();
+        for (file_index, file) in self.file_tree.iter().enumerate() {
+            for index_in_file in 0..file.length.div_ceil(piece_length) {
+                let offset = index_in_file * piece_length;
+                pieces.push(V2Piece {
+                    file: file_index,
+                    index_in_file: index_in_file as u32,
+                    length: min(piece_length, file.length - offset) as u32,
+                });
+            }
+        }
+        pieces
+    }
+
+    /// Files in the order their bytes appear in the piece space, including
+    /// hybrid padding files.
+    pub fn content_files(&self) -> Vec<ContentFile> {
+        if !self.has_v1() {
+            return self.content_files_v2();
+        }
+        if self.files.is_empty() {
+            return vec![ContentFile {
+                path: vec![self.name.clone()],
+                length: self.length,
+                padding: false,
+            }];
+        }
+        self.files
+            .iter()
+            .map(|file| ContentFile {
+                path: self.with_name(&file.path),
+                length: file.length,
+                padding: file.is_padding(),
+            })
+            .collect()
+    }
+
+    /// The files of `file tree`, which has no padding.
+    fn content_files_v2(&self) -> Vec<ContentFile> {
+        let single_file =
+            self.file_tree.len() == 1 && self.file_tree[0].path == [self.name.clone()];
+        self.file_tree
+            .iter()
+            .map(|file| ContentFile {
+                path: if single_file {
+                    file.path.clone()
+                } else {
+                    self.with_name(&file.path)
+                },
+                length: file.length,
+                padding: false,
+            })
+            .collect()
+    }
+
+    fn with_name(&self, path: &[String]) -> Vec<String> {
+        let mut full = vec![self.name.clone()];
+        full.extend(path.iter().cloned());
+        full
+    }
+
+    pub fn is_single_file(&self) -> bool {
+        let files = self.content_files();
+        files.len() == 1 && files[0].path.len() == 1
+    }
+
+    /// Files with their paths relative to the download directory, in torrent order.
+    pub fn file_paths(&self) -> Vec<(PathBuf, u64)> {
+        self.content_files()
+            .into_iter()
+            .filter(|file| !file.padding)
+            .map(|file| (file.path.iter().collect(), file.length))
+            .collect()
+    }
+
+    /// The ranges of each file that the bytes of piece `piece_index` come from, in order.
+    pub fn piece_spans(&self, piece_index: u32) -> Vec<FileSpan> {
+        let files = self.content_files();
+
+        if !self.has_v1() {
+            return self
+                .v2_pieces()
+                .get(piece_index as usize)
+                .map(|piece| FileSpan {
+                    path: files[piece.file].path.clone(),
+                    offset: piece.index_in_file as u64 * self.piece_length as u64,
+                    length: piece.length as u64,
+                    padding: false,
+                })
+                .into_iter()
+                .collect();
+        }
+
+        let start = piece_index as u64 * self.piece_length as u64;
+        let end = min(start + self.piece_length as u64, self.length);
+        let mut spans = Vec::new();
+        let mut file_start = 0;
+        for file in files {
+            let file_end = file_start + file.length;
+            if file_end > start && file_start < end {
+                let offset = start.max(file_start) - file_start;
+                spans.push(FileSpan {
+                    offset,
+                    length: end.min(file_end) - file_start - offset,
+                    path: file.path,
+                    padding: file.padding,
+                });
+            }
+            file_start = file_end;
+        }
+        spans
+    }
+}
+
+fn swarm_info_hash(info: &TorrentInfo, raw_info: &[u8]) -> [u8; 20] {
+    if info.has_v1() {
+        return hash_bytes(raw_info);
+    }
+    let mut truncated = [0u8; 20];
+    truncated.copy_from_slice(&hash_bytes_sha256(raw_info)[..20]);
+    truncated
+}
+
+/// Reads `piece layers` and checks every layer against its file's `pieces root`.
+fn parse_piece_layers(
+    value: &BencodeValue,
+    info: &TorrentInfo,
+) -> Result<BTreeMap<Hash, Vec<Hash>>> {
+    let mut piece_layers = BTreeMap::new();
+    if let Some(layers) = value.get("piece layers") {
+        let layers = layers
+            .as_dict()
+            .ok_or_else(|| Error::Metainfo("Field `piece layers` must be a dictionary".into()))?;
+        for (root, layer) in layers {
+            let root = Hash::try_from(root.as_slice())
+                .map_err(|_| Error::Metainfo("Piece layer keys must be 32 bytes".into()))?;
+            let layer = layer
+                .as_bytes()
+                .filter(|layer| layer.len() % 32 == 0)
+                .ok_or_else(|| {
+                    Error::Metainfo("Piece layers must be a multiple of 32 bytes".into())
+                })?
+                .chunks(32)
+                .map(|hash| Hash::try_from(hash).unwrap())
+                .collect::<Vec<_>>();
+            if merkle::file_root_from_layer(&layer, info.piece_length) != root {
+                return Err(Error::Metainfo(
+                    "Piece layer does not match its pieces root".into(),
+                ));
+            }
+            piece_layers.insert(root, layer);
+        }
+    }
+
+    for file in &info.file_tree {
+        if file.length > info.piece_length as u64 {
+            let root = file.pieces_root.unwrap_or_default();
+            if !piece_layers.contains_key(&root) {
+                return Err(Error::Metainfo(format!(
+                    "Missing piece layer for {}",
+                    file.path.join("/")
+                )));
+            }
+        }
+    }
+    Ok(piece_layers)
 }
 
 impl TorrentFile {
-    pub fn parse_file_from_path(path: &String) -> anyhow::Result<TorrentFile> {
-        let file_path = current_dir().unwrap().join(path);
-        let mut file = File::open(file_path).unwrap();
+    /// `raw_info` must be the exact bytes the `info` dictionary was decoded from;
+    /// the info hash is taken over them rather than over a re-encoding.
+    pub fn from_bencode(value: &BencodeValue, raw_info: &[u8]) -> Result<TorrentFile> {
+        let announce_list = value
+            .get("announce-list")
+            .and_then(BencodeValue::as_list)
+            .map(|tiers| {
+                tiers
+                    .iter()
+                    .map(string_list)
+                    .filter(|tier| !tier.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
 
-        let mut contents = Vec::new();
-        file.read_to_end(&mut contents).unwrap();
+        let info = TorrentInfo::from_bencode(required(value, "info")?)?;
+        let info_hash_v2 = info.has_v2().then(|| hash_bytes_sha256(raw_info));
+        let piece_layers = if info.has_v2() {
+            parse_piece_layers(value, &info)?
+        } else {
+            BTreeMap::new()
+        };
 
-        let torrent_file = Parser::parse_torrent_file(contents.as_ref()).unwrap();
-        Ok(torrent_file)
+        Ok(TorrentFile {
+            announce: optional_str(value, "announce").unwrap_or_default(),
+            announce_list,
+            comment: optional_str(value, "comment"),
+            created_by: optional_str(value, "created by"),
+            creation_date: value
+                .get("creation date")
+                .and_then(BencodeValue::as_integer),
+            url_list: value.get("url-list").map(string_list).unwrap_or_default(),
+            info_hash: swarm_info_hash(&info, raw_info),
+            info,
+            piece_layers,
+            info_hash_v2,
+        })
     }
 
-    pub async fn discover_peers(&self) -> Result<Vec<(Ipv4Addr, u16)>, Error> {
-        let client = Client::new();
+    /// Builds a torrent from a freshly constructed `info` dictionary.
+    pub fn new(announce: String, mut info: TorrentInfo) -> TorrentFile {
+        info.v2_pieces = info.layout_v2_pieces();
+        let raw_info = bencode::encode(&info.to_bencode());
+        TorrentFile {
+            announce,
+            info_hash: swarm_info_hash(&info, &raw_info),
+            info_hash_v2: info.has_v2().then(|| hash_bytes_sha256(&raw_info)),
+            info,
+            ..Default::default()
+        }
+    }
 
-        let url_encoded_info_hash =
-            bytes_to_hex_url_encoded(&serde_bencode::to_bytes(&self.info).unwrap());
-        let url = format!("{}?info_hash={}", self.announce, url_encoded_info_hash);
+    pub fn to_bencode(&self) -> BencodeValue {
+        let mut entries = vec![("info", self.info.to_bencode())];
+        if !self.announce.is_empty() {
+            entries.push(("announce", BencodeValue::from(self.announce.as_str())));
+        }
+        if !self.announce_list.is_empty() {
+            entries.push((
+                "announce-list",
+                BencodeValue::List(
+                    self.announce_list
+                        .iter()
+                        .map(|tier| list_of_strings(tier))
+                        .collect(),
+                ),
+            ));
+        }
+        if let Some(comment) = &self.comment {
+            entries.push(("comment", BencodeValue::from(comment.as_str())));
+        }
+        if let Some(created_by) = &self.created_by {
+            entries.push(("created by", BencodeValue::from(created_by.as_str())));
+        }
+        if let Some(creation_date) = self.creation_date {
+            entries.push(("creation date", BencodeValue::Integer(creation_date)));
+        }
+        if !self.url_list.is_empty() {
+            entries.push(("url-list", list_of_strings(&self.url_list)));
+        }
+        if !self.piece_layers.is_empty() {
+            entries.push((
+                "piece layers",
+                BencodeValue::Dict(
+                    self.piece_layers
+                        .iter()
+                        .map(|(root, layer)| (root.to_vec(), BencodeValue::Bytes(layer.concat())))
+                        .collect(),
+                ),
+            ));
+        }
+        dict(entries)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bencode::encode(&self.to_bencode())
+    }
+
+    /// The 20-byte hash identifying the swarm in handshakes and announces: the v1
+    /// info hash, or the truncated v2 hash for v2-only torrents.
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.info_hash
+    }
+
+    pub fn info_hash_v1(&self) -> Option<[u8; 20]> {
+        self.info.has_v1().then_some(self.info_hash)
+    }
+
+    pub fn info_hash_v2(&self) -> Option<Hash> {
+        self.info_hash_v2
+    }
 
-        let req = client
-            .get(url)
-            .query(&[
-                ("peer_id", String::from("-TR2940-5f2b3b3b3b3b")),
-                ("port", String::from("6881")),
-                ("uploaded", String::from("0")),
-                ("downloaded", String::from("0")),
-                ("left", self.info.length.to_string()),
-                ("compact", String::from("1")),
-            ])
-            .build()?;
-        let response = client.execute(req).await?;
-        let response = response.bytes().await?;
+    /// Checks a downloaded piece against every hash the torrent carries: the
+    /// SHA-1 piece hash for v1 and the SHA-256 merkle tree of its 16 KiB blocks for v2.
+    pub fn verify_piece(&self, piece_index: u32, data: &[u8]) -> bool {
+        if data.len() != self.piece_length(piece_index) as usize {
+            return false;
+        }
 
-        let tracker_response = serde_bencode::from_bytes::<TrackerResponse>(&response)?;
+        if self.info.has_v1() {
+            let Some(expected) = self.info.pieces.chunks(20).nth(piece_index as usize) else {
+                return false;
+            };
+            if hash_bytes(data) != expected {
+                return false;
+            }
+        }
 
-        let mut peers = Vec::<(Ipv4Addr, u16)>::new();
-        for peer in tracker_response.peers.chunks(6) {
-            let mut ip = [0u8; 4];
-            ip.copy_from_slice(&peer[..4]);
-            let port = u16::from_be_bytes([peer[4], peer[5]]);
-            peers.push((Ipv4Addr::from(ip), port));
+        if self.info.has_v2() {
+            let Some(piece) = self.info.v2_pieces().get(piece_index as usize).copied() else {
+                return false;
+            };
+            // Hybrid pieces carry padding after the end of the file.
+            let Some(data) = data.get(..piece.length as usize) else {
+                return false;
+            };
+            let file = &self.info.file_tree[piece.file];
+            let Some(pieces_root) = file.pieces_root else {
+                return false;
+            };
+            if file.length <= self.info.piece_length as u64 {
+                return merkle::file_root(data) == pieces_root;
+            }
+            let Some(expected) = self
+                .piece_layers
+                .get(&pieces_root)
+                .and_then(|layer| layer.get(piece.index_in_file as usize))
+            else {
+                return false;
+            };
+            return merkle::piece_root(data, self.info.piece_length) == *expected;
         }
-        Ok(peers)
+
+        true
     }
 
-    pub fn piece_and_length(&self) -> Vec<(u32, u32)> {
-        let total_length = self.info.length;
-        let mut piece_index_and_length = Vec::new();
+    pub fn parse_file_from_path(path: impl AsRef<Path>) -> Result<TorrentFile> {
+        let contents = fs::read(path.as_ref()).map_err(|e| {
+            Error::Metainfo(format!("Cannot read {}: {}", path.as_ref().display(), e))
+        })?;
+        Parser::parse_torrent_file(&contents)
+    }
 
-        let mut piece_offset = 0;
-        let mut piece_length;
-        let mut piece_index = 0;
-        let standard_piece_length = self.info.piece_length;
-        while piece_offset < total_length {
-            piece_length = min(total_length - piece_offset, standard_piece_length);
+    pub async fn discover_peers(&self, tracker: &TrackerClient) -> Result<Vec<SocketAddr>, Error> {
+        tracker
+            .announce(&self.announce, &self.info_hash, self.info.length)
+            .await
+    }
 
-            piece_index_and_length.push((piece_index, piece_length));
-            piece_offset += standard_piece_length;
-            piece_index += 1;
+    pub fn piece_and_length(&self) -> Vec<(u32, u32)> {
+        if !self.info.has_v1() {
+            return self
+                .info
+                .v2_pieces()
+                .iter()
+                .enumerate()
+                .map(|(piece_index, piece)| (piece_index as u32, piece.length))
+                .collect();
         }
+        (0..self.info.piece_count() as u32)
+            .map(|piece_index| (piece_index, self.piece_length(piece_index)))
+            .collect()
+    }
 
-        piece_index_and_length
+    /// Length of the given piece. For v1 and hybrid torrents only the last piece
+    /// may be shorter than `piece length`; v2-only torrents also have a short
+    /// piece at the end of each file.
+    pub fn piece_length(&self, piece_index: u32) -> u32 {
+        if !self.info.has_v1() {
+            return self
+                .info
+                .v2_pieces()
+                .get(piece_index as usize)
+                .map_or(0, |piece| piece.length);
+        }
+        let standard_piece_length = self.info.piece_length as u64;
+        let piece_offset = piece_index as u64 * standard_piece_length;
+        min(
+            self.info.length.saturating_sub(piece_offset),
+            standard_piece_length,
+        ) as u32
     }
 }
 
@@ -99,11 +820,146 @@ mod tests {
 
     #[test]
     fn test_torrent_file_ser_deser() {
-        let torrent_file = TorrentFile::default();
+        let info = TorrentInfo {
+            name: String::from("a"),
+            ..Default::default()
+        };
+        let torrent_file = TorrentFile::new(String::new(), info);
 
-        let serialized_tf = serde_bencode::to_bytes(&torrent_file).unwrap();
+        let serialized_tf = torrent_file.to_bytes();
         let deserialized_tf = Parser::parse_torrent_file(&serialized_tf).unwrap();
 
         assert_eq!(torrent_file, deserialized_tf);
     }
+
+    #[test]
+    fn test_info_hash_covers_unmodelled_fields() {
+        let raw_info = b"d6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1e6:source3:abce";
+        let metainfo = [b"d8:announce3:url4:info".as_slice(), raw_info, b"e"].concat();
+
+        let torrent_file = Parser::parse_torrent_file(&metainfo).unwrap();
+
+        assert_eq!(torrent_file.info_hash(), hash_bytes(raw_info));
+    }
+
+    #[test]
+    fn test_v2_torrent_verifies_pieces() {
+        let piece_length = 2 * BLOCK_SIZE as u32;
+        let big = (0..5 * BLOCK_SIZE)
+            .map(|i| (i % 253) as u8)
+            .collect::<Vec<_>>();
+        let small = b"tiny".to_vec();
+        let layer = big
+            .chunks(piece_length as usize)
+            .map(|piece| merkle::piece_root(piece, piece_length))
+            .collect::<Vec<_>>();
+        let big_root = merkle::file_root_from_layer(&layer, piece_length);
+
+        let info = TorrentInfo {
+            length: (big.len() + small.len()) as u64,
+            name: String::from("dir"),
+            piece_length,
+            meta_version: Some(2),
+            file_tree: vec![
+                V2File {
+                    path: vec![String::from("a.bin")],
+                    length: big.len() as u64,
+                    pieces_root: Some(big_root),
+                },
+                V2File {
+                    path: vec![String::from("b.txt")],
+                    length: small.len() as u64,
+                    pieces_root: Some(merkle::file_root(&small)),
+                },
+            ],
+            ..Default::default()
+        };
+        let mut torrent_file = TorrentFile::new(String::new(), info);
+        torrent_file.piece_layers.insert(big_root, layer);
+
+        let parsed = Parser::parse_torrent_file(&torrent_file.to_bytes()).unwrap();
+        let piece = piece_length as usize;
+
+        assert_eq!(parsed.info_hash_v2(), torrent_file.info_hash_v2());
+        assert_eq!(parsed.info_hash_v1(), None);
+        assert_eq!(parsed.info.piece_count(), 4);
+        assert!(parsed.verify_piece(1, &big[piece..2 * piece]));
+        assert!(parsed.verify_piece(2, &big[2 * piece..]));
+        assert!(parsed.verify_piece(3, &small));
+        assert!(!parsed.verify_piece(0, &big[piece..2 * piece]));
+    }
+
+    #[test]
+    fn test_rejects_hybrid_with_mismatched_layouts() {
+        let content = vec![7u8; 16_000];
+        let hybrid = |v1_length: usize| TorrentInfo {
+            length: v1_length as u64,
+            name: String::from("a.bin"),
+            piece_length: BLOCK_SIZE as u32,
+            pieces: hash_bytes(&content[..v1_length]).to_vec(),
+            meta_version: Some(2),
+            file_tree: vec![V2File {
+                path: vec![String::from("a.bin")],
+                length: content.len() as u64,
+                pieces_root: Some(merkle::file_root(&content)),
+            }],
+            ..Default::default()
+        };
+
+        let consistent = TorrentFile::new(String::new(), hybrid(content.len()));
+        let parsed = Parser::parse_torrent_file(&consistent.to_bytes()).unwrap();
+        assert!(parsed.verify_piece(0, &content));
+
+        // v1 claims 10 bytes that hash fine, while `file tree` claims 16000
+        let mismatched = TorrentFile::new(String::new(), hybrid(10));
+        assert!(Parser::parse_torrent_file(&mismatched.to_bytes()).is_err());
+        assert!(!mismatched.verify_piece(0, &content[..10]));
+    }
+
+    #[test]
+    fn test_rejects_paths_escaping_the_download_dir() {
+        let parses = |info: TorrentInfo| {
+            Parser::parse_torrent_file(&TorrentFile::new(String::new(), info).to_bytes()).is_ok()
+        };
+        let in_files = |component: &str| TorrentInfo {
+            length: 1,
+            name: String::from("dir"),
+            piece_length: 1,
+            pieces: vec![0; 20],
+            files: vec![FileEntry {
+                length: 1,
+                path: vec![String::from("sub"), component.to_string()],
+                attr: None,
+            }],
+            ..Default::default()
+        };
+        let as_name = |component: &str| TorrentInfo {
+            length: 1,
+            name: component.to_string(),
+            piece_length: 1,
+            pieces: vec![0; 20],
+            ..Default::default()
+        };
+        let in_file_tree = |component: &str| TorrentInfo {
+            name: String::from("dir"),
+            piece_length: BLOCK_SIZE as u32,
+            meta_version: Some(2),
+            file_tree: vec![V2File {
+                path: vec![component.to_string(), String::from("a")],
+                length: 0,
+                pieces_root: None,
+            }],
+            ..Default::default()
+        };
+
+        assert!(parses(in_files("a")) && parses(as_name("a")) && parses(in_file_tree("a")));
+        for component in ["..", ".", "", "a/b", "a\\b", "/etc", "/"] {
+            assert!(!parses(in_files(component)), "{:?}", component);
+            assert!(!parses(as_name(component)), "{:?}", component);
+            // An empty key marks a file in `file tree` rather than a directory
+            if !component.is_empty() {
+                assert!(!parses(in_file_tree(component)), "{:?}", component);
+            }
+        }
+    }
 }
@@ -0,0 +1,209 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+
+/// In sequential mode, this many missing pieces from the playhead on are
+/// requested first, each with a deadline.
+pub const STREAMING_WINDOW: usize = 8;
+/// The first piece of the window is due this long after it's requested, the
+/// second twice as long and so on.
+const DEADLINE_STEP: Duration = Duration::from_secs(2);
+/// In sequential mode every fourth pick is a random piece outside the window,
+/// so we keep having pieces to offer peers that aren't streaming along.
+const FAIR_SHARE: u64 = 4;
+
+/// Decides which piece to request next.
+///
+/// Without a playhead, pieces go out in the order given (by file priority,
+/// see [`wanted_pieces`](crate::storage::wanted_pieces)). With some, the
+/// window of missing pieces from each playhead on comes first, nearest to a
+/// playhead first; a window piece that misses its deadline is handed out
+/// once more so a second peer races the first.
+pub struct PiecePicker {
+    /// Every wanted piece and its length, in picking order.
+    order: Vec<(u32, u32)>,
+    next: usize,
+    lengths: HashMap<u32, u32>,
+    missing: BTreeSet<u32>,
+    /// Requested pieces, with a deadline for window pieces until they're
+    /// handed out again.
+    in_flight: HashMap<u32, Option<Instant>>,
+    /// Pieces whose download failed, picked before the rest of `order`.
+    retry: VecDeque<u32>,
+    playheads: Vec<u32>,
+    picks: u64,
+}
+
+impl PiecePicker {
+    /// `wanted` in picking order; pieces already in `have` are never picked.
+    pub fn new(wanted: Vec<(u32, u32)>, have: impl Fn(u32) -> bool) -> Self {
+        PiecePicker {
+            lengths: wanted.iter().copied().collect(),
+            missing: wanted
+                .iter()
+                .map(|&(piece, _)| piece)
+                .filter(|&piece| !have(piece))
+                .collect(),
+            order: wanted,
+            next: 0,
+            in_flight: HashMap::new(),
+            retry: VecDeque::new(),
+            playheads: Vec::new(),
+            picks: 0,
+        }
+    }
+
+    /// Streams from each of `playheads`, e.g. a sequential download and
+    /// readers of its files; empty goes back to the plain order.
+    pub fn set_playheads(&mut self, playheads: Vec<u32>) {
+        self.playheads = playheads;
+    }
+
+    /// Wanted pieces not received yet, including those in flight.
+    pub fn remaining(&self) -> usize {
+        self.missing.len()
+    }
+
+    /// The next piece to request and its length, or `None` if every missing
+    /// piece is already requested.
+    pub fn pick(&mut self, now: Instant) -> Option<(u32, u32)> {
+        if !self.playheads.is_empty() {
+            self.picks += 1;
+            let window = self.window();
+            let pick = if self.picks.is_multiple_of(FAIR_SHARE) {
+                self.random_outside(&window).map(|piece| (piece, None))
+            } else {
+                window
+                    .iter()
+                    .find(|(_, piece)| !self.in_flight.contains_key(piece))
+                    .map(|&(distance, piece)| {
+                        (piece, Some(now + DEADLINE_STEP * (distance as u32 + 1)))
+                    })
+            };
+            if let Some((piece, deadline)) = pick {
+                self.in_flight.insert(piece, deadline);
+                return Some((piece, self.lengths[&piece]));
+            }
+        }
+
+        while let Some(piece) = self.retry.pop_front() {
+            if self.is_pickable(piece) {
+                self.in_flight.insert(piece, None);
+                return Some((piece, self.lengths[&piece]));
+            }
+        }
+        while let Some(&(piece, length)) = self.order.get(self.next) {
+            self.next += 1;
+            if self.is_pickable(piece) {
+                self.in_flight.insert(piece, None);
+                return Some((piece, length));
+            }
+        }
+        None
+    }
+
+    /// Window pieces still in flight past their deadline, to be requested
+    /// again. Each is returned at most once per request.
+    pub fn overdue(&mut self, now: Instant) -> Vec<(u32, u32)> {
+        let mut overdue = Vec::new();
+        for (&piece, deadline) in &mut self.in_flight {
+            if deadline.is_some_and(|deadline| deadline <= now) {
+                *deadline = None;
+                overdue.push((piece, self.lengths[&piece]));
+            }
+        }
+        overdue.sort();
+        overdue
+    }
+
+    pub fn received(&mut self, piece: u32) {
+        self.missing.remove(&piece);
+        self.in_flight.remove(&piece);
+    }
+
+    /// The download of `piece` failed; it'll be picked again unless another
+    /// peer delivered it meanwhile.
+    pub fn failed(&mut self, piece: u32) {
+        self.in_flight.remove(&piece);
+        if self.missing.contains(&piece) {
+            self.retry.push_back(piece);
+        }
+    }
+
+    fn is_pickable(&self, piece: u32) -> bool {
+        self.missing.contains(&piece) && !self.in_flight.contains_key(&piece)
+    }
+
+    /// The missing pieces near any playhead with their distance from it,
+    /// nearest first.
+    fn window(&self) -> Vec<(usize, u32)> {
+        let mut window = self
+            .playheads
+            .iter()
+            .flat_map(|&playhead| {
+                self.missing
+                    .range(playhead..)
+                    .take(STREAMING_WINDOW)
+                    .copied()
+                    .enumerate()
+            })
+            .collect::<Vec<_>>();
+        window.sort();
+        let mut seen = HashSet::new();
+        window.retain(|&(_, piece)| seen.insert(piece));
+        window
+    }
+
+    /// The first pickable piece from a random point on, wrapping around.
+    fn random_outside(&self, window: &[(usize, u32)]) -> Option<u32> {
+        let last = *self.missing.last()?;
+        let start = rand::thread_rng().gen_range(0..=last);
+        self.missing
+            .range(start..)
+            .chain(self.missing.range(..start))
+            .copied()
+            .find(|&piece| {
+                !window.iter().any(|&(_, near)| near == piece)
+                    && !self.in_flight.contains_key(&piece)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_playhead_window_deadlines_and_retries() {
+        let wanted = (0..40).map(|piece| (piece, 10)).collect();
+        let mut picker = PiecePicker::new(wanted, |piece| piece == 21);
+        assert_eq!(picker.remaining(), 39);
+
+        let now = Instant::now();
+        assert_eq!(picker.pick(now), Some((0, 10)));
+        picker.set_playheads(vec![20]);
+        let picks = (0..8)
+            .map(|_| picker.pick(now).unwrap().0)
+            .collect::<Vec<_>>();
+        // Piece 21 is already there; every fourth pick is outside the window
+        assert_eq!(picks[..3], [20, 22, 23]);
+        assert!(!(20..=28).contains(&picks[3]));
+        assert_eq!(picks[4..7], [24, 25, 26]);
+        assert!(!(20..=28).contains(&picks[7]));
+
+        assert!(picker.overdue(now + DEADLINE_STEP).contains(&(20, 10)));
+        assert!(!picker.overdue(now + DEADLINE_STEP).contains(&(20, 10)));
+
+        picker.received(20);
+        picker.failed(20);
+        picker.failed(22);
+        assert_eq!(picker.remaining(), 38);
+        picker.set_playheads(Vec::new());
+        assert_eq!(picker.pick(now), Some((22, 10)));
+        let next = (1..).find(|piece| !picks.contains(piece)).unwrap();
+        assert_eq!(picker.pick(now), Some((next, 10)));
+    }
+}
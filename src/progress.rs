@@ -0,0 +1,281 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    io::{stdout, IsTerminal, Write},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use codecrafters_bittorrent::{
+    events::{Event, PieceFailure},
+    util::format_bytes,
+};
+
+/// How often a line is printed when stdout isn't a terminal.
+const PLAIN_INTERVAL: Duration = Duration::from_secs(5);
+/// Per-peer rates average over the pieces verified in this window.
+const PEER_RATE_WINDOW: Duration = Duration::from_secs(10);
+const BAR_WIDTH: usize = 30;
+const MAP_WIDTH: usize = 64;
+const SHOWN_PEERS: usize = 8;
+
+/// Renders download events as a live view on a terminal, or as a plain line
+/// every few seconds otherwise.
+pub struct ProgressView {
+    name: String,
+    piece_count: usize,
+    /// Length of every piece we want, by index.
+    wanted: BTreeMap<u32, u32>,
+    done: HashSet<u32>,
+    total_bytes: u64,
+    done_bytes: u64,
+    download_rate: f64,
+    upload_rate: f64,
+    last_totals: Option<(Instant, u64, u64)>,
+    /// Connected peers and the pieces they recently delivered.
+    peers: HashMap<SocketAddr, VecDeque<(Instant, u64)>>,
+    message: Option<String>,
+    interactive: bool,
+    drawn_lines: usize,
+    last_plain: Option<Instant>,
+}
+
+impl ProgressView {
+    pub fn new(name: &str, piece_count: usize, wanted: &[(u32, u32)]) -> Self {
+        ProgressView {
+            name: name.to_string(),
+            piece_count,
+            wanted: wanted.iter().copied().collect(),
+            done: HashSet::new(),
+            total_bytes: wanted.iter().map(|&(_, length)| length as u64).sum(),
+            done_bytes: 0,
+            download_rate: 0.0,
+            upload_rate: 0.0,
+            last_totals: None,
+            peers: HashMap::new(),
+            message: None,
+            interactive: stdout().is_terminal(),
+            drawn_lines: 0,
+            last_plain: None,
+        }
+    }
+
+    pub fn handle(&mut self, event: &Event) {
+        match event {
+            Event::Transferred {
+                downloaded,
+                uploaded,
+                ..
+            } => {
+                let now = Instant::now();
+                if let Some((at, last_downloaded, last_uploaded)) = self.last_totals {
+                    let elapsed = now.duration_since(at).as_secs_f64().max(0.001);
+                    let smooth = |rate: f64, bytes: u64| 0.7 * rate + 0.3 * bytes as f64 / elapsed;
+                    self.download_rate = smooth(self.download_rate, downloaded - last_downloaded);
+                    self.upload_rate = smooth(self.upload_rate, uploaded - last_uploaded);
+                }
+                self.last_totals = Some((now, *downloaded, *uploaded));
+                self.render(false);
+            }
+            Event::PeerConnected { peer, .. } => {
+                self.peers.entry(*peer).or_default();
+            }
+            Event::PeerDisconnected { peer, .. } => {
+                self.peers.remove(peer);
+            }
+            Event::PieceVerified { piece, peer, .. } => {
+                let length = self.wanted.get(piece).copied().unwrap_or_default() as u64;
+                if self.done.insert(*piece) {
+                    self.done_bytes += length;
+                }
+                if let Some(samples) = peer.and_then(|peer| self.peers.get_mut(&peer)) {
+                    samples.push_back((Instant::now(), length));
+                }
+            }
+            Event::PieceFailed { piece, reason, .. } => {
+                self.message = Some(match reason {
+                    PieceFailure::Download => format!("Piece {} failed, requesting again", piece),
+                    PieceFailure::HashMismatch => {
+                        format!("Piece {} failed hash check, requesting again", piece)
+                    }
+                });
+            }
+            Event::TrackerAnnounce {
+                tracker,
+                result: Err(e),
+                ..
+            } => self.message = Some(format!("Tracker {} failed: {}", tracker, e)),
+            Event::Warning { message, .. } => self.message = Some(message.clone()),
+            _ => {}
+        }
+    }
+
+    /// Draws the final state.
+    pub fn finish(&mut self) {
+        self.render(true);
+    }
+
+    fn render(&mut self, force: bool) {
+        if self.interactive {
+            let lines = self.lines();
+            let mut out = stdout().lock();
+            if self.drawn_lines > 0 {
+                let _ = write!(out, "\x1b[{}A", self.drawn_lines);
+            }
+            for line in &lines {
+                let _ = writeln!(out, "\x1b[2K{}", line);
+            }
+            // Clear what's left of a taller previous frame
+            let _ = write!(out, "\x1b[J");
+            let _ = out.flush();
+            self.drawn_lines = lines.len();
+        } else if force
+            || self
+                .last_plain
+                .is_none_or(|at| at.elapsed() >= PLAIN_INTERVAL)
+        {
+            self.last_plain = Some(Instant::now());
+            println!(
+                "{} {:.1}% {}/{} down {}/s up {}/s ETA {} peers {}",
+                self.name,
+                self.percent(),
+                format_bytes(self.done_bytes),
+                format_bytes(self.total_bytes),
+                format_bytes(self.download_rate as u64),
+                format_bytes(self.upload_rate as u64),
+                self.eta(),
+                self.peers.len()
+            );
+        }
+    }
+
+    fn lines(&mut self) -> Vec<String> {
+        let filled = (self.percent() / 100.0 * BAR_WIDTH as f64) as usize;
+        let mut lines = vec![
+            self.name.clone(),
+            format!(
+                "[{}{}] {:5.1}%  {} / {}  {}/{} pieces",
+                "#".repeat(filled),
+                ".".repeat(BAR_WIDTH - filled),
+                self.percent(),
+                format_bytes(self.done_bytes),
+                format_bytes(self.total_bytes),
+                self.done.len(),
+                self.wanted.len()
+            ),
+            format!(
+                "down {}/s  up {}/s  ETA {}  {} peers",
+                format_bytes(self.download_rate as u64),
+                format_bytes(self.upload_rate as u64),
+                self.eta(),
+                self.peers.len()
+            ),
+        ];
+
+        let now = Instant::now();
+        let mut peers = self
+            .peers
+            .iter_mut()
+            .map(|(peer, samples)| {
+                while samples
+                    .front()
+                    .is_some_and(|(at, _)| now.duration_since(*at) > PEER_RATE_WINDOW)
+                {
+                    samples.pop_front();
+                }
+                let bytes = samples.iter().map(|(_, length)| length).sum::<u64>();
+                (*peer, bytes / PEER_RATE_WINDOW.as_secs())
+            })
+            .collect::<Vec<_>>();
+        peers.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        for (peer, rate) in peers.iter().take(SHOWN_PEERS) {
+            lines.push(format!(
+                "  {:<47} {}/s",
+                peer.to_string(),
+                format_bytes(*rate)
+            ));
+        }
+        if peers.len() > SHOWN_PEERS {
+            lines.push(format!("  and {} more", peers.len() - SHOWN_PEERS));
+        }
+
+        lines.push(piece_map(self.piece_count, &self.wanted, &self.done));
+        if let Some(message) = &self.message {
+            lines.push(message.clone());
+        }
+        lines
+    }
+
+    fn percent(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 100.0;
+        }
+        self.done_bytes as f64 * 100.0 / self.total_bytes as f64
+    }
+
+    fn eta(&self) -> String {
+        let remaining = self.total_bytes - self.done_bytes;
+        if remaining == 0 {
+            return format_duration(0);
+        }
+        if self.download_rate < 1.0 {
+            return "--".to_string();
+        }
+        format_duration((remaining as f64 / self.download_rate) as u64)
+    }
+}
+
+/// One character per run of pieces, shaded by how much of it is done.
+/// Runs without any wanted piece are blank.
+fn piece_map(piece_count: usize, wanted: &BTreeMap<u32, u32>, done: &HashSet<u32>) -> String {
+    let cells = piece_count.min(MAP_WIDTH);
+    (0..cells)
+        .map(|cell| {
+            let start = cell * piece_count / cells;
+            let end = (cell + 1) * piece_count / cells;
+            let pieces = wanted
+                .range(start as u32..end as u32)
+                .map(|(piece, _)| piece);
+            let (total, completed) = pieces.fold((0, 0), |(total, completed), piece| {
+                (total + 1, completed + done.contains(piece) as usize)
+            });
+            match (total, completed) {
+                (0, _) => ' ',
+                (_, 0) => '░',
+                (total, completed) if completed == total => '█',
+                (total, completed) if completed * 2 < total => '▒',
+                _ => '▓',
+            }
+        })
+        .collect()
+}
+
+fn format_duration(seconds: u64) -> String {
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m{:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h{:02}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_piece_map_and_eta() {
+        let wanted = (0..4).map(|piece| (piece, 10)).collect();
+        let done = HashSet::from([0, 1, 2]);
+        assert_eq!(piece_map(6, &wanted, &done), "███░  ");
+
+        let wanted = (0..128).map(|piece| (piece, 10)).collect();
+        let done = (0..64).chain(64..80).collect();
+        let map = piece_map(128, &wanted, &done);
+        assert_eq!(map.chars().count(), MAP_WIDTH);
+        assert!(map.starts_with("████████"));
+        assert!(map.ends_with("░░"));
+
+        assert_eq!(format_duration(42), "42s");
+        assert_eq!(format_duration(125), "2m05s");
+        assert_eq!(format_duration(7300), "2h01m");
+    }
+}
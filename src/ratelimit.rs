@@ -0,0 +1,195 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{sleep, Sleep},
+};
+
+/// A token bucket shared by every connection it applies to. The rate can be
+/// changed at any time; `None` means unlimited.
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+struct Bucket {
+    /// Bytes per second.
+    rate: Option<u64>,
+    tokens: f64,
+    refilled_at: Instant,
+    /// Bytes moved through the bucket so far, limited or not.
+    total: u64,
+}
+
+impl Default for Bucket {
+    fn default() -> Self {
+        Bucket {
+            rate: None,
+            tokens: 0.0,
+            refilled_at: Instant::now(),
+            total: 0,
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<u64>) -> Self {
+        let limiter = RateLimiter::default();
+        limiter.set_rate(rate);
+        limiter
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.bucket.lock().unwrap().rate
+    }
+
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.rate = rate.filter(|&rate| rate > 0);
+        bucket.tokens = bucket.rate.unwrap_or(0) as f64;
+        bucket.refilled_at = Instant::now();
+    }
+
+    pub fn total(&self) -> u64 {
+        self.bucket.lock().unwrap().total
+    }
+
+    /// Takes `bytes` from the bucket, going into debt if needed, and returns
+    /// how long the caller should wait before moving more data.
+    pub fn consume(&self, bytes: usize) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.total += bytes as u64;
+        let Some(rate) = bucket.rate else {
+            return Duration::ZERO;
+        };
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        // Allow bursts of up to one second's worth
+        bucket.tokens = (bucket.tokens + elapsed * rate as f64).min(rate as f64);
+        bucket.refilled_at = now;
+        bucket.tokens -= bytes as f64;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate as f64)
+        }
+    }
+}
+
+/// Upload and download limits for one scope, e.g. a torrent or the whole
+/// client.
+#[derive(Clone, Default)]
+pub struct BandwidthLimits {
+    pub download: RateLimiter,
+    pub upload: RateLimiter,
+}
+
+impl BandwidthLimits {
+    pub fn new(download: Option<u64>, upload: Option<u64>) -> Self {
+        BandwidthLimits {
+            download: RateLimiter::new(download),
+            upload: RateLimiter::new(upload),
+        }
+    }
+}
+
+/// Wraps a peer socket so reads and writes are charged against every set of
+/// limits it belongs to; the slowest one wins.
+pub struct RateLimitedStream<S> {
+    inner: S,
+    limits: Vec<BandwidthLimits>,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> RateLimitedStream<S> {
+    pub fn new(inner: S, limits: Vec<BandwidthLimits>) -> Self {
+        RateLimitedStream {
+            inner,
+            limits,
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+}
+
+fn wait(delay: &mut Option<Pin<Box<Sleep>>>, cx: &mut Context<'_>) -> Poll<()> {
+    if let Some(sleep) = delay {
+        ready!(sleep.as_mut().poll(cx));
+        *delay = None;
+    }
+    Poll::Ready(())
+}
+
+fn charge(delay: &mut Option<Pin<Box<Sleep>>>, limiters: Vec<&RateLimiter>, bytes: usize) {
+    let longest = limiters
+        .into_iter()
+        .map(|limiter| limiter.consume(bytes))
+        .max()
+        .unwrap_or_default();
+    if !longest.is_zero() {
+        *delay = Some(Box::pin(sleep(longest)));
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for RateLimitedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(wait(&mut this.read_delay, cx));
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        let limiters = this.limits.iter().map(|limits| &limits.download).collect();
+        charge(&mut this.read_delay, limiters, buf.filled().len() - filled);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for RateLimitedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(wait(&mut this.write_delay, cx));
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        let limiters = this.limits.iter().map(|limits| &limits.upload).collect();
+        charge(&mut this.write_delay, limiters, written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new(Some(1000));
+        assert_eq!(limiter.consume(1000), Duration::ZERO);
+        let wait = limiter.consume(500);
+        assert!(wait > Duration::from_millis(450) && wait <= Duration::from_millis(500));
+
+        limiter.set_rate(None);
+        assert_eq!(limiter.consume(1_000_000), Duration::ZERO);
+    }
+}
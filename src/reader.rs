@@ -0,0 +1,192 @@
+use std::{
+    future::Future,
+    io::{self, SeekFrom},
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+
+use crate::{error::Result, session::Session};
+
+static NEXT_READER_ID: AtomicU64 = AtomicU64::new(0);
+
+type PieceFuture = Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send>>;
+
+/// A file inside a torrent that can be read while it downloads, from
+/// [`Session::open_file`].
+///
+/// A read waits until the piece it covers is verified. While the reader is
+/// open its position acts as a playhead: the pieces from there on are
+/// requested first, with deadlines, as in a sequential download. Reads from
+/// a paused torrent wait until it is resumed.
+pub struct FileReader {
+    session: Session,
+    info_hash: [u8; 20],
+    id: u64,
+    /// Where the file starts in the torrent's piece space.
+    start: u64,
+    length: u64,
+    piece_length: u64,
+    position: u64,
+    /// The piece last read, kept for the reads that follow within it.
+    current: Option<(u32, Vec<u8>)>,
+    pending: Option<(u32, PieceFuture)>,
+}
+
+impl FileReader {
+    pub(crate) fn new(
+        session: Session,
+        info_hash: [u8; 20],
+        start: u64,
+        length: u64,
+        piece_length: u32,
+    ) -> Self {
+        FileReader {
+            session,
+            info_hash,
+            id: NEXT_READER_ID.fetch_add(1, Ordering::Relaxed),
+            start,
+            length,
+            piece_length: piece_length as u64,
+            position: 0,
+            current: None,
+            pending: None,
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+}
+
+impl AsyncRead for FileReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.position >= this.length || buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        let offset = this.start + this.position;
+        let piece = (offset / this.piece_length) as u32;
+
+        if this.current.as_ref().map(|(index, _)| *index) != Some(piece) {
+            if this.pending.as_ref().map(|(index, _)| *index) != Some(piece) {
+                // The torrent may have been removed; the read then fails below
+                let _ = this
+                    .session
+                    .set_reader_position(&this.info_hash, this.id, Some(piece));
+                let read = this.session.clone().read_piece(this.info_hash, piece);
+                this.pending = Some((piece, Box::pin(read)));
+            }
+            let (_, read) = this.pending.as_mut().unwrap();
+            let data = match read.as_mut().poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(result) => {
+                    this.pending = None;
+                    result.map_err(io::Error::other)?
+                }
+            };
+            this.current = Some((piece, data));
+        }
+
+        let (_, data) = this.current.as_ref().unwrap();
+        let from = (offset % this.piece_length) as usize;
+        let available = (data.len() - from.min(data.len())) as u64;
+        let length = available
+            .min(this.length - this.position)
+            .min(buf.remaining() as u64) as usize;
+        buf.put_slice(&data[from..from + length]);
+        this.position += length as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for FileReader {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => this.length.checked_add_signed(offset),
+            SeekFrom::Current(offset) => this.position.checked_add_signed(offset),
+        };
+        this.position = position
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Seek before the start"))?;
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
+
+impl Drop for FileReader {
+    fn drop(&mut self) {
+        let _ = self
+            .session
+            .set_reader_position(&self.info_hash, self.id, None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    use super::*;
+    use crate::{
+        create::{create_torrent, CreateOptions},
+        session::{AddTorrentOptions, SessionConfig},
+        transport::TransportMode,
+        webseed::tests::spawn_fake_seed,
+    };
+
+    #[tokio::test]
+    async fn test_read_and_seek_while_downloading() {
+        let dir = tempfile::tempdir().unwrap();
+        let content = (0..100_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        fs::write(dir.path().join("movie.bin"), &content).unwrap();
+
+        let url = spawn_fake_seed(dir.path()).await;
+
+        let options = CreateOptions {
+            web_seeds: vec![url],
+            ..Default::default()
+        };
+        let torrent_file = create_torrent(&dir.path().join("movie.bin"), &options).unwrap();
+        let session = Session::new(SessionConfig {
+            listen_port: 0,
+            transport: TransportMode::Tcp,
+            local_discovery: false,
+            download_dir: dir.path().join("downloads"),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let info_hash = session
+            .add_torrent(torrent_file, AddTorrentOptions::default())
+            .unwrap();
+
+        let mut reader = session.open_file(&info_hash, 0).unwrap();
+        assert_eq!(reader.len(), 100_000);
+        reader.seek(SeekFrom::Start(50_000)).await.unwrap();
+        let mut tail = Vec::new();
+        reader.read_to_end(&mut tail).await.unwrap();
+        assert_eq!(tail, content[50_000..]);
+
+        reader.seek(SeekFrom::Start(10)).await.unwrap();
+        let mut head = [0; 20];
+        reader.read_exact(&mut head).await.unwrap();
+        assert_eq!(head, content[10..30]);
+        assert!(session.open_file(&info_hash, 1).is_err());
+    }
+}
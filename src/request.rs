@@ -1,7 +1,166 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use reqwest::Client;
 use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{Error, Result},
+    hasher::bytes_to_hex_url_encoded,
+    tcp::generate_peer_id,
+    PORT,
+};
+
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct TrackerResponse {
+    /// Set instead of the other fields when the tracker refuses the announce.
+    #[serde(default, rename = "failure reason")]
+    pub failure_reason: Option<String>,
+    #[serde(default)]
     pub interval: usize,
-    #[serde(with = "serde_bytes")]
+    #[serde(default, with = "serde_bytes")]
     pub peers: Vec<u8>,
+    /// Compact IPv6 peers (BEP 7), 18 bytes each.
+    #[serde(default, with = "serde_bytes")]
+    pub peers6: Vec<u8>,
+}
+
+impl TrackerResponse {
+    pub fn peer_addresses(&self) -> Vec<SocketAddr> {
+        let mut addresses = parse_compact_peers(&self.peers);
+        addresses.extend(parse_compact_peers6(&self.peers6));
+        addresses
+    }
+}
+
+/// Announces to HTTP trackers as one client: the same peer id and listen
+/// port every time.
+#[derive(Debug, Clone)]
+pub struct TrackerClient {
+    client: Client,
+    peer_id: [u8; 20],
+    port: u16,
+}
+
+impl Default for TrackerClient {
+    fn default() -> Self {
+        TrackerClient::new(generate_peer_id(), PORT)
+    }
+}
+
+impl TrackerClient {
+    pub fn new(peer_id: [u8; 20], port: u16) -> Self {
+        TrackerClient {
+            client: Client::new(),
+            peer_id,
+            port,
+        }
+    }
+
+    /// Announces to an HTTP tracker and returns the peers it knows about.
+    /// `left` is how many bytes we still need.
+    pub async fn announce(
+        &self,
+        tracker_url: &str,
+        info_hash: &[u8; 20],
+        left: u64,
+    ) -> Result<Vec<SocketAddr>> {
+        let url = format!(
+            "{}?info_hash={}&peer_id={}",
+            tracker_url,
+            bytes_to_hex_url_encoded(info_hash),
+            bytes_to_hex_url_encoded(&self.peer_id)
+        );
+
+        let req = self
+            .client
+            .get(url)
+            .query(&[
+                ("port", self.port.to_string()),
+                ("uploaded", String::from("0")),
+                ("downloaded", String::from("0")),
+                ("left", left.to_string()),
+                ("compact", String::from("1")),
+            ])
+            .build()
+            .map_err(|e| Error::Tracker(format!("Invalid tracker URL {}: {}", tracker_url, e)))?;
+        let response = async { self.client.execute(req).await?.bytes().await }
+            .await
+            .map_err(|e| Error::Tracker(format!("Tracker {} unreachable: {}", tracker_url, e)))?;
+
+        let tracker_response = serde_bencode::from_bytes::<TrackerResponse>(&response)
+            .map_err(|e| Error::Tracker(format!("Bad response from {}: {}", tracker_url, e)))?;
+        if let Some(reason) = tracker_response.failure_reason {
+            return Err(Error::Tracker(format!(
+                "{} refused the announce: {}",
+                tracker_url, reason
+            )));
+        }
+        Ok(tracker_response.peer_addresses())
+    }
+}
+
+/// Parses 6-byte compact entries: an IPv4 address and a port.
+pub fn parse_compact_peers(peers: &[u8]) -> Vec<SocketAddr> {
+    peers
+        .chunks_exact(6)
+        .map(|peer| {
+            let ip = Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]);
+            SocketAddr::from((ip, u16::from_be_bytes([peer[4], peer[5]])))
+        })
+        .collect()
+}
+
+/// Parses 18-byte compact entries: an IPv6 address and a port.
+pub fn parse_compact_peers6(peers: &[u8]) -> Vec<SocketAddr> {
+    peers
+        .chunks_exact(18)
+        .map(|peer| {
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&peer[..16]);
+            SocketAddr::from((Ipv6Addr::from(ip), u16::from_be_bytes([peer[16], peer[17]])))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peers_and_peers6() {
+        let response = b"d8:intervali900e5:peers6:\x7f\x00\x00\x01\x1a\xe16:peers618:\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe2e";
+        let response = serde_bencode::from_bytes::<TrackerResponse>(response).unwrap();
+        assert_eq!(
+            response.peer_addresses(),
+            vec![
+                "127.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "[::1]:6882".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failure_reason_is_a_tracker_error() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tracker_url = format!("http://{}/announce", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request).await.unwrap();
+            let body = b"d14:failure reason17:torrent not founde";
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+            stream.write_all(head.as_bytes()).await.unwrap();
+            stream.write_all(body).await.unwrap();
+        });
+
+        let error = TrackerClient::default()
+            .announce(&tracker_url, &[0; 20], 0)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&error, Error::Tracker(message) if message.ends_with("torrent not found"))
+        );
+    }
 }
@@ -0,0 +1,1038 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use serde::Serialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    fs,
+    net::TcpListener,
+    sync::{
+        broadcast,
+        mpsc::{self, Sender},
+        watch, Semaphore,
+    },
+    task::JoinHandle,
+    time::{interval, Duration, MissedTickBehavior},
+};
+
+use crate::{
+    connections::{
+        global_limit, ConnectionManager, DEFAULT_MAX_PEERS_GLOBAL, DEFAULT_MAX_PEERS_PER_TORRENT,
+    },
+    error::{Error, Result},
+    events::{Event, EventBus, PieceFailure},
+    hasher::bytes_to_hex,
+    lsd::LocalServiceDiscovery,
+    metadata,
+    mse::EncryptionPolicy,
+    parser::{is_plain_component, TorrentFile, TorrentInfo},
+    picker::PiecePicker,
+    ratelimit::BandwidthLimits,
+    reader::FileReader,
+    request::TrackerClient,
+    storage::{file_piece_ranges, wanted_pieces, FilePriority, PieceStore, Storage},
+    tcp::{generate_peer_id, ConnectionConfig, PeerConnection, PeerManager, PeerRequest},
+    transport::{Dialer, PeerStream, TransportMode},
+    util::decode_magnet_link,
+    CHUNKSIZE, PORT,
+};
+
+/// Pieces queued for, and pieces returned by, the peer workers of a torrent.
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 1000;
+/// Requests waiting for a free peer worker, or fewer if the channel is
+/// smaller. More are picked as pieces arrive, so a moved playhead takes
+/// effect quickly.
+const REQUEST_QUEUE_DEPTH: usize = 8;
+
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    pub listen_port: u16,
+    /// Sent to trackers and in handshakes; a fresh one by default.
+    pub peer_id: [u8; 20],
+    pub max_peers_global: usize,
+    pub max_peers_per_torrent: usize,
+    /// Bytes per second shared by all torrents; `None` is unlimited.
+    pub download_limit: Option<u64>,
+    pub upload_limit: Option<u64>,
+    pub encryption: EncryptionPolicy,
+    pub transport: TransportMode,
+    pub local_discovery: bool,
+    /// Size of the blocks pieces are requested in.
+    pub block_size: u32,
+    pub channel_capacity: usize,
+    /// Relative output paths are resolved against this, and torrents added
+    /// without one are written here under their own name.
+    pub download_dir: PathBuf,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            listen_port: PORT,
+            peer_id: generate_peer_id(),
+            max_peers_global: DEFAULT_MAX_PEERS_GLOBAL,
+            max_peers_per_torrent: DEFAULT_MAX_PEERS_PER_TORRENT,
+            download_limit: None,
+            upload_limit: None,
+            encryption: EncryptionPolicy::default(),
+            transport: TransportMode::default(),
+            local_discovery: true,
+            block_size: CHUNKSIZE,
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            download_dir: PathBuf::from("."),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TorrentState {
+    Downloading,
+    Paused,
+    /// Everything is downloaded and written; pieces are still uploaded.
+    Seeding,
+    Failed,
+}
+
+/// Options for a single torrent added to a session.
+#[derive(Debug, Clone, Default)]
+pub struct AddTorrentOptions {
+    /// Relative to the session's download directory; empty means the
+    /// torrent's name.
+    pub output: PathBuf,
+    /// Download just this piece and write it alone to `output`.
+    pub piece: Option<u32>,
+    pub start_paused: bool,
+    /// Fetch pieces in order from a playhead, at the start until moved with
+    /// [`Session::set_playhead`], so media can be played while downloading.
+    pub sequential: bool,
+    /// One per file of [`TorrentInfo::file_paths`](crate::TorrentInfo::file_paths);
+    /// empty means every file at [`FilePriority::Normal`].
+    pub file_priorities: Vec<FilePriority>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TorrentStatus {
+    pub info_hash: String,
+    pub name: String,
+    pub state: TorrentState,
+    pub pieces_completed: usize,
+    pub pieces_total: usize,
+    pub output: PathBuf,
+}
+
+/// Totals across the whole session.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SessionStats {
+    pub torrents: usize,
+    pub downloading: usize,
+    pub seeding: usize,
+    pub paused: usize,
+    pub failed: usize,
+    /// Bytes received from and sent to peers since the session started.
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub download_limit: Option<u64>,
+    pub upload_limit: Option<u64>,
+}
+
+struct Torrent {
+    torrent_file: Arc<TorrentFile>,
+    options: AddTorrentOptions,
+    limits: BandwidthLimits,
+    pieces: PieceStore,
+    /// Peers, bans and the per-torrent cap of the current run, shared with
+    /// incoming connections.
+    connections: ConnectionManager,
+    state: watch::Sender<TorrentState>,
+    /// The piece a sequential download streams from; `None` otherwise.
+    playhead: watch::Sender<Option<u32>>,
+    /// The piece each open [`FileReader`] is reading, by reader id.
+    readers: watch::Sender<BTreeMap<u64, u32>>,
+    task: Option<JoinHandle<()>>,
+}
+
+struct SessionInner {
+    config: SessionConfig,
+    dialer: Dialer,
+    global_slots: Arc<Semaphore>,
+    bandwidth: BandwidthLimits,
+    tracker: TrackerClient,
+    events: EventBus,
+    listen_error: Option<String>,
+    torrents: Mutex<HashMap<[u8; 20], Torrent>>,
+}
+
+/// Owns every torrent in the process together with the resources they share:
+/// the listening sockets, the global peer cap and the global rate limits.
+#[derive(Clone)]
+pub struct Session {
+    inner: Arc<SessionInner>,
+}
+
+impl Session {
+    /// Starts the session and its listener. Failing to listen is not fatal;
+    /// the session then only makes outgoing connections.
+    pub async fn new(config: SessionConfig) -> Result<Session> {
+        let listener = bind_listener(config.listen_port);
+        let session = Session {
+            inner: Arc::new(SessionInner {
+                dialer: Dialer::new(config.transport, config.listen_port).await?,
+                global_slots: global_limit(config.max_peers_global),
+                bandwidth: BandwidthLimits::new(config.download_limit, config.upload_limit),
+                tracker: TrackerClient::new(config.peer_id, config.listen_port),
+                events: EventBus::default(),
+                listen_error: listener.as_ref().err().map(ToString::to_string),
+                torrents: Mutex::new(HashMap::new()),
+                config,
+            }),
+        };
+
+        if let Ok(listener) = listener {
+            tokio::spawn(session.clone().accept_tcp(listener));
+        }
+        if session.inner.dialer.utp_socket().is_some() {
+            tokio::spawn(session.clone().accept_utp());
+        }
+        Ok(session)
+    }
+
+    pub fn config(&self) -> &SessionConfig {
+        &self.inner.config
+    }
+
+    /// Why incoming TCP peers aren't accepted, if the listen port couldn't be
+    /// bound. Torrents still download from the peers they dial.
+    pub fn listen_error(&self) -> Option<&str> {
+        self.inner.listen_error.as_deref()
+    }
+
+    /// Streams progress events for every torrent in the session.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.inner.events.subscribe()
+    }
+
+    /// Adds a torrent and starts it unless asked to start paused. Returns
+    /// its info hash, which identifies it in the other methods.
+    pub fn add_torrent(
+        &self,
+        torrent_file: TorrentFile,
+        mut options: AddTorrentOptions,
+    ) -> Result<[u8; 20]> {
+        let info_hash = torrent_file.info_hash();
+        if options.output.as_os_str().is_empty() {
+            if !is_plain_component(&torrent_file.info.name) {
+                return Err(Error::Metainfo(format!(
+                    "Invalid torrent name {:?}",
+                    torrent_file.info.name
+                )));
+            }
+            options.output = PathBuf::from(&torrent_file.info.name);
+        }
+        options.output = self.inner.config.download_dir.join(&options.output);
+        let start_paused = options.start_paused;
+        let sequential = options.sequential;
+        let torrent_file = Arc::new(torrent_file);
+        let storage = options.piece.is_none().then(|| {
+            Storage::new(
+                torrent_file.clone(),
+                &options.output,
+                &options.file_priorities,
+            )
+        });
+        {
+            let mut torrents = self.inner.torrents.lock().unwrap();
+            if torrents.contains_key(&info_hash) {
+                return Err(Error::DuplicateTorrent(bytes_to_hex(&info_hash)));
+            }
+            torrents.insert(
+                info_hash,
+                Torrent {
+                    torrent_file,
+                    options,
+                    limits: BandwidthLimits::default(),
+                    pieces: PieceStore::new(storage),
+                    connections: self.connection_manager(),
+                    state: watch::channel(TorrentState::Paused).0,
+                    playhead: watch::channel(sequential.then_some(0)).0,
+                    readers: watch::channel(BTreeMap::new()).0,
+                    task: None,
+                },
+            );
+        }
+        if !start_paused {
+            self.resume(&info_hash)?;
+        }
+        Ok(info_hash)
+    }
+
+    /// Fetches the metadata of a magnet link from its swarm so the torrent
+    /// can then be added with [`Session::add_torrent`].
+    pub async fn resolve_magnet(&self, magnet_link: &str) -> Result<TorrentFile> {
+        let magnet = decode_magnet_link(magnet_link)?;
+        if self.contains(&magnet.info_hash) {
+            return Err(Error::DuplicateTorrent(bytes_to_hex(&magnet.info_hash)));
+        }
+        metadata::fetch_torrent(&magnet, &self.inner.tracker, &self.connection_config()).await
+    }
+
+    pub fn torrent_file(&self, info_hash: &[u8; 20]) -> Result<Arc<TorrentFile>> {
+        let mut torrents = self.inner.torrents.lock().unwrap();
+        Ok(lookup(&mut torrents, info_hash)?.torrent_file.clone())
+    }
+
+    pub fn contains(&self, info_hash: &[u8; 20]) -> bool {
+        self.inner.torrents.lock().unwrap().contains_key(info_hash)
+    }
+
+    pub fn pause(&self, info_hash: &[u8; 20]) -> Result<()> {
+        let mut torrents = self.inner.torrents.lock().unwrap();
+        let torrent = lookup(&mut torrents, info_hash)?;
+        if let Some(task) = torrent.task.take() {
+            task.abort();
+        }
+        torrent.state.send_replace(TorrentState::Paused);
+        Ok(())
+    }
+
+    /// Starts or restarts a torrent, fetching only the pieces it is missing.
+    pub fn resume(&self, info_hash: &[u8; 20]) -> Result<()> {
+        let mut torrents = self.inner.torrents.lock().unwrap();
+        let torrent = lookup(&mut torrents, info_hash)?;
+        if torrent
+            .task
+            .as_ref()
+            .is_some_and(|task| !task.is_finished())
+        {
+            return Ok(());
+        }
+        torrent.state.send_replace(TorrentState::Downloading);
+        torrent.connections = self.connection_manager();
+        let job = TorrentJob {
+            session: self.clone(),
+            torrent_file: torrent.torrent_file.clone(),
+            options: torrent.options.clone(),
+            limits: torrent.limits.clone(),
+            pieces: torrent.pieces.clone(),
+            connections: torrent.connections.clone(),
+            state: torrent.state.clone(),
+            playhead: torrent.playhead.subscribe(),
+            readers: torrent.readers.subscribe(),
+        };
+        torrent.task = Some(tokio::spawn(job.run()));
+        Ok(())
+    }
+
+    pub fn remove(&self, info_hash: &[u8; 20]) -> Result<()> {
+        let mut torrents = self.inner.torrents.lock().unwrap();
+        let mut torrent = torrents
+            .remove(info_hash)
+            .ok_or_else(|| Error::UnknownTorrent(bytes_to_hex(info_hash)))?;
+        if let Some(task) = torrent.task.take() {
+            task.abort();
+        }
+        Ok(())
+    }
+
+    pub fn torrents(&self) -> Vec<TorrentStatus> {
+        let torrents = self.inner.torrents.lock().unwrap();
+        let mut statuses = torrents
+            .iter()
+            .map(|(info_hash, torrent)| TorrentStatus {
+                info_hash: bytes_to_hex(info_hash),
+                name: torrent.torrent_file.info.name.clone(),
+                state: *torrent.state.borrow(),
+                pieces_completed: torrent.pieces.len(),
+                pieces_total: match torrent.options.piece {
+                    Some(_) => 1,
+                    None => {
+                        wanted_pieces(&torrent.torrent_file, &torrent.options.file_priorities).len()
+                    }
+                },
+                output: torrent.options.output.clone(),
+            })
+            .collect::<Vec<_>>();
+        statuses.sort_by(|a, b| a.name.cmp(&b.name));
+        statuses
+    }
+
+    pub fn stats(&self) -> SessionStats {
+        let mut stats = SessionStats {
+            downloaded: self.inner.bandwidth.download.total(),
+            uploaded: self.inner.bandwidth.upload.total(),
+            download_limit: self.inner.bandwidth.download.rate(),
+            upload_limit: self.inner.bandwidth.upload.rate(),
+            ..Default::default()
+        };
+        for torrent in self.inner.torrents.lock().unwrap().values() {
+            stats.torrents += 1;
+            match *torrent.state.borrow() {
+                TorrentState::Downloading => stats.downloading += 1,
+                TorrentState::Seeding => stats.seeding += 1,
+                TorrentState::Paused => stats.paused += 1,
+                TorrentState::Failed => stats.failed += 1,
+            }
+        }
+        stats
+    }
+
+    /// Changes the limits shared by all torrents; takes effect immediately.
+    pub fn set_global_limits(&self, download: Option<u64>, upload: Option<u64>) {
+        self.inner.bandwidth.download.set_rate(download);
+        self.inner.bandwidth.upload.set_rate(upload);
+    }
+
+    pub fn set_torrent_limits(
+        &self,
+        info_hash: &[u8; 20],
+        download: Option<u64>,
+        upload: Option<u64>,
+    ) -> Result<()> {
+        let mut torrents = self.inner.torrents.lock().unwrap();
+        let torrent = lookup(&mut torrents, info_hash)?;
+        torrent.limits.download.set_rate(download);
+        torrent.limits.upload.set_rate(upload);
+        Ok(())
+    }
+
+    /// Streams the torrent from `piece` on: the next few missing pieces are
+    /// requested first, with deadlines. `None` goes back to the usual order.
+    pub fn set_playhead(&self, info_hash: &[u8; 20], piece: Option<u32>) -> Result<()> {
+        let mut torrents = self.inner.torrents.lock().unwrap();
+        lookup(&mut torrents, info_hash)?
+            .playhead
+            .send_replace(piece);
+        Ok(())
+    }
+
+    /// Opens file `file_index` of [`TorrentInfo::file_paths`](crate::TorrentInfo::file_paths)
+    /// for reading while the torrent downloads; see [`FileReader`].
+    pub fn open_file(&self, info_hash: &[u8; 20], file_index: usize) -> Result<FileReader> {
+        let mut torrents = self.inner.torrents.lock().unwrap();
+        let torrent = lookup(&mut torrents, info_hash)?;
+        let info = &torrent.torrent_file.info;
+        let (path, _) = info
+            .file_paths()
+            .into_iter()
+            .nth(file_index)
+            .ok_or_else(|| Error::Storage(format!("{} has no file {}", info.name, file_index)))?;
+        let skipped = torrent.options.file_priorities.get(file_index) == Some(&FilePriority::Skip);
+        if skipped || torrent.options.piece.is_some() {
+            return Err(Error::Storage(format!(
+                "{} is not being downloaded",
+                path.display()
+            )));
+        }
+        let (start, length) = file_extent(info, file_index);
+        Ok(FileReader::new(
+            self.clone(),
+            *info_hash,
+            start,
+            length,
+            info.piece_length,
+        ))
+    }
+
+    /// Moves reader `reader`'s playhead to `piece`, or drops it with `None`.
+    pub(crate) fn set_reader_position(
+        &self,
+        info_hash: &[u8; 20],
+        reader: u64,
+        piece: Option<u32>,
+    ) -> Result<()> {
+        let mut torrents = self.inner.torrents.lock().unwrap();
+        lookup(&mut torrents, info_hash)?
+            .readers
+            .send_if_modified(|readers| match piece {
+                Some(piece) => readers.insert(reader, piece) != Some(piece),
+                None => readers.remove(&reader).is_some(),
+            });
+        Ok(())
+    }
+
+    /// Waits until `piece` is verified and returns its data.
+    pub(crate) async fn read_piece(self, info_hash: [u8; 20], piece: u32) -> Result<Vec<u8>> {
+        // Subscribe first so no verified piece is missed
+        let mut events = self.subscribe();
+        let (pieces, mut state) = {
+            let mut torrents = self.inner.torrents.lock().unwrap();
+            let torrent = lookup(&mut torrents, &info_hash)?;
+            (torrent.pieces.clone(), torrent.state.subscribe())
+        };
+        loop {
+            if pieces.contains(piece) {
+                return pieces.read(piece).await?.ok_or_else(|| {
+                    Error::Storage(format!("Piece {} is verified but not stored", piece))
+                });
+            }
+            if *state.borrow_and_update() == TorrentState::Failed {
+                return Err(Error::Peer("Download failed".into()));
+            }
+            tokio::select! {
+                event = events.recv() => {
+                    if let Err(broadcast::error::RecvError::Closed) = event {
+                        return Err(Error::Peer("Session is gone".into()));
+                    }
+                }
+                changed = state.changed() => {
+                    if changed.is_err() {
+                        return Err(Error::Peer("Torrent was removed".into()));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Waits until the torrent has been fully downloaded.
+    pub async fn wait_until_complete(&self, info_hash: &[u8; 20]) -> Result<()> {
+        let mut state = {
+            let mut torrents = self.inner.torrents.lock().unwrap();
+            lookup(&mut torrents, info_hash)?.state.subscribe()
+        };
+        let state = state
+            .wait_for(|state| matches!(state, TorrentState::Seeding | TorrentState::Failed))
+            .await
+            .map_err(|_| Error::Peer("Torrent was removed".into()))?;
+        match *state {
+            TorrentState::Failed => Err(Error::Peer("Download failed".into())),
+            _ => Ok(()),
+        }
+    }
+
+    fn connection_config(&self) -> ConnectionConfig {
+        ConnectionConfig {
+            dialer: self.inner.dialer.clone(),
+            encryption: self.inner.config.encryption,
+            bandwidth: vec![self.inner.bandwidth.clone()],
+            block_size: self.inner.config.block_size,
+            peer_id: self.inner.config.peer_id,
+        }
+    }
+
+    fn connection_manager(&self) -> ConnectionManager {
+        ConnectionManager::new(
+            self.inner.config.max_peers_per_torrent,
+            self.inner.global_slots.clone(),
+        )
+    }
+
+    async fn accept_tcp(self, listener: TcpListener) {
+        loop {
+            let Ok((stream, peer_address)) = listener.accept().await else {
+                continue;
+            };
+            tokio::spawn(self.clone().serve_incoming(stream, peer_address));
+        }
+    }
+
+    async fn accept_utp(self) {
+        let Some(utp) = self.inner.dialer.utp_socket().cloned() else {
+            return;
+        };
+        while let Ok((stream, peer_address)) = utp.accept().await {
+            tokio::spawn(self.clone().serve_incoming(stream, peer_address));
+        }
+    }
+
+    async fn serve_incoming<S: PeerStream + 'static>(self, stream: S, peer_address: SocketAddr) {
+        let Ok(_permit) = self.inner.global_slots.clone().try_acquire_owned() else {
+            return;
+        };
+        let info_hashes = self
+            .inner
+            .torrents
+            .lock()
+            .unwrap()
+            .keys()
+            .copied()
+            .collect::<Vec<_>>();
+        let config = self.connection_config();
+        let (response_tx, _) = mpsc::channel(1);
+        // Incoming peers come and go; failures aren't worth reporting
+        let _ = async {
+            let mut connection =
+                PeerConnection::accept(stream, peer_address, response_tx, &info_hashes, &config)
+                    .await?;
+            let info_hash = connection.receive_handshake().await?.info_hash;
+            let (torrent_file, pieces, limits, connections) = {
+                let torrents = self.inner.torrents.lock().unwrap();
+                let torrent = torrents
+                    .get(&info_hash)
+                    .filter(|torrent| *torrent.state.borrow() != TorrentState::Paused)
+                    .ok_or_else(|| Error::Peer("Peer asked for a torrent we don't serve".into()))?;
+                (
+                    torrent.torrent_file.clone(),
+                    torrent.pieces.clone(),
+                    torrent.limits.clone(),
+                    torrent.connections.clone(),
+                )
+            };
+            if connections.is_banned(peer_address) {
+                return Err(Error::Peer(format!("{} is banned", peer_address)));
+            }
+            let _slot = connections
+                .try_acquire_torrent_slot()
+                .ok_or_else(|| Error::Peer("Torrent is at its peer cap".into()))?;
+            connection
+                .serve(
+                    info_hash,
+                    torrent_file.info.piece_count(),
+                    &pieces,
+                    &limits.upload,
+                )
+                .await
+        }
+        .await;
+    }
+}
+
+fn lookup<'a>(
+    torrents: &'a mut HashMap<[u8; 20], Torrent>,
+    info_hash: &[u8; 20],
+) -> Result<&'a mut Torrent> {
+    torrents
+        .get_mut(info_hash)
+        .ok_or_else(|| Error::UnknownTorrent(bytes_to_hex(info_hash)))
+}
+
+/// Where a file of [`TorrentInfo::file_paths`] starts in the piece space and
+/// its length. v2 files start on a piece boundary of their own.
+fn file_extent(info: &TorrentInfo, file_index: usize) -> (u64, u64) {
+    let (_, length) = info.file_paths()[file_index];
+    if !info.has_v1() {
+        let first = file_piece_ranges(info)[file_index].start;
+        return (first as u64 * info.piece_length as u64, length);
+    }
+    let start = info
+        .content_files()
+        .into_iter()
+        .scan(0, |offset, file| {
+            let start = *offset;
+            *offset += file.length;
+            Some((start, file.padding))
+        })
+        .filter(|(_, padding)| !padding)
+        .nth(file_index)
+        .map_or(0, |(start, _)| start);
+    (start, length)
+}
+
+async fn request_piece(
+    peer_request_tx: &Sender<PeerRequest>,
+    piece_index: u32,
+    piece_length: u32,
+) -> Result<()> {
+    peer_request_tx
+        .send(PeerRequest::DowloadPiece {
+            piece_index,
+            piece_length,
+        })
+        .await
+        .map_err(|_| Error::Peer("All peer workers are gone".into()))
+}
+
+/// Listens on `[::]:port` for both IPv6 and IPv4 peers where possible.
+fn bind_listener(port: u16) -> Result<TcpListener> {
+    let socket = match Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP)) {
+        Ok(socket) => {
+            socket.set_only_v6(false)?;
+            socket.set_reuse_address(true)?;
+            socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+            socket
+        }
+        Err(_) => {
+            let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?;
+            socket.set_reuse_address(true)?;
+            socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)).into())?;
+            socket
+        }
+    };
+    socket.listen(128)?;
+    socket.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+/// Everything one run of a torrent needs; dropped when it is paused.
+struct TorrentJob {
+    session: Session,
+    torrent_file: Arc<TorrentFile>,
+    options: AddTorrentOptions,
+    limits: BandwidthLimits,
+    pieces: PieceStore,
+    connections: ConnectionManager,
+    state: watch::Sender<TorrentState>,
+    playhead: watch::Receiver<Option<u32>>,
+    readers: watch::Receiver<BTreeMap<u64, u32>>,
+}
+
+impl TorrentJob {
+    async fn run(self) {
+        let info_hash = bytes_to_hex(&self.torrent_file.info_hash());
+        let state = match self.download().await {
+            Ok(()) => {
+                self.emit_transferred();
+                self.session
+                    .inner
+                    .events
+                    .emit(Event::Completed { info_hash });
+                TorrentState::Seeding
+            }
+            Err(e) => {
+                self.session.inner.events.emit(Event::Error {
+                    info_hash,
+                    message: e.to_string(),
+                });
+                TorrentState::Failed
+            }
+        };
+        self.state.send_replace(state);
+    }
+
+    fn emit_transferred(&self) {
+        self.session.inner.events.emit(Event::Transferred {
+            info_hash: bytes_to_hex(&self.torrent_file.info_hash()),
+            downloaded: self.limits.download.total(),
+            uploaded: self.limits.upload.total(),
+        });
+    }
+
+    async fn download(&self) -> Result<()> {
+        let torrent_file = &self.torrent_file;
+        let session = &self.session.inner;
+        let info_hash = bytes_to_hex(&torrent_file.info_hash());
+        let wanted = match self.options.piece {
+            Some(piece) => vec![(piece, torrent_file.piece_length(piece))],
+            None => wanted_pieces(torrent_file, &self.options.file_priorities),
+        };
+        if let Some(storage) = self.pieces.storage() {
+            storage.allocate().await?;
+        }
+        let mut picker = PiecePicker::new(wanted, |piece_index| self.pieces.contains(piece_index));
+
+        if picker.remaining() > 0 {
+            let use_web_seeds = self.options.piece.is_none() && !torrent_file.url_list.is_empty();
+            let infohash = Arc::new(torrent_file.info_hash());
+
+            // Dropping the receiver when this run ends also stops discovery
+            let mut lan_peers = None;
+            if self.options.piece.is_none()
+                && session.config.local_discovery
+                && !torrent_file.info.private
+            {
+                match LocalServiceDiscovery::bind(session.config.listen_port) {
+                    Ok(lsd) => lan_peers = Some(lsd.spawn(*infohash, session.events.clone())),
+                    Err(e) => session.events.emit(Event::Warning {
+                        info_hash: info_hash.clone(),
+                        message: format!("Local service discovery unavailable: {}", e),
+                    }),
+                }
+            }
+
+            let announced = torrent_file.discover_peers(&session.tracker).await;
+            session.events.emit(Event::TrackerAnnounce {
+                info_hash: info_hash.clone(),
+                tracker: torrent_file.announce.clone(),
+                result: match &announced {
+                    Ok(peers) => Ok(peers.len()),
+                    Err(e) => Err(e.to_string()),
+                },
+            });
+            let peers = match announced {
+                Ok(peers) => peers,
+                // Web seeds and LAN peers can serve the whole torrent on
+                // their own
+                Err(_) if use_web_seeds || lan_peers.is_some() => Vec::new(),
+                Err(e) => return Err(e),
+            };
+
+            let (peer_request_tx, peer_request_rx) = mpsc::channel(session.config.channel_capacity);
+            let (peer_response_tx, mut peer_response_rx) =
+                mpsc::channel(session.config.channel_capacity);
+
+            let peer_manager = PeerManager::new(peer_request_rx, peer_response_tx)
+                .await
+                .with_encryption(session.config.encryption)
+                .with_block_size(session.config.block_size)
+                .with_peer_id(session.config.peer_id)
+                .with_dialer(session.dialer.clone())
+                .with_bandwidth_limits(session.bandwidth.clone())
+                .with_bandwidth_limits(self.limits.clone())
+                .with_connection_manager(self.connections.clone())
+                .with_events(session.events.clone());
+            peer_manager.spawn_peers(peers, infohash.clone()).await;
+
+            if use_web_seeds {
+                peer_manager
+                    .spawn_web_seeds(torrent_file.url_list.clone(), torrent_file.clone())
+                    .await;
+            }
+
+            let mut playhead = self.playhead.clone();
+            let mut readers = self.readers.clone();
+            let mut progress = interval(Duration::from_secs(1));
+            progress.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            let queue_depth = REQUEST_QUEUE_DEPTH.min(session.config.channel_capacity);
+
+            // While discovery runs, a LAN peer may still turn up
+            while picker.remaining() > 0 && (peer_manager.worker_count() > 0 || lan_peers.is_some())
+            {
+                let mut playheads = Vec::from_iter(*playhead.borrow_and_update());
+                playheads.extend(readers.borrow_and_update().values());
+                picker.set_playheads(playheads);
+                // Never wait for room: workers may be waiting for us to take
+                // their responses. Without room an overdue piece stays with
+                // its first peer.
+                for (piece_index, piece_length) in picker.overdue(Instant::now()) {
+                    if peer_request_tx.capacity() == 0 {
+                        break;
+                    }
+                    request_piece(&peer_request_tx, piece_index, piece_length).await?;
+                }
+                // A short queue, so pieces are picked as late as possible
+                while peer_request_tx.max_capacity() - peer_request_tx.capacity() < queue_depth {
+                    let Some((piece_index, piece_length)) = picker.pick(Instant::now()) else {
+                        break;
+                    };
+                    request_piece(&peer_request_tx, piece_index, piece_length).await?;
+                }
+
+                let data = tokio::select! {
+                    data = peer_response_rx.recv() => data,
+                    _ = progress.tick() => {
+                        self.emit_transferred();
+                        continue;
+                    }
+                    Ok(()) = playhead.changed() => continue,
+                    Ok(()) = readers.changed() => continue,
+                    peer = async { lan_peers.as_mut()?.recv().await }, if lan_peers.is_some() => {
+                        match peer {
+                            Some(peer) => {
+                                peer_manager.spawn_peers(vec![peer.into()], infohash.clone()).await;
+                            }
+                            None => lan_peers = None,
+                        }
+                        continue;
+                    }
+                };
+                let Some(data) = data else {
+                    break;
+                };
+                if data.data.is_empty() {
+                    session.events.emit(Event::PieceFailed {
+                        info_hash: info_hash.clone(),
+                        piece: data.piece,
+                        peer: data.peer,
+                        reason: PieceFailure::Download,
+                    });
+                    picker.failed(data.piece);
+                    continue;
+                }
+                // The loser of a race for an overdue piece
+                if self.pieces.contains(data.piece) {
+                    continue;
+                }
+                if !torrent_file.verify_piece(data.piece, &data.data) {
+                    session.events.emit(Event::PieceFailed {
+                        info_hash: info_hash.clone(),
+                        piece: data.piece,
+                        peer: data.peer,
+                        reason: PieceFailure::HashMismatch,
+                    });
+                    if let Some(peer) = data.peer {
+                        peer_manager.ban_peer(peer);
+                    }
+                    picker.failed(data.piece);
+                    continue;
+                }
+                let (piece, peer) = (data.piece, data.peer);
+                if self.options.piece.is_some() {
+                    fs::write(&self.options.output, &data.data).await?;
+                }
+                self.pieces.insert(piece, &data.data).await?;
+                picker.received(piece);
+                session.events.emit(Event::PieceVerified {
+                    info_hash: info_hash.clone(),
+                    piece,
+                    peer,
+                });
+            }
+
+            // Close the request channel so peer workers know when to exit
+            drop(peer_request_tx);
+            if picker.remaining() > 0 {
+                return Err(Error::Peer(format!(
+                    "All peers are gone with {} pieces left",
+                    picker.remaining()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tokio::net::UdpSocket;
+
+    use super::*;
+    use crate::{
+        create::{create_torrent, CreateOptions},
+        lsd::{LsdAnnounce, LSD_PORT},
+        webseed::tests::spawn_fake_seed,
+    };
+
+    #[tokio::test]
+    async fn test_fails_once_every_source_is_gone() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.bin"), vec![1u8; 100_000]).unwrap();
+        // A web seed nobody listens on, and no tracker
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/a.bin", closed.local_addr().unwrap());
+        drop(closed);
+        let options = CreateOptions {
+            web_seeds: vec![url],
+            ..Default::default()
+        };
+        let torrent_file = create_torrent(&dir.path().join("a.bin"), &options).unwrap();
+
+        let session = Session::new(SessionConfig {
+            listen_port: 0,
+            transport: TransportMode::Tcp,
+            local_discovery: false,
+            download_dir: dir.path().join("downloads"),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let info_hash = session
+            .add_torrent(torrent_file, AddTorrentOptions::default())
+            .unwrap();
+
+        let finished = tokio::time::timeout(
+            Duration::from_secs(30),
+            session.wait_until_complete(&info_hash),
+        );
+        assert!(finished.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_downloads_with_a_tiny_request_channel() {
+        let dir = tempfile::tempdir().unwrap();
+        let content = (0..200_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        fs::write(dir.path().join("a.bin"), &content).unwrap();
+        let url = spawn_fake_seed(dir.path()).await;
+        let options = CreateOptions {
+            web_seeds: vec![url],
+            ..Default::default()
+        };
+        let torrent_file = create_torrent(&dir.path().join("a.bin"), &options).unwrap();
+
+        let session = Session::new(SessionConfig {
+            listen_port: 0,
+            transport: TransportMode::Tcp,
+            local_discovery: false,
+            download_dir: dir.path().join("downloads"),
+            channel_capacity: 1,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let info_hash = session
+            .add_torrent(
+                torrent_file,
+                AddTorrentOptions {
+                    sequential: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let finished = tokio::time::timeout(
+            Duration::from_secs(30),
+            session.wait_until_complete(&info_hash),
+        );
+        finished.await.unwrap().unwrap();
+        assert_eq!(
+            fs::read(dir.path().join("downloads/a.bin")).unwrap(),
+            content
+        );
+    }
+
+    #[tokio::test]
+    async fn test_downloads_from_a_lan_peer_without_a_tracker() {
+        let dir = tempfile::tempdir().unwrap();
+        let content = (0..100_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        fs::write(dir.path().join("a.bin"), &content).unwrap();
+        let options = CreateOptions {
+            web_seeds: vec![spawn_fake_seed(dir.path()).await],
+            ..Default::default()
+        };
+        let seeded = create_torrent(&dir.path().join("a.bin"), &options).unwrap();
+        // The same torrent without the web seed and with a dead tracker
+        let mut torrent_file =
+            create_torrent(&dir.path().join("a.bin"), &CreateOptions::default()).unwrap();
+        torrent_file.announce = String::from("http://127.0.0.1:1/announce");
+        let free_port = || {
+            std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port()
+        };
+        let session = |listen_port, local_discovery, download_dir| {
+            Session::new(SessionConfig {
+                listen_port,
+                transport: TransportMode::Tcp,
+                local_discovery,
+                download_dir,
+                ..Default::default()
+            })
+        };
+
+        let seeder_port = free_port();
+        let seeder = session(seeder_port, false, dir.path().join("seeder"))
+            .await
+            .unwrap();
+        let info_hash = seeder
+            .add_torrent(seeded, AddTorrentOptions::default())
+            .unwrap();
+        seeder.wait_until_complete(&info_hash).await.unwrap();
+
+        let leecher = session(free_port(), true, dir.path().join("leecher"))
+            .await
+            .unwrap();
+        leecher
+            .add_torrent(torrent_file, AddTorrentOptions::default())
+            .unwrap();
+        // The seeder announcing itself, straight to the leecher's LSD socket
+        let announce = LsdAnnounce {
+            port: seeder_port,
+            info_hashes: vec![info_hash],
+            cookie: None,
+        };
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let finished = tokio::time::timeout(Duration::from_secs(30), async {
+            loop {
+                socket
+                    .send_to(&announce.to_bytes(), ("127.0.0.1", LSD_PORT))
+                    .await
+                    .unwrap();
+                let complete = leecher.wait_until_complete(&info_hash);
+                if let Ok(result) = tokio::time::timeout(Duration::from_millis(200), complete).await
+                {
+                    return result;
+                }
+            }
+        });
+        finished.await.unwrap().unwrap();
+        assert_eq!(fs::read(dir.path().join("leecher/a.bin")).unwrap(), content);
+    }
+}
@@ -0,0 +1,238 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+use toml::{Table, Value};
+
+use crate::{
+    api::DEFAULT_API_PORT,
+    error::{Error, Result},
+    mse::EncryptionPolicy,
+    session::SessionConfig,
+    tcp::generate_peer_id,
+    transport::TransportMode,
+};
+
+/// `BITTORRENT_LISTEN_PORT=51413` overrides `listen_port`, and so on for
+/// every setting.
+pub const ENV_PREFIX: &str = "BITTORRENT_";
+/// The fields of [`Settings`]; other `BITTORRENT_*` variables are ignored.
+const SETTING_NAMES: [&str; 13] = [
+    "listen_port",
+    "peer_id",
+    "block_size",
+    "channel_capacity",
+    "max_peers",
+    "max_peers_global",
+    "download_limit",
+    "upload_limit",
+    "encryption",
+    "transport",
+    "local_discovery",
+    "download_dir",
+    "api_port",
+];
+/// Settings taken as they are, even if they look like a number.
+const STRING_SETTINGS: [&str; 2] = ["peer_id", "download_dir"];
+/// Names the config file to read instead of the default one.
+pub const CONFIG_ENV: &str = "BITTORRENT_CONFIG";
+const MAX_BLOCK_SIZE: u32 = 128 * 1024;
+
+/// Everything an operator can tune. Each source overrides the previous one:
+/// built-in defaults, the TOML config file, `BITTORRENT_*` environment
+/// variables and finally command-line flags.
+///
+/// ```toml
+/// listen_port = 51413
+/// download_dir = "/srv/torrents"
+/// download_limit = 2048
+/// encryption = "forced"
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub listen_port: u16,
+    /// 20 bytes, sent to trackers and in handshakes. A fresh one, starting
+    /// with [`PEER_ID_PREFIX`](crate::tcp::PEER_ID_PREFIX), is generated per session if unset.
+    pub peer_id: Option<String>,
+    /// Size of the blocks pieces are requested in; many peers refuse
+    /// anything over 16 KiB.
+    pub block_size: u32,
+    /// Queue length between a torrent and its peer workers.
+    pub channel_capacity: usize,
+    /// Per torrent.
+    pub max_peers: usize,
+    pub max_peers_global: usize,
+    /// KiB/s; unlimited if unset.
+    pub download_limit: Option<u64>,
+    pub upload_limit: Option<u64>,
+    pub encryption: EncryptionPolicy,
+    pub transport: TransportMode,
+    pub local_discovery: bool,
+    pub download_dir: PathBuf,
+    /// Port of the daemon's control API on 127.0.0.1.
+    pub api_port: u16,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        let session = SessionConfig::default();
+        Settings {
+            listen_port: session.listen_port,
+            peer_id: None,
+            block_size: session.block_size,
+            channel_capacity: session.channel_capacity,
+            max_peers: session.max_peers_per_torrent,
+            max_peers_global: session.max_peers_global,
+            download_limit: None,
+            upload_limit: None,
+            encryption: session.encryption,
+            transport: session.transport,
+            local_discovery: session.local_discovery,
+            download_dir: session.download_dir,
+            api_port: DEFAULT_API_PORT,
+        }
+    }
+}
+
+impl Settings {
+    /// Reads the config file at `path`, or at `$BITTORRENT_CONFIG`, or the
+    /// default one if it exists, then applies the environment.
+    pub fn load(path: Option<&Path>) -> Result<Settings> {
+        let path = path
+            .map(Path::to_path_buf)
+            .or_else(|| env::var_os(CONFIG_ENV).map(PathBuf::from));
+        let contents = match path {
+            Some(path) => fs::read_to_string(&path)
+                .map_err(|e| Error::Config(format!("Cannot read {}: {}", path.display(), e)))?,
+            None => default_path()
+                .and_then(|path| fs::read_to_string(path).ok())
+                .unwrap_or_default(),
+        };
+        Settings::parse(&contents, env::vars())
+    }
+
+    /// Parses a config file and overrides it with `vars`, ignoring any not
+    /// naming a setting after [`ENV_PREFIX`].
+    pub fn parse(
+        contents: &str,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Settings> {
+        let mut table = contents
+            .parse::<Table>()
+            .map_err(|e| Error::Config(format!("Cannot parse the config file: {}", e)))?;
+        for (key, value) in vars {
+            let Some(name) = key.strip_prefix(ENV_PREFIX).map(str::to_lowercase) else {
+                continue;
+            };
+            if STRING_SETTINGS.contains(&name.as_str()) {
+                table.insert(name, Value::String(value));
+            } else if SETTING_NAMES.contains(&name.as_str()) {
+                table.insert(name, env_value(value));
+            }
+        }
+        let settings = table
+            .try_into::<Settings>()
+            .map_err(|e| Error::Config(e.to_string()))?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if let Some(peer_id) = self.peer_id.as_ref().filter(|peer_id| peer_id.len() != 20) {
+            return Err(Error::Config(format!(
+                "peer_id must be 20 bytes, not {}",
+                peer_id.len()
+            )));
+        }
+        if self.block_size == 0 || self.block_size > MAX_BLOCK_SIZE {
+            return Err(Error::Config(format!(
+                "block_size must be between 1 and {}",
+                MAX_BLOCK_SIZE
+            )));
+        }
+        if self.channel_capacity == 0 || self.max_peers == 0 || self.max_peers_global == 0 {
+            return Err(Error::Config(
+                "channel_capacity, max_peers and max_peers_global must be positive".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn session_config(&self) -> SessionConfig {
+        SessionConfig {
+            listen_port: self.listen_port,
+            peer_id: self
+                .peer_id
+                .as_ref()
+                .and_then(|peer_id| peer_id.as_bytes().try_into().ok())
+                .unwrap_or_else(generate_peer_id),
+            max_peers_global: self.max_peers_global,
+            max_peers_per_torrent: self.max_peers,
+            download_limit: self.download_limit.map(|limit| limit.saturating_mul(1024)),
+            upload_limit: self.upload_limit.map(|limit| limit.saturating_mul(1024)),
+            encryption: self.encryption,
+            transport: self.transport,
+            local_discovery: self.local_discovery,
+            block_size: self.block_size,
+            channel_capacity: self.channel_capacity,
+            download_dir: self.download_dir.clone(),
+        }
+    }
+}
+
+/// `$XDG_CONFIG_HOME/bittorrent/config.toml`, falling back to `~/.config`.
+fn default_path() -> Option<PathBuf> {
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_dir.join("bittorrent").join("config.toml"))
+}
+
+/// Environment values are TOML values where they parse as one, so numbers
+/// and booleans work unquoted; anything else is a string.
+fn env_value(value: String) -> Value {
+    format!("value = {}", value)
+        .parse::<Table>()
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or(Value::String(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_environment_overrides_file() {
+        let file = "listen_port = 51413\nencryption = \"forced\"\ndownload_limit = 100\n";
+        let vars = [
+            ("BITTORRENT_DOWNLOAD_LIMIT", "250"),
+            ("BITTORRENT_TRANSPORT", "tcp"),
+            ("BITTORRENT_LOCAL_DISCOVERY", "false"),
+            ("BITTORRENT_PEER_ID", "12345678901234567890"),
+            ("BITTORRENT_DOWNLOAD_DIR", "2024"),
+            ("BITTORRENT_LOG", "debug"),
+            ("BITTORRENT_CONFIG", "/etc/bittorrent.toml"),
+            ("HOME", "/root"),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string()));
+        let settings = Settings::parse(file, vars).unwrap();
+        assert_eq!(settings.listen_port, 51413);
+        assert_eq!(settings.encryption, EncryptionPolicy::Forced);
+        assert_eq!(settings.download_limit, Some(250));
+        assert_eq!(settings.transport, TransportMode::Tcp);
+        assert!(!settings.local_discovery);
+        assert_eq!(settings.peer_id.as_deref(), Some("12345678901234567890"));
+        assert_eq!(settings.download_dir, PathBuf::from("2024"));
+        assert_eq!(settings.block_size, Settings::default().block_size);
+        assert_eq!(settings.session_config().download_limit, Some(250 * 1024));
+        let huge = Settings::parse(&format!("upload_limit = {}", i64::MAX), []).unwrap();
+        assert_eq!(huge.session_config().upload_limit, Some(u64::MAX));
+
+        assert!(Settings::parse("listen_prot = 1", []).is_err());
+        assert!(Settings::parse("block_size = 0", []).is_err());
+    }
+}
@@ -0,0 +1,515 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    ffi::OsString,
+    io::{self, SeekFrom},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{self, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+use crate::{
+    error::{Error, Result},
+    parser::{is_plain_component, TorrentFile, TorrentInfo},
+};
+
+/// How eagerly a file is fetched. Pieces are requested highest priority
+/// first; a piece shared by two files gets the higher of the two.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilePriority {
+    /// Not downloaded or written at all.
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// Chooses file priorities with globs over each file's path inside the
+/// torrent, e.g. `*.mkv` or `extras/**`. Single-file torrents match against
+/// the torrent's name.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct FileSelection {
+    /// If not empty, files matching none of these are skipped.
+    pub only: Vec<String>,
+    /// Files to skip, even if they match `only`.
+    pub exclude: Vec<String>,
+    pub high: Vec<String>,
+    pub low: Vec<String>,
+}
+
+impl FileSelection {
+    /// One priority per file of [`TorrentInfo::file_paths`].
+    pub fn priorities(&self, info: &TorrentInfo) -> Result<Vec<FilePriority>> {
+        let only = glob_set(&self.only)?;
+        let exclude = glob_set(&self.exclude)?;
+        let high = glob_set(&self.high)?;
+        let low = glob_set(&self.low)?;
+
+        Ok(paths_inside(info)
+            .into_iter()
+            .map(|path| {
+                if (!self.only.is_empty() && !only.is_match(&path)) || exclude.is_match(&path) {
+                    FilePriority::Skip
+                } else if high.is_match(&path) {
+                    FilePriority::High
+                } else if low.is_match(&path) {
+                    FilePriority::Low
+                } else {
+                    FilePriority::Normal
+                }
+            })
+            .collect())
+    }
+}
+
+/// Each file of [`TorrentInfo::file_paths`] as a path inside the torrent:
+/// without the torrent's name for multi-file torrents, and the name itself
+/// for single-file ones.
+pub fn paths_inside(info: &TorrentInfo) -> Vec<PathBuf> {
+    let single_file = info.is_single_file();
+    info.file_paths()
+        .into_iter()
+        .map(|(path, _)| match single_file {
+            true => path,
+            false => path.iter().skip(1).collect(),
+        })
+        .collect()
+}
+
+fn glob_set(globs: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(
+            Glob::new(glob).map_err(|e| Error::Config(format!("Invalid glob {}: {}", glob, e)))?,
+        );
+    }
+    builder
+        .build()
+        .map_err(|e| Error::Config(format!("Invalid globs: {}", e)))
+}
+
+/// The pieces each file of [`TorrentInfo::file_paths`] overlaps. Empty files
+/// overlap none.
+pub fn file_piece_ranges(info: &TorrentInfo) -> Vec<Range<u32>> {
+    let piece_length = info.piece_length as u64;
+    let mut ranges = Vec::new();
+    if !info.has_v1() {
+        // Every v2 file starts on a piece boundary
+        let mut first = 0;
+        for file in info.content_files() {
+            let pieces = file.length.div_ceil(piece_length) as u32;
+            ranges.push(first..first + pieces);
+            first += pieces;
+        }
+        return ranges;
+    }
+    let mut start = 0;
+    for file in info.content_files() {
+        let end = start + file.length;
+        if !file.padding {
+            ranges.push(match file.length {
+                0 => 0..0,
+                _ => (start / piece_length) as u32..((end - 1) / piece_length + 1) as u32,
+            });
+        }
+        start = end;
+    }
+    ranges
+}
+
+/// The pieces to request and their lengths, highest priority first and in
+/// index order within a priority. Pieces that only overlap skipped files are
+/// left out; no priorities means every file is [`FilePriority::Normal`].
+pub fn wanted_pieces(torrent_file: &TorrentFile, priorities: &[FilePriority]) -> Vec<(u32, u32)> {
+    let all_pieces = torrent_file.piece_and_length();
+    if priorities.is_empty() {
+        return all_pieces;
+    }
+    let mut piece_priorities = vec![FilePriority::Skip; all_pieces.len()];
+    for (range, &priority) in file_piece_ranges(&torrent_file.info)
+        .into_iter()
+        .zip(priorities)
+    {
+        for piece in range {
+            let piece = &mut piece_priorities[piece as usize];
+            *piece = (*piece).max(priority);
+        }
+    }
+
+    let mut wanted = all_pieces
+        .into_iter()
+        .filter(|&(piece, _)| piece_priorities[piece as usize] != FilePriority::Skip)
+        .collect::<Vec<_>>();
+    wanted.sort_by_key(|&(piece, _)| std::cmp::Reverse(piece_priorities[piece as usize]));
+    wanted
+}
+
+/// Writes verified pieces into the torrent's files as they arrive.
+///
+/// A single-file torrent is written to `output`; a multi-file torrent to a
+/// directory `output` holding its files. Skipped files are never created.
+/// Pieces that also overlap a skipped file are kept whole in
+/// `<output>.parts`, each as a big-endian `u32` index and length followed by
+/// the data, so the piece can still be served and checked later.
+pub struct Storage {
+    torrent_file: Arc<TorrentFile>,
+    output: PathBuf,
+    skipped: HashSet<Vec<String>>,
+}
+
+impl Storage {
+    pub fn new(
+        torrent_file: Arc<TorrentFile>,
+        output: impl Into<PathBuf>,
+        priorities: &[FilePriority],
+    ) -> Storage {
+        let skipped = torrent_file
+            .info
+            .content_files()
+            .into_iter()
+            .filter(|file| !file.padding)
+            .zip(priorities)
+            .filter(|(_, &priority)| priority == FilePriority::Skip)
+            .map(|(file, _)| file.path)
+            .collect();
+        Storage {
+            torrent_file,
+            output: output.into(),
+            skipped,
+        }
+    }
+
+    /// Where a file of [`TorrentInfo::content_files`] is written. Fails for
+    /// a path that would end up outside `output`.
+    pub fn file_path(&self, path: &[String]) -> Result<PathBuf> {
+        if self.torrent_file.info.is_single_file() {
+            return Ok(self.output.clone());
+        }
+        let inside = path.get(1..).unwrap_or_default();
+        if inside.is_empty() || !inside.iter().all(|component| is_plain_component(component)) {
+            return Err(Error::Storage(format!(
+                "{:?} is not a path inside {}",
+                path,
+                self.output.display()
+            )));
+        }
+        let mut file_path = self.output.clone();
+        file_path.extend(inside);
+        Ok(file_path)
+    }
+
+    pub fn parts_path(&self) -> PathBuf {
+        let mut parts = OsString::from(self.output.as_os_str());
+        parts.push(".parts");
+        parts.into()
+    }
+
+    /// Creates every wanted file at its full length, keeping the data
+    /// already in it.
+    pub async fn allocate(&self) -> Result<()> {
+        for file in self.torrent_file.info.content_files() {
+            if file.padding || self.skipped.contains(&file.path) {
+                continue;
+            }
+            let path = self.file_path(&file.path)?;
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)
+                    .await
+                    .map_err(|e| storage_error(&path, e))?;
+            }
+            let handle = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)
+                .await
+                .map_err(|e| storage_error(&path, e))?;
+            let current = handle.metadata().await?.len();
+            if current != file.length {
+                handle.set_len(file.length).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn write_piece(&self, piece_index: u32, data: &[u8]) -> Result<()> {
+        let mut boundary = false;
+        let mut data_offset = 0;
+        for span in self.torrent_file.info.piece_spans(piece_index) {
+            let bytes = &data[data_offset..data_offset + span.length as usize];
+            data_offset += span.length as usize;
+            if span.padding {
+                continue;
+            }
+            if self.skipped.contains(&span.path) {
+                boundary = true;
+                continue;
+            }
+            let path = self.file_path(&span.path)?;
+            let mut file = OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .await
+                .map_err(|e| storage_error(&path, e))?;
+            file.seek(SeekFrom::Start(span.offset)).await?;
+            file.write_all(bytes).await?;
+            file.flush().await?;
+        }
+
+        if boundary {
+            let mut parts = self.read_parts().await?;
+            parts.insert(piece_index, data.to_vec());
+            let mut contents = Vec::new();
+            for (piece_index, data) in parts {
+                contents.extend(piece_index.to_be_bytes());
+                contents.extend((data.len() as u32).to_be_bytes());
+                contents.extend(data);
+            }
+            // Replaced whole, so readers never see a half-written record
+            let path = self.parts_path();
+            let mut temporary = OsString::from(path.as_os_str());
+            temporary.push(".tmp");
+            fs::write(&temporary, contents)
+                .await
+                .map_err(|e| storage_error(Path::new(&temporary), e))?;
+            fs::rename(&temporary, &path)
+                .await
+                .map_err(|e| storage_error(&path, e))?;
+        }
+        Ok(())
+    }
+
+    /// Reads back a piece written by [`Storage::write_piece`].
+    pub async fn read_piece(&self, piece_index: u32) -> Result<Vec<u8>> {
+        let spans = self.torrent_file.info.piece_spans(piece_index);
+        if spans
+            .iter()
+            .any(|span| !span.padding && self.skipped.contains(&span.path))
+        {
+            return self
+                .read_parts()
+                .await?
+                .remove(&piece_index)
+                .ok_or_else(|| {
+                    Error::Storage(format!(
+                        "{} has no piece {}",
+                        self.parts_path().display(),
+                        piece_index
+                    ))
+                });
+        }
+        let mut data = Vec::new();
+        for span in spans {
+            let start = data.len();
+            data.resize(start + span.length as usize, 0);
+            if span.padding {
+                continue;
+            }
+            let path = self.file_path(&span.path)?;
+            let mut file = fs::File::open(&path)
+                .await
+                .map_err(|e| read_error(&path, e))?;
+            file.seek(SeekFrom::Start(span.offset)).await?;
+            file.read_exact(&mut data[start..])
+                .await
+                .map_err(|e| read_error(&path, e))?;
+        }
+        Ok(data)
+    }
+
+    /// The pieces kept in `<output>.parts`, by index.
+    pub async fn read_parts(&self) -> Result<BTreeMap<u32, Vec<u8>>> {
+        let path = self.parts_path();
+        let contents = match fs::read(&path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+            Err(e) => return Err(read_error(&path, e)),
+        };
+        let mut parts = BTreeMap::new();
+        let mut rest = &contents[..];
+        while let (Some(piece_index), Some(length)) = (rest.get(..4), rest.get(4..8)) {
+            let piece_index = u32::from_be_bytes(piece_index.try_into().unwrap());
+            let length = u32::from_be_bytes(length.try_into().unwrap()) as usize;
+            let Some(data) = rest.get(8..8 + length) else {
+                return Err(Error::Storage(format!("{} is truncated", path.display())));
+            };
+            parts.insert(piece_index, data.to_vec());
+            rest = &rest[8 + length..];
+        }
+        Ok(parts)
+    }
+}
+
+/// The verified pieces of a torrent. Their data is read back from the
+/// torrent's [`Storage`]; without one, as for a single-piece download, only
+/// which pieces are verified is kept.
+#[derive(Clone, Default)]
+pub struct PieceStore {
+    verified: Arc<Mutex<BTreeSet<u32>>>,
+    storage: Option<Arc<Storage>>,
+}
+
+impl PieceStore {
+    pub fn new(storage: Option<Storage>) -> Self {
+        PieceStore {
+            verified: Arc::default(),
+            storage: storage.map(Arc::new),
+        }
+    }
+
+    pub fn storage(&self) -> Option<&Storage> {
+        self.storage.as_deref()
+    }
+
+    pub fn contains(&self, piece_index: u32) -> bool {
+        self.verified.lock().unwrap().contains(&piece_index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.verified.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The verified pieces [`PieceStore::read`] returns the data of.
+    pub fn readable(&self) -> Vec<u32> {
+        match self.storage {
+            Some(_) => self.verified.lock().unwrap().iter().copied().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Writes a verified piece to storage, if any, and marks it verified.
+    pub async fn insert(&self, piece_index: u32, data: &[u8]) -> Result<()> {
+        if let Some(storage) = &self.storage {
+            storage.write_piece(piece_index, data).await?;
+        }
+        self.verified.lock().unwrap().insert(piece_index);
+        Ok(())
+    }
+
+    /// The data of a verified piece, or `None` if it isn't verified or
+    /// there is no storage to read it from.
+    pub async fn read(&self, piece_index: u32) -> Result<Option<Vec<u8>>> {
+        match &self.storage {
+            Some(storage) if self.contains(piece_index) => {
+                storage.read_piece(piece_index).await.map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+fn storage_error(path: &Path, e: io::Error) -> Error {
+    Error::Storage(format!("Cannot write {}: {}", path.display(), e))
+}
+
+fn read_error(path: &Path, e: io::Error) -> Error {
+    Error::Storage(format!("Cannot read {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::create::{create_torrent, CreateOptions};
+
+    #[tokio::test]
+    async fn test_selected_files_and_boundary_pieces() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("release");
+        fs::create_dir_all(&root).unwrap();
+        let mut content = vec![1u8; 20_000];
+        content.extend(b"hello");
+        content.extend(vec![2u8; 30_000]);
+        fs::write(root.join("a.bin"), &content[..20_000]).unwrap();
+        fs::write(root.join("b.txt"), &content[20_000..20_005]).unwrap();
+        fs::write(root.join("c.bin"), &content[20_005..]).unwrap();
+        let torrent_file = Arc::new(create_torrent(&root, &CreateOptions::default()).unwrap());
+        let piece_length = torrent_file.info.piece_length as usize;
+        assert_eq!(piece_length, 16 * 1024);
+
+        let selection = FileSelection {
+            high: vec!["*.txt".into()],
+            ..Default::default()
+        };
+        let priorities = selection.priorities(&torrent_file.info).unwrap();
+        let order = wanted_pieces(&torrent_file, &priorities)
+            .into_iter()
+            .map(|(piece, _)| piece)
+            .collect::<Vec<_>>();
+        assert_eq!(order, vec![1, 0, 2, 3]);
+
+        let selection = FileSelection {
+            only: vec!["*.txt".into()],
+            ..Default::default()
+        };
+        let priorities = selection.priorities(&torrent_file.info).unwrap();
+        assert_eq!(
+            priorities,
+            vec![FilePriority::Skip, FilePriority::Normal, FilePriority::Skip]
+        );
+        assert_eq!(
+            wanted_pieces(&torrent_file, &priorities),
+            vec![(1, piece_length as u32)]
+        );
+
+        let output = dir.path().join("out");
+        let storage = Storage::new(torrent_file, &output, &priorities);
+        storage.allocate().await.unwrap();
+        let piece = &content[piece_length..2 * piece_length];
+        storage.write_piece(1, piece).await.unwrap();
+        // Written again, e.g. by a second run; the record is replaced
+        storage.write_piece(1, piece).await.unwrap();
+
+        assert_eq!(fs::read(output.join("b.txt")).unwrap(), b"hello");
+        assert!(!output.join("a.bin").exists());
+        assert!(!output.join("c.bin").exists());
+        let parts = fs::read(storage.parts_path()).unwrap();
+        assert_eq!(parts.len(), 8 + piece_length);
+        assert_eq!(parts[..4], 1u32.to_be_bytes());
+        assert_eq!(parts[4..8], (piece_length as u32).to_be_bytes());
+        assert_eq!(&parts[8..], piece);
+        assert_eq!(storage.read_piece(1).await.unwrap(), piece);
+
+        let storage = Storage::new(storage.torrent_file.clone(), dir.path().join("all"), &[]);
+        storage.allocate().await.unwrap();
+        storage.write_piece(1, piece).await.unwrap();
+        assert_eq!(storage.read_piece(1).await.unwrap(), piece);
+        assert!(!storage.parts_path().exists());
+    }
+
+    #[tokio::test]
+    async fn test_rejects_paths_outside_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("release");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.bin"), [1; 100]).unwrap();
+        fs::write(root.join("b.bin"), [2; 100]).unwrap();
+        let mut torrent_file = create_torrent(&root, &CreateOptions::default()).unwrap();
+        // Built by hand, so the parser's checks never ran
+        torrent_file.info.files[0].path = vec!["..".into(), "escaped".into()];
+
+        let output = dir.path().join("out");
+        let storage = Storage::new(Arc::new(torrent_file), &output, &[]);
+        assert!(storage.file_path(&["release".into(), "..".into()]).is_err());
+        assert!(storage
+            .file_path(&["release".into(), "/etc".into()])
+            .is_err());
+        assert!(storage.allocate().await.is_err());
+        assert!(storage.write_piece(0, &[1; 200]).await.is_err());
+        assert!(!dir.path().join("escaped").exists());
+    }
+}
@@ -0,0 +1,318 @@
+use std::{io::SeekFrom, ops::Range, path::Path, time::Duration};
+
+use tokio::{
+    io::{self, AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, Take},
+    net::{TcpListener, TcpStream},
+    time::sleep,
+};
+
+use crate::{
+    error::{Error, Result},
+    parser::TorrentInfo,
+    session::Session,
+    storage::paths_inside,
+};
+
+/// The most we read of a request line and its headers; players send a few
+/// hundred bytes.
+const MAX_HEAD_LENGTH: u64 = 16 * 1024;
+/// Pause after a failed accept, e.g. when we're out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Serves every file of torrent `info_hash` on `listener`, at the paths of
+/// [`file_urls`], while it downloads. `GET /` lists the files.
+///
+/// Each request reads through a [`FileReader`](crate::FileReader), so it
+/// waits for the pieces it covers and they are fetched first. A `Range`
+/// header (`bytes=start-end`, `bytes=start-` or `bytes=-suffix`) is answered
+/// with `206 Partial Content`, which is what players need to seek.
+pub async fn serve_files(session: Session, info_hash: [u8; 20], listener: TcpListener) {
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            sleep(ACCEPT_BACKOFF).await;
+            continue;
+        };
+        let session = session.clone();
+        tokio::spawn(async move {
+            // Players hang up mid-stream all the time; nothing worth reporting
+            let _ = handle_request(stream, &session, &info_hash).await;
+        });
+    }
+}
+
+/// The URL path of each file of [`TorrentInfo::file_paths`], percent-encoded.
+pub fn file_urls(info: &TorrentInfo) -> Vec<String> {
+    paths_inside(info)
+        .iter()
+        .map(|path| {
+            path.iter()
+                .map(|component| format!("/{}", urlencoding::encode(&component.to_string_lossy())))
+                .collect()
+        })
+        .collect()
+}
+
+async fn handle_request(stream: TcpStream, session: &Session, info_hash: &[u8; 20]) -> Result<()> {
+    let mut stream = BufReader::new(stream.take(MAX_HEAD_LENGTH));
+
+    let request_line = read_head_line(&mut stream).await?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(Error::Api("Malformed request line".into()));
+    };
+    let (method, target) = (method.to_string(), target.to_string());
+
+    let mut range = None;
+    loop {
+        let header = read_head_line(&mut stream).await?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("range") {
+                range = Some(value.trim().to_string());
+            }
+        }
+    }
+    let stream = stream.get_mut().get_mut();
+
+    if method != "GET" && method != "HEAD" {
+        return respond(stream, "405 Method Not Allowed", "Only GET and HEAD").await;
+    }
+    let info = &session.torrent_file(info_hash)?.info;
+    let path = target.split('?').next().unwrap_or_default();
+    let path = urlencoding::decode(path).map_err(|e| Error::Api(e.to_string()))?;
+    if path == "/" {
+        let index = file_urls(info).join("\n") + "\n";
+        return respond(stream, "200 OK", &index).await;
+    }
+    let path = path.strip_prefix('/').unwrap_or(&path);
+    let file_index = paths_inside(info)
+        .iter()
+        .position(|inside| Path::new(path) == inside);
+    let reader = file_index.map(|file_index| session.open_file(info_hash, file_index));
+    let mut reader = match reader {
+        Some(Ok(reader)) => reader,
+        Some(Err(e)) => return respond(stream, "404 Not Found", &e.to_string()).await,
+        None => return respond(stream, "404 Not Found", "No such file").await,
+    };
+
+    let length = reader.len();
+    let (status, range) = match range {
+        None => ("200 OK", 0..length),
+        Some(range) => match parse_range(&range, length) {
+            Some(range) => ("206 Partial Content", range),
+            None => {
+                let head = format!(
+                    "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    length
+                );
+                stream.write_all(head.as_bytes()).await?;
+                return Ok(());
+            }
+        },
+    };
+    let mut head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nAccept-Ranges: bytes\r\nConnection: close\r\n",
+        status,
+        content_type(path),
+        range.end - range.start
+    );
+    if status.starts_with("206") {
+        head += &format!(
+            "Content-Range: bytes {}-{}/{}\r\n",
+            range.start,
+            range.end - 1,
+            length
+        );
+    }
+    head += "\r\n";
+    stream.write_all(head.as_bytes()).await?;
+    if method == "GET" {
+        reader.seek(SeekFrom::Start(range.start)).await?;
+        io::copy(&mut reader.take(range.end - range.start), stream).await?;
+    }
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// One line of the request head, failing once the head grows past
+/// [`MAX_HEAD_LENGTH`].
+async fn read_head_line(stream: &mut BufReader<Take<TcpStream>>) -> Result<String> {
+    let mut line = String::new();
+    stream.read_line(&mut line).await?;
+    if !line.ends_with('\n') {
+        return Err(Error::Api("Request head is too long or cut short".into()));
+    }
+    Ok(line)
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, body: &str) -> Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// The bytes a `Range` header asks for, or `None` if it can't be satisfied.
+/// Several ranges aren't supported; they get the whole file.
+fn parse_range(header: &str, length: u64) -> Option<Range<u64>> {
+    let spec = header.strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return (length > 0).then_some(0..length);
+    }
+    let (start, end) = spec.split_once('-')?;
+    let range = match (start.trim(), end.trim()) {
+        ("", suffix) => length.saturating_sub(suffix.parse().ok()?)..length,
+        (start, "") => start.parse().ok()?..length,
+        (start, end) => start.parse().ok()?..length.min(end.parse::<u64>().ok()?.checked_add(1)?),
+    };
+    (range.start < range.end).then_some(range)
+}
+
+fn content_type(path: &str) -> &'static str {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    match extension.as_str() {
+        "mp4" | "m4v" => "video/mp4",
+        "mkv" => "video/x-matroska",
+        "webm" => "video/webm",
+        "avi" => "video/x-msvideo",
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "ogg" => "audio/ogg",
+        "txt" | "nfo" => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        create::{create_torrent, CreateOptions},
+        session::{AddTorrentOptions, SessionConfig},
+        storage::FilePriority,
+        transport::TransportMode,
+        webseed::tests::spawn_fake_seed,
+    };
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(0..100));
+        assert_eq!(parse_range("bytes=900-", 1000), Some(900..1000));
+        assert_eq!(parse_range("bytes=-100", 1000), Some(900..1000));
+        assert_eq!(parse_range("bytes=990-2000", 1000), Some(990..1000));
+        assert_eq!(parse_range("bytes=0-1,5-9", 1000), Some(0..1000));
+        assert_eq!(parse_range("bytes=1000-", 1000), None);
+        assert_eq!(parse_range("bytes=-0", 1000), None);
+        assert_eq!(parse_range("items=0-1", 1000), None);
+        assert_eq!(content_type("/Show/e01.MKV"), "video/x-matroska");
+    }
+
+    #[tokio::test]
+    async fn test_serve_files_over_loopback() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("show");
+        fs::create_dir_all(&root).unwrap();
+        let content = (0..50_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        fs::write(root.join("e01.mkv"), &content).unwrap();
+        fs::write(root.join("extras.bin"), [7; 1000]).unwrap();
+
+        let url = spawn_fake_seed(dir.path()).await;
+
+        let options = CreateOptions {
+            web_seeds: vec![url],
+            ..Default::default()
+        };
+        let torrent_file = create_torrent(&root, &options).unwrap();
+        let session = Session::new(SessionConfig {
+            listen_port: 0,
+            transport: TransportMode::Tcp,
+            local_discovery: false,
+            download_dir: dir.path().join("downloads"),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let info_hash = session
+            .add_torrent(
+                torrent_file,
+                AddTorrentOptions {
+                    file_priorities: vec![FilePriority::Normal, FilePriority::Skip],
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let base = format!("http://{}", address);
+        tokio::spawn(serve_files(session, info_hash, listener));
+        let client = reqwest::Client::new();
+
+        let index = client.get(&base).send().await.unwrap();
+        assert_eq!(index.text().await.unwrap(), "/e01.mkv\n/extras.bin\n");
+
+        let episode = format!("{}/e01.mkv", base);
+        let partial = client
+            .get(&episode)
+            .header("Range", "bytes=100-199")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(partial.status(), 206);
+        assert_eq!(partial.headers()["content-range"], "bytes 100-199/50000");
+        assert_eq!(partial.headers()["content-type"], "video/x-matroska");
+        assert_eq!(partial.bytes().await.unwrap(), content[100..200]);
+
+        let unsatisfiable = client
+            .get(&episode)
+            .header("Range", "bytes=50000-")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(unsatisfiable.status(), 416);
+        assert_eq!(unsatisfiable.headers()["content-range"], "bytes */50000");
+
+        let head = client.head(&episode).send().await.unwrap();
+        assert_eq!(head.status(), 200);
+        assert_eq!(head.headers()["content-length"], "50000");
+        assert_eq!(head.bytes().await.unwrap().len(), 0);
+
+        let skipped = client
+            .get(format!("{}/extras.bin", base))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(skipped.status(), 404);
+        let missing = client
+            .get(format!("{}/e02.mkv", base))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(missing.status(), 404);
+
+        // A head that never ends is cut off instead of read forever
+        let mut endless = TcpStream::connect(address).await.unwrap();
+        let filler = format!("X-Filler: {}\r\n", "a".repeat(1000));
+        let mut head = b"GET / HTTP/1.1\r\n".to_vec();
+        head.extend(filler.repeat(32).as_bytes());
+        let _ = endless.write_all(&head).await;
+        let mut response = Vec::new();
+        let hung_up =
+            tokio::time::timeout(Duration::from_secs(5), endless.read_to_end(&mut response));
+        assert!(hung_up.await.is_ok());
+        assert!(response.is_empty());
+    }
+}
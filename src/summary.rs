@@ -0,0 +1,227 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::{
+    hasher::bytes_to_hex,
+    parser::TorrentFile,
+    util::{format_bytes, format_unix_timestamp},
+};
+
+/// Machine-readable description of a metainfo, printed by `info --json`.
+/// Fields are only ever added to this schema, never renamed or removed.
+#[derive(Debug, Serialize)]
+pub struct TorrentSummary {
+    pub name: String,
+    pub info_hash_v1: Option<String>,
+    pub info_hash_v2: Option<String>,
+    pub announce: Option<String>,
+    pub announce_list: Vec<Vec<String>>,
+    pub length: u64,
+    pub piece_length: u32,
+    pub piece_count: usize,
+    pub private: bool,
+    pub creation_date: Option<i64>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    pub web_seeds: Vec<String>,
+    pub files: Vec<FileSummary>,
+    pub piece_hashes: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileSummary {
+    /// Relative to the torrent's directory; just the file name for single-file torrents.
+    pub path: Vec<String>,
+    pub length: u64,
+}
+
+impl TorrentSummary {
+    pub fn new(torrent_file: &TorrentFile) -> TorrentSummary {
+        let info = &torrent_file.info;
+        let files = if !info.file_tree.is_empty() {
+            info.file_tree
+                .iter()
+                .map(|file| FileSummary {
+                    path: file.path.clone(),
+                    length: file.length,
+                })
+                .collect()
+        } else if info.files.is_empty() {
+            vec![FileSummary {
+                path: vec![info.name.clone()],
+                length: info.length,
+            }]
+        } else {
+            info.files
+                .iter()
+                .filter(|file| !file.is_padding())
+                .map(|file| FileSummary {
+                    path: file.path.clone(),
+                    length: file.length,
+                })
+                .collect()
+        };
+
+        TorrentSummary {
+            name: info.name.clone(),
+            info_hash_v1: torrent_file.info_hash_v1().map(|hash| bytes_to_hex(&hash)),
+            info_hash_v2: torrent_file.info_hash_v2().map(|hash| bytes_to_hex(&hash)),
+            announce: Some(torrent_file.announce.clone()).filter(|announce| !announce.is_empty()),
+            announce_list: torrent_file.announce_list.clone(),
+            length: info.length,
+            piece_length: info.piece_length,
+            piece_count: info.piece_count(),
+            private: info.private,
+            creation_date: torrent_file.creation_date,
+            comment: torrent_file.comment.clone(),
+            created_by: torrent_file.created_by.clone(),
+            web_seeds: torrent_file.url_list.clone(),
+            files,
+            piece_hashes: info.pieces.chunks(20).map(bytes_to_hex).collect(),
+        }
+    }
+
+    pub fn print_text(&self) {
+        println!("Name: {}", self.name);
+        println!("Tracker URL: {}", self.announce.as_deref().unwrap_or(""));
+        for (tier, trackers) in self.announce_list.iter().enumerate() {
+            println!("Tier {}: {}", tier + 1, trackers.join(", "));
+        }
+        println!("Length: {}", self.length);
+        if let Some(info_hash) = &self.info_hash_v1 {
+            println!("Info Hash: {}", info_hash);
+        }
+        if let Some(info_hash) = &self.info_hash_v2 {
+            println!("Info Hash v2: {}", info_hash);
+        }
+        println!("Piece Length: {}", self.piece_length);
+        println!("Piece Count: {}", self.piece_count);
+        println!("Private: {}", if self.private { "yes" } else { "no" });
+        if let Some(creation_date) = self.creation_date {
+            println!("Creation Date: {}", format_unix_timestamp(creation_date));
+        }
+        if let Some(comment) = &self.comment {
+            println!("Comment: {}", comment);
+        }
+        if let Some(created_by) = &self.created_by {
+            println!("Created By: {}", created_by);
+        }
+        for web_seed in &self.web_seeds {
+            println!("Web Seed: {}", web_seed);
+        }
+
+        println!("Files:");
+        self.print_file_tree();
+
+        println!("Piece Hashes:");
+        for piece_hash in &self.piece_hashes {
+            println!("{}", piece_hash);
+        }
+    }
+
+    fn print_file_tree(&self) {
+        #[derive(Default)]
+        struct Node {
+            length: u64,
+            children: BTreeMap<String, Node>,
+        }
+
+        fn print(name: &str, node: &Node, depth: usize) {
+            let suffix = if node.children.is_empty() { "" } else { "/" };
+            println!(
+                "{}{}{} ({})",
+                "  ".repeat(depth),
+                name,
+                suffix,
+                format_bytes(node.length)
+            );
+            for (child_name, child) in &node.children {
+                print(child_name, child, depth + 1);
+            }
+        }
+
+        if self.files.len() == 1 && self.files[0].path == [self.name.clone()] {
+            print(
+                &self.name,
+                &Node {
+                    length: self.length,
+                    children: BTreeMap::new(),
+                },
+                1,
+            );
+            return;
+        }
+
+        let mut root = Node::default();
+        for file in &self.files {
+            root.length += file.length;
+            let mut node = &mut root;
+            for component in &file.path {
+                node = node.children.entry(component.clone()).or_default();
+                node.length += file.length;
+            }
+        }
+        print(&self.name, &root, 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{FileEntry, TorrentInfo};
+
+    #[test]
+    fn test_json_summary() {
+        let info = TorrentInfo {
+            length: 20,
+            name: String::from("sample"),
+            piece_length: 16,
+            pieces: vec![0xab; 40],
+            files: vec![
+                FileEntry {
+                    length: 15,
+                    path: vec![String::from("a.txt")],
+                    attr: None,
+                },
+                FileEntry {
+                    length: 5,
+                    path: vec![String::from("sub"), String::from("b.txt")],
+                    attr: None,
+                },
+            ],
+            ..Default::default()
+        };
+        let mut torrent_file = TorrentFile::new(String::from("http://tracker.test/announce"), info);
+        torrent_file.creation_date = Some(0);
+        torrent_file.comment = Some(String::from("sample"));
+
+        let json = serde_json::to_value(TorrentSummary::new(&torrent_file)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "name": "sample",
+                "info_hash_v1": "823f29b4c654570b8276e71bb537edb16dee466f",
+                "info_hash_v2": null,
+                "announce": "http://tracker.test/announce",
+                "announce_list": [],
+                "length": 20,
+                "piece_length": 16,
+                "piece_count": 2,
+                "private": false,
+                "creation_date": 0,
+                "comment": "sample",
+                "created_by": null,
+                "web_seeds": [],
+                "files": [
+                    { "path": ["a.txt"], "length": 15 },
+                    { "path": ["sub", "b.txt"], "length": 5 },
+                ],
+                "piece_hashes": [
+                    "abababababababababababababababababababab",
+                    "abababababababababababababababababababab",
+                ],
+            })
+        );
+    }
+}
@@ -1,23 +1,47 @@
 use std::{
     cmp::min,
-    collections::HashMap,
-    future::Future,
-    net::Ipv4Addr,
-    pin::Pin,
-    sync::{mpsc, Arc},
-    task::{Context, Poll},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
+use rand::{distributions::Alphanumeric, Rng};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
     sync::{
         mpsc::{Receiver, Sender},
         Mutex,
     },
+    time::{sleep, timeout},
+};
+
+use crate::{
+    connections::{global_limit, ConnectionManager, DEFAULT_MAX_PEERS_PER_TORRENT},
+    error::{Error, Result},
+    events::{Event, EventBus},
+    hasher::bytes_to_hex,
+    mse::{self, EncryptionPolicy},
+    parser::TorrentFile,
+    ratelimit::{BandwidthLimits, RateLimitedStream, RateLimiter},
+    storage::PieceStore,
+    transport::{connect_tcp, Dialer, PeerStream},
+    webseed::{web_seed_worker, WebSeed},
+    CHUNKSIZE,
 };
 
-use crate::{cli::PeerRequest, hasher::bytes_to_hex, CHUNKSIZE};
+const ENCRYPTION_TIMEOUT: Duration = Duration::from_secs(10);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const PROTOCOL: &[u8; 20] = b"\x13BitTorrent protocol";
+const UNCHOKE_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_MESSAGE_LENGTH: u32 = 1 << 20;
+const MAX_BLOCK_LENGTH: u32 = 128 * 1024;
+/// Peers that take longer than this for one piece are dropped as too slow.
+const PIECE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Azureus-style client id and version that starts our peer ids.
+pub const PEER_ID_PREFIX: &[u8; 8] = b"-CB0100-";
 
 #[derive(Clone, Copy, Debug)]
 pub enum PeerMessage {
@@ -30,59 +54,259 @@ pub enum PeerMessage {
     Request,
     Piece,
     Cancel,
+    /// BEP 10 extension protocol.
+    Extended = 20,
 }
 
+/// Protocol extensions advertised in the reserved bytes of a handshake.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// BEP 5
+    pub dht: bool,
+    /// BEP 6
+    pub fast: bool,
+    /// BEP 10
+    pub extension: bool,
+}
+
+impl Capabilities {
+    pub fn from_reserved(reserved: [u8; 8]) -> Self {
+        Capabilities {
+            dht: reserved[7] & 0x01 != 0,
+            fast: reserved[7] & 0x04 != 0,
+            extension: reserved[5] & 0x10 != 0,
+        }
+    }
+
+    pub fn to_reserved(self) -> [u8; 8] {
+        let mut reserved = [0u8; 8];
+        reserved[7] |= self.dht as u8 | (self.fast as u8) << 2;
+        reserved[5] |= (self.extension as u8) << 4;
+        reserved
+    }
+}
+
+/// One side's BitTorrent handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handshake {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub capabilities: Capabilities,
+}
+
+impl Handshake {
+    pub fn to_bytes(&self) -> [u8; 68] {
+        let mut bytes = [0u8; 68];
+        bytes[..20].copy_from_slice(PROTOCOL);
+        bytes[20..28].copy_from_slice(&self.capabilities.to_reserved());
+        bytes[28..48].copy_from_slice(&self.info_hash);
+        bytes[48..].copy_from_slice(&self.peer_id);
+        bytes
+    }
+
+    pub fn parse(bytes: &[u8; 68]) -> Result<Handshake> {
+        if &bytes[..20] != PROTOCOL {
+            return Err(Error::Peer("Not a BitTorrent handshake".into()));
+        }
+        Ok(Handshake {
+            info_hash: bytes[28..48].try_into().unwrap(),
+            peer_id: bytes[48..].try_into().unwrap(),
+            capabilities: Capabilities::from_reserved(bytes[20..28].try_into().unwrap()),
+        })
+    }
+}
+
+/// Work handed to peer workers.
+#[derive(Debug)]
+pub enum PeerRequest {
+    DowloadPiece { piece_index: u32, piece_length: u32 },
+}
+
+/// A downloaded piece. Empty data means the download failed.
 pub struct PeerResponse {
     pub data: Vec<u8>,
     pub piece: u32,
+    /// `None` for pieces from web seeds.
+    pub peer: Option<SocketAddr>,
+}
+
+/// How peer connections are opened and shaped.
+#[derive(Clone)]
+pub struct ConnectionConfig {
+    pub dialer: Dialer,
+    pub encryption: EncryptionPolicy,
+    /// Every scope (global, per torrent) whose rate limits apply.
+    pub bandwidth: Vec<BandwidthLimits>,
+    /// Size of the blocks pieces are requested in.
+    pub block_size: u32,
+    /// Ours, sent in every handshake.
+    pub peer_id: [u8; 20],
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        ConnectionConfig {
+            dialer: Dialer::default(),
+            encryption: EncryptionPolicy::default(),
+            bandwidth: Vec::new(),
+            block_size: CHUNKSIZE,
+            peer_id: generate_peer_id(),
+        }
+    }
+}
+
+/// A fresh peer id: [`PEER_ID_PREFIX`] followed by 12 random alphanumerics.
+pub fn generate_peer_id() -> [u8; 20] {
+    let mut peer_id = [0u8; 20];
+    peer_id[..8].copy_from_slice(PEER_ID_PREFIX);
+    for (byte, random) in peer_id[8..]
+        .iter_mut()
+        .zip(rand::thread_rng().sample_iter(Alphanumeric))
+    {
+        *byte = random;
+    }
+    peer_id
 }
 
+/// One connection speaking the peer wire protocol.
 pub struct PeerConnection {
-    pub stream: TcpStream,
-    pub peer_address: String,
+    pub stream: Box<dyn PeerStream>,
+    pub peer_address: SocketAddr,
     pub response_tx: Sender<PeerResponse>,
+    /// Ours, not the remote peer's.
+    pub peer_id: [u8; 20],
 }
 
 impl PeerConnection {
-    pub async fn new(peer_address: String, response_tx: Sender<PeerResponse>) -> Self {
-        // let address = format!("{}:{}", peer_address.0, peer_address.1);
-        let stream = TcpStream::connect(peer_address.clone()).await.unwrap();
-        PeerConnection {
+    pub async fn new(
+        peer_address: SocketAddr,
+        peer_id: [u8; 20],
+        response_tx: Sender<PeerResponse>,
+    ) -> Result<Self> {
+        let stream = connect_tcp(peer_address).await?;
+        Ok(PeerConnection {
+            stream: Box::new(stream),
+            peer_address,
+            response_tx,
+            peer_id,
+        })
+    }
+
+    /// Connects to a peer, negotiating encryption according to the config.
+    /// With `Enabled`, peers that fail the encrypted handshake are redialled
+    /// in plaintext.
+    pub async fn connect(
+        peer_address: SocketAddr,
+        response_tx: Sender<PeerResponse>,
+        infohash: &[u8; 20],
+        config: &ConnectionConfig,
+    ) -> Result<Self> {
+        let dial = || async {
+            let stream = config.dialer.dial(peer_address).await?;
+            Ok::<_, Error>(RateLimitedStream::new(stream, config.bandwidth.clone()))
+        };
+        let encryption = config.encryption;
+        let stream = dial().await?;
+        let stream: Box<dyn PeerStream> = match encryption {
+            EncryptionPolicy::Disabled => Box::new(stream),
+            EncryptionPolicy::Enabled => {
+                match timeout(
+                    ENCRYPTION_TIMEOUT,
+                    mse::initiate(stream, infohash, encryption),
+                )
+                .await
+                {
+                    Ok(Ok(stream)) => Box::new(stream),
+                    _ => Box::new(dial().await?),
+                }
+            }
+            EncryptionPolicy::Forced => Box::new(
+                timeout(
+                    ENCRYPTION_TIMEOUT,
+                    mse::initiate(stream, infohash, encryption),
+                )
+                .await
+                .map_err(|_| Error::Peer("Encryption handshake timed out".into()))??,
+            ),
+        };
+        Ok(PeerConnection {
             stream,
             peer_address,
             response_tx,
-        }
+            peer_id: config.peer_id,
+        })
     }
 
-    // pub async fn temp(peer_address: String)
-    pub async fn establish_connection(&mut self, infohash: Arc<[u8; 20]>) {
-        self.handshake(infohash, None).await;
-        self.wait(PeerMessage::Bitfield).await;
-        self.send_interested().await;
-        self.wait(PeerMessage::Unchoke).await;
+    /// Wraps an incoming connection, answering its encryption handshake if it
+    /// sent one. The BitTorrent handshake itself is left to the caller.
+    pub async fn accept<S: PeerStream + 'static>(
+        stream: S,
+        peer_address: SocketAddr,
+        response_tx: Sender<PeerResponse>,
+        info_hashes: &[[u8; 20]],
+        config: &ConnectionConfig,
+    ) -> Result<Self> {
+        let stream = RateLimitedStream::new(stream, config.bandwidth.clone());
+        let (stream, _) = timeout(
+            ENCRYPTION_TIMEOUT,
+            mse::accept(stream, info_hashes, config.encryption),
+        )
+        .await
+        .map_err(|_| Error::Peer("Encryption handshake timed out".into()))??;
+        Ok(PeerConnection {
+            stream: Box::new(stream),
+            peer_address,
+            response_tx,
+            peer_id: config.peer_id,
+        })
+    }
+
+    /// Handshakes and waits to be unchoked, returning the peer's handshake.
+    pub async fn establish_connection(&mut self, infohash: Arc<[u8; 20]>) -> Result<Handshake> {
+        let handshake = self.handshake(&infohash, Capabilities::default()).await?;
+        timeout(UNCHOKE_TIMEOUT, async {
+            self.wait(PeerMessage::Bitfield).await?;
+            self.send_interested().await?;
+            self.wait(PeerMessage::Unchoke).await
+        })
+        .await
+        .map_err(|_| Error::Peer("Peer never unchoked us".into()))??;
+        Ok(handshake)
     }
 
-    pub async fn download_and_respond_piece(&mut self, piece_index: u32, piece_length: u32) {
+    /// Downloads one piece in blocks of `block_size` and sends it on
+    /// `response_tx`.
+    pub async fn download_and_respond_piece(
+        &mut self,
+        piece_index: u32,
+        piece_length: u32,
+        block_size: u32,
+    ) -> Result<()> {
         let mut i = 0;
         let mut piece_data_in_bytes = Vec::new();
         while i < piece_length {
-            let block_length = min(CHUNKSIZE, piece_length - i);
+            let block_length = min(block_size, piece_length - i);
 
-            self.send_request(piece_index as u32, i as u32, block_length as u32)
-                .await;
+            self.send_request(piece_index, i, block_length).await?;
 
-            let payload = self.wait(PeerMessage::Piece).await;
+            let payload = self.wait(PeerMessage::Piece).await?;
+            if payload.len() < 8 {
+                return Err(Error::Peer("Piece message too short".into()));
+            }
             // Verify we got the right piece and offset
             let received_index =
                 u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
             let received_begin =
                 u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]);
 
-            if received_index != piece_index as u32 || received_begin != i as u32 {
-                panic!(
+            if received_index != piece_index || received_begin != i {
+                return Err(Error::Peer(format!(
                     "Received wrong piece data: expected piece {}, offset {}, got piece {}, offset {}",
-                    piece_index, i, received_index, received_begin
-                );
+                    piece_index,
+                    i,
+                    received_index,
+                    received_begin
+                )));
             }
 
             piece_data_in_bytes.extend_from_slice(&payload[8..]);
@@ -95,81 +319,196 @@ impl PeerConnection {
             .send(PeerResponse {
                 data: piece_data_in_bytes,
                 piece: piece_index,
+                peer: Some(self.peer_address),
             })
             .await;
+        Ok(())
     }
-    pub async fn handshake(&mut self, infohash: Arc<[u8; 20]>, extension: Option<bool>) -> String {
-        // Construct a message
-        let mut message = vec![19];
-        message.extend(b"BitTorrent protocol"); // 19 bytes
-        message.extend([0u8; 8]);
-        message.extend(*infohash);
-        message.extend(b"00112233445566778899");
 
-        self.stream.write(&message).await.unwrap();
+    /// Sends our handshake advertising `capabilities` and returns the
+    /// peer's, which must be for the same torrent.
+    pub async fn handshake(
+        &mut self,
+        info_hash: &[u8; 20],
+        capabilities: Capabilities,
+    ) -> Result<Handshake> {
+        let ours = Handshake {
+            info_hash: *info_hash,
+            peer_id: self.peer_id,
+            capabilities,
+        };
+        let theirs = timeout(HANDSHAKE_TIMEOUT, async {
+            self.stream.write_all(&ours.to_bytes()).await?;
+            self.read_handshake().await
+        })
+        .await
+        .map_err(|_| Error::Peer("Handshake timed out".into()))??;
+        if theirs.info_hash != *info_hash {
+            return Err(Error::Peer("Peer answered for another torrent".into()));
+        }
+        Ok(theirs)
+    }
 
-        let mut response = vec![0; message.len()];
-        self.stream.read(&mut response).await.unwrap();
+    async fn read_handshake(&mut self) -> Result<Handshake> {
+        let mut bytes = [0u8; 68];
+        self.stream.read_exact(&mut bytes).await?;
+        let handshake = Handshake::parse(&bytes)?;
+        if handshake.peer_id == self.peer_id {
+            return Err(Error::SelfConnection);
+        }
+        Ok(handshake)
+    }
 
-        let response_peer_id = &response[response.len() - 20..];
-        // println!("Peer ID: {}", bytes_to_hex(response_peer_id));
-        return bytes_to_hex(response_peer_id);
+    /// Reads messages until one of type `id` arrives, skipping keep-alives
+    /// and anything else we don't act on yet. Being choked while waiting for
+    /// a piece is an error.
+    pub async fn wait(&mut self, id: PeerMessage) -> Result<Vec<u8>> {
+        loop {
+            let (msg_type, payload) = self.read_message().await?;
+            if msg_type == id as u8 {
+                return Ok(payload);
+            }
+            if msg_type == PeerMessage::Choke as u8 && matches!(id, PeerMessage::Piece) {
+                return Err(Error::Peer("Choked while downloading".into()));
+            }
+        }
     }
 
-    pub async fn wait(&mut self, id: PeerMessage) -> Vec<u8> {
-        // println!("Peer Message: {:?}", id);
-        let mut length_buf = [0; 4];
-        self.stream.read_exact(&mut length_buf).await.unwrap();
+    /// Reads the next message other than a keep-alive as (id, payload).
+    pub async fn read_message(&mut self) -> Result<(u8, Vec<u8>)> {
+        loop {
+            let mut length_buf = [0; 4];
+            self.stream.read_exact(&mut length_buf).await?;
+            let length = u32::from_be_bytes(length_buf);
+            if length == 0 {
+                continue;
+            }
+            if length > MAX_MESSAGE_LENGTH {
+                return Err(Error::Peer(format!(
+                    "Message of {} bytes is too long",
+                    length
+                )));
+            }
+
+            let mut msg_type = [0; 1];
+            self.stream.read_exact(&mut msg_type).await?;
 
-        let mut msg_type = [0; 1];
-        self.stream
-            .read_exact(&mut msg_type)
-            .await
-            .expect("Failed to read mssage id");
-        if msg_type[0] != id.clone() as u8 {
-            panic!("Expected msg id {}, got {}", id as u8, msg_type[0]);
+            let mut payload = vec![0; length as usize - 1];
+            self.stream.read_exact(&mut payload).await?;
+            return Ok((msg_type[0], payload));
         }
+    }
 
-        let payload_size = u32::from_be_bytes(length_buf) - 1;
-        let mut payload = vec![0; payload_size as usize];
-        self.stream
-            .read_exact(&mut payload)
+    /// Reads the handshake of an incoming peer. Checking that we serve the
+    /// info hash it asked for is left to the caller.
+    pub async fn receive_handshake(&mut self) -> Result<Handshake> {
+        timeout(HANDSHAKE_TIMEOUT, self.read_handshake())
             .await
-            .expect("Failed to read payload");
-        return payload;
+            .map_err(|_| Error::Peer("Handshake timed out".into()))?
+    }
+
+    /// Answers an incoming peer's handshake and uploads whatever it requests
+    /// from `pieces` until it disconnects, charging `upload` for every block.
+    pub async fn serve(
+        &mut self,
+        infohash: [u8; 20],
+        piece_count: usize,
+        pieces: &PieceStore,
+        upload: &RateLimiter,
+    ) -> Result<()> {
+        let handshake = Handshake {
+            info_hash: infohash,
+            peer_id: self.peer_id,
+            capabilities: Capabilities::default(),
+        };
+        self.stream.write_all(&handshake.to_bytes()).await?;
+
+        let mut bitfield = vec![0u8; piece_count.div_ceil(8)];
+        for piece_index in pieces.readable() {
+            bitfield[piece_index as usize / 8] |= 0x80 >> (piece_index % 8);
+        }
+        self.send_message(PeerMessage::Bitfield, bitfield).await?;
+        self.send_message(PeerMessage::Unchoke, vec![]).await?;
+
+        // The piece last read from storage, for the blocks that follow
+        let mut current: Option<(u32, Vec<u8>)> = None;
+        loop {
+            let (msg_type, payload) = self.read_message().await?;
+            if msg_type != PeerMessage::Request as u8 || payload.len() != 12 {
+                continue;
+            }
+            let field = |at: usize| {
+                u32::from_be_bytes([
+                    payload[at],
+                    payload[at + 1],
+                    payload[at + 2],
+                    payload[at + 3],
+                ])
+            };
+            let (piece_index, begin, length) = (field(0), field(4), field(8));
+            if current.as_ref().map(|(index, _)| *index) != Some(piece_index) {
+                current = pieces
+                    .read(piece_index)
+                    .await?
+                    .map(|data| (piece_index, data));
+            }
+            let block = current.as_ref().and_then(|(_, piece)| {
+                piece
+                    .get(begin as usize..begin as usize + length.min(MAX_BLOCK_LENGTH) as usize)
+                    .map(<[u8]>::to_vec)
+            });
+            if let Some(block) = block {
+                let mut reply = Vec::with_capacity(8 + block.len());
+                reply.extend(piece_index.to_be_bytes());
+                reply.extend(begin.to_be_bytes());
+                reply.extend(block);
+                let length = reply.len();
+                self.send_message(PeerMessage::Piece, reply).await?;
+                // Charged here because the torrent wasn't known when the
+                // stream was wrapped
+                let delay = upload.consume(length);
+                if !delay.is_zero() {
+                    sleep(delay).await;
+                }
+            }
+        }
     }
 
-    pub async fn send_interested(&mut self) {
-        self.send_message(PeerMessage::Interested, vec![]).await;
+    pub async fn send_interested(&mut self) -> Result<()> {
+        self.send_message(PeerMessage::Interested, vec![]).await
     }
 
-    pub async fn send_request(&mut self, piece_index: u32, begin: u32, length: u32) {
+    pub async fn send_request(&mut self, piece_index: u32, begin: u32, length: u32) -> Result<()> {
         let mut payload = vec![0; 12];
 
         payload[0..4].copy_from_slice(&piece_index.to_be_bytes());
         payload[4..8].copy_from_slice(&begin.to_be_bytes());
         payload[8..12].copy_from_slice(&length.to_be_bytes());
-        self.send_message(PeerMessage::Request, payload).await;
+        self.send_message(PeerMessage::Request, payload).await
     }
 
-    pub async fn send_message(&mut self, id: PeerMessage, payload: Vec<u8>) {
+    pub async fn send_message(&mut self, id: PeerMessage, payload: Vec<u8>) -> Result<()> {
         let mut msg = vec![0; 5 + payload.len()];
-        let mut length = payload.len() as u32;
-        if length == 0 {
-            length = 1;
-        }
-        // println!("Hello");
+        // The length prefix covers the message id as well as the payload
+        let length = payload.len() as u32 + 1;
         msg[0..4].copy_from_slice(&(length).to_be_bytes());
         msg[4] = id as u8;
         msg[5..].copy_from_slice(&payload);
 
-        self.stream.write_all(&msg).await.unwrap();
+        self.stream.write_all(&msg).await?;
+        Ok(())
     }
 }
 
+/// Spawns peer workers that take piece requests from a shared queue.
+#[derive(Clone)]
 pub struct PeerManager {
     peer_request_rx: Arc<Mutex<Receiver<PeerRequest>>>,
     peer_response_tx: Sender<PeerResponse>,
+    config: ConnectionConfig,
+    connections: ConnectionManager,
+    events: EventBus,
+    workers: Arc<AtomicUsize>,
 }
 
 impl PeerManager {
@@ -177,42 +516,215 @@ impl PeerManager {
         PeerManager {
             peer_request_rx: Arc::new(Mutex::new(request_rx)),
             peer_response_tx: response_tx,
+            config: ConnectionConfig {
+                dialer: Dialer::tcp(),
+                ..Default::default()
+            },
+            connections: ConnectionManager::new(
+                DEFAULT_MAX_PEERS_PER_TORRENT,
+                global_limit(DEFAULT_MAX_PEERS_PER_TORRENT),
+            ),
+            events: EventBus::default(),
+            workers: Arc::default(),
         }
     }
 
-    pub async fn spawn_peers(&self, peer_addresses: Vec<String>, infohash: Arc<[u8; 20]>) {
-        let peer_request_rx = self.peer_request_rx.clone();
-        // let infohash = Arc::new(infohash);
+    pub fn with_dialer(mut self, dialer: Dialer) -> Self {
+        self.config.dialer = dialer;
+        self
+    }
+
+    pub fn with_encryption(mut self, encryption: EncryptionPolicy) -> Self {
+        self.config.encryption = encryption;
+        self
+    }
+
+    pub fn with_block_size(mut self, block_size: u32) -> Self {
+        self.config.block_size = block_size;
+        self
+    }
+
+    pub fn with_peer_id(mut self, peer_id: [u8; 20]) -> Self {
+        self.config.peer_id = peer_id;
+        self
+    }
+
+    /// Adds a set of limits every connection is charged against; call once
+    /// per scope, e.g. for the global and the per-torrent limits.
+    pub fn with_bandwidth_limits(mut self, limits: BandwidthLimits) -> Self {
+        self.config.bandwidth.push(limits);
+        self
+    }
+
+    pub fn with_connection_manager(mut self, connections: ConnectionManager) -> Self {
+        self.connections = connections;
+        self
+    }
+
+    /// Where peer connect and disconnect events are published.
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Starts a supervised worker for every address not seen before.
+    pub async fn spawn_peers(&self, peer_addresses: Vec<SocketAddr>, infohash: Arc<[u8; 20]>) {
         for peer_address in peer_addresses {
-            let infohash = infohash.clone();
+            if !self.connections.add(peer_address) {
+                continue;
+            }
+            let worker = WorkerGuard::new(&self.workers);
+            let supervisor = supervise_peer(
+                peer_address,
+                infohash.clone(),
+                self.peer_response_tx.clone(),
+                self.peer_request_rx.clone(),
+                self.config.clone(),
+                self.connections.clone(),
+                self.events.clone(),
+            );
+            let _handle = tokio::spawn(async move {
+                supervisor.await;
+                drop(worker);
+            });
+        }
+    }
+
+    /// Peer and web seed workers that haven't given up yet. Once none are
+    /// left, nothing answers the requests anymore.
+    pub fn worker_count(&self) -> usize {
+        self.workers.load(Ordering::Relaxed)
+    }
+
+    /// Called when a piece from this peer fails its hash check.
+    pub fn ban_peer(&self, peer_address: SocketAddr) {
+        self.connections.ban(peer_address);
+    }
+}
 
-            let peer_request_rx = peer_request_rx.clone();
-            let peer_response_tx = self.peer_response_tx.clone();
-            let _handle = tokio::spawn(peer_worker(
-                peer_address.clone(),
-                infohash,
-                peer_response_tx,
-                peer_request_rx,
-            ));
+impl PeerManager {
+    pub async fn spawn_web_seeds(&self, urls: Vec<String>, torrent_file: Arc<TorrentFile>) {
+        for url in urls {
+            let worker = WorkerGuard::new(&self.workers);
+            let web_seed = web_seed_worker(
+                WebSeed::new(url),
+                torrent_file.clone(),
+                self.peer_response_tx.clone(),
+                self.peer_request_rx.clone(),
+                self.events.clone(),
+            );
+            let _handle = tokio::spawn(async move {
+                web_seed.await;
+                drop(worker);
+            });
         }
     }
 }
 
-pub async fn peer_worker(
-    peer_address: String,
+/// Counts one running worker in [`PeerManager::worker_count`] until dropped.
+struct WorkerGuard(Arc<AtomicUsize>);
+
+impl WorkerGuard {
+    fn new(workers: &Arc<AtomicUsize>) -> Self {
+        workers.fetch_add(1, Ordering::Relaxed);
+        WorkerGuard(workers.clone())
+    }
+}
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Keeps a peer connected while there is work, reconnecting with backoff
+/// after failures until the connection manager gives up on it.
+async fn supervise_peer(
+    peer_address: SocketAddr,
     infohash: Arc<[u8; 20]>,
     response_tx: Sender<PeerResponse>,
     request_rx: Arc<Mutex<Receiver<PeerRequest>>>,
+    config: ConnectionConfig,
+    connections: ConnectionManager,
+    events: EventBus,
 ) {
-    let mut connection = PeerConnection::new(peer_address, response_tx).await;
-    connection.establish_connection(infohash).await;
+    loop {
+        let permits = connections.acquire().await;
+        if connections.is_banned(peer_address) {
+            return;
+        }
+        let result = peer_worker(
+            peer_address,
+            infohash.clone(),
+            response_tx.clone(),
+            request_rx.clone(),
+            &config,
+            &connections,
+            &events,
+        )
+        .await;
+        drop(permits);
+
+        // The address stays known, so it's never dialled again
+        if let Err(Error::SelfConnection) = result {
+            return;
+        }
+        match connections.disconnected(peer_address, result.is_err()) {
+            Some(backoff) => sleep(backoff).await,
+            None => return,
+        }
+    }
+}
 
+/// Serves piece requests until the queue closes. A piece in progress when the
+/// connection fails is answered with empty data so it gets queued again.
+pub async fn peer_worker(
+    peer_address: SocketAddr,
+    infohash: Arc<[u8; 20]>,
+    response_tx: Sender<PeerResponse>,
+    request_rx: Arc<Mutex<Receiver<PeerRequest>>>,
+    config: &ConnectionConfig,
+    connections: &ConnectionManager,
+    events: &EventBus,
+) -> Result<()> {
+    let info_hash = bytes_to_hex(&*infohash);
+    let mut connection =
+        PeerConnection::connect(peer_address, response_tx.clone(), &infohash, config).await?;
+    let handshake = connection.establish_connection(infohash).await?;
+    if !connections.connected(peer_address, &bytes_to_hex(&handshake.peer_id)) {
+        // Already connected to this peer under another address
+        return Ok(());
+    }
+
+    events.emit(Event::PeerConnected {
+        info_hash: info_hash.clone(),
+        peer: peer_address,
+    });
+    let result = download_pieces(&mut connection, &request_rx, config, connections).await;
+    events.emit(Event::PeerDisconnected {
+        info_hash,
+        peer: peer_address,
+        error: result.as_ref().err().map(ToString::to_string),
+    });
+    result
+}
+
+async fn download_pieces(
+    connection: &mut PeerConnection,
+    request_rx: &Mutex<Receiver<PeerRequest>>,
+    config: &ConnectionConfig,
+    connections: &ConnectionManager,
+) -> Result<()> {
+    let peer_address = connection.peer_address;
     loop {
+        if connections.is_banned(peer_address) {
+            return Ok(());
+        }
         let req = {
             let mut rx = request_rx.lock().await; // Lock briefly
             match rx.recv().await {
                 Some(req) => req,
-                None => break, // Channel closed, exit loop
+                None => return Ok(()), // Channel closed, exit loop
             }
         }; // Lock released immediately
 
@@ -221,10 +733,83 @@ pub async fn peer_worker(
                 piece_index,
                 piece_length,
             } => {
-                connection
-                    .download_and_respond_piece(piece_index, piece_length)
-                    .await;
+                let download = timeout(
+                    PIECE_TIMEOUT,
+                    connection.download_and_respond_piece(
+                        piece_index,
+                        piece_length,
+                        config.block_size,
+                    ),
+                )
+                .await
+                .unwrap_or_else(|_| Err(Error::Peer("Peer too slow".into())));
+                if let Err(e) = download {
+                    let _ = connection
+                        .response_tx
+                        .send(PeerResponse {
+                            data: Vec::new(),
+                            piece: piece_index,
+                            peer: Some(peer_address),
+                        })
+                        .await;
+                    return Err(e);
+                }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_handshake_with_ourselves_fails() {
+        let peer_id = generate_peer_id();
+        assert!(peer_id.starts_with(PEER_ID_PREFIX));
+        assert!(peer_id.iter().all(u8::is_ascii_graphic));
+        assert_ne!(generate_peer_id(), peer_id);
+
+        // A peer that echoes our handshake looks exactly like ourselves
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer_address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0u8; 68];
+            stream.read_exact(&mut handshake).await.unwrap();
+            stream.write_all(&handshake).await.unwrap();
+        });
+
+        let (response_tx, _) = tokio::sync::mpsc::channel(1);
+        let mut connection = PeerConnection::new(peer_address, peer_id, response_tx)
+            .await
+            .unwrap();
+        let result = connection
+            .handshake(&[7; 20], Capabilities::default())
+            .await;
+        assert!(matches!(result, Err(Error::SelfConnection)));
+    }
+
+    #[test]
+    fn test_handshake_bytes() {
+        let handshake = Handshake {
+            info_hash: [1; 20],
+            peer_id: *b"-XX0000-abcdefghijkl",
+            capabilities: Capabilities {
+                dht: true,
+                fast: false,
+                extension: true,
+            },
+        };
+        let bytes = handshake.to_bytes();
+        assert_eq!(&bytes[..20], b"\x13BitTorrent protocol");
+        assert_eq!(bytes[20..28], [0, 0, 0, 0, 0, 0x10, 0, 0x01]);
+        assert_eq!(Handshake::parse(&bytes).unwrap(), handshake);
+
+        let mut bad = bytes;
+        bad[1] = b'b';
+        assert!(Handshake::parse(&bad).is_err());
+    }
+}
@@ -0,0 +1,87 @@
+use std::{net::SocketAddr, time::Duration};
+
+use clap::ValueEnum;
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    time::timeout,
+};
+
+use crate::{
+    error::{Error, Result},
+    utp::UtpSocket,
+};
+
+const TCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Any byte stream a peer connection can run over.
+pub trait PeerStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> PeerStream for T {}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TransportMode {
+    Tcp,
+    Utp,
+    /// Try uTP first and fall back to TCP for peers that don't answer it.
+    #[default]
+    PreferUtp,
+}
+
+/// Opens outgoing peer connections over TCP or uTP.
+#[derive(Clone, Default)]
+pub struct Dialer {
+    mode: TransportMode,
+    utp: Option<UtpSocket>,
+}
+
+impl Dialer {
+    pub fn tcp() -> Self {
+        Dialer {
+            mode: TransportMode::Tcp,
+            utp: None,
+        }
+    }
+
+    /// Binds the shared uTP socket on `port` (IPv4 and IPv6) when the mode
+    /// needs one, using an ephemeral port if it is taken.
+    pub async fn new(mode: TransportMode, port: u16) -> Result<Self> {
+        if mode == TransportMode::Tcp {
+            return Ok(Dialer::tcp());
+        }
+        let utp = match UtpSocket::bind_dual_stack(port) {
+            Ok(utp) => utp,
+            Err(_) => UtpSocket::bind_dual_stack(0)?,
+        };
+        Ok(Dialer {
+            mode,
+            utp: Some(utp),
+        })
+    }
+
+    pub fn utp_socket(&self) -> Option<&UtpSocket> {
+        self.utp.as_ref()
+    }
+
+    pub async fn dial(&self, peer_address: SocketAddr) -> Result<Box<dyn PeerStream>> {
+        match (&self.utp, self.mode) {
+            (Some(utp), TransportMode::Utp) => Ok(Box::new(utp.connect(peer_address).await?)),
+            // uTP gives up after its last SYN goes unanswered
+            (Some(utp), TransportMode::PreferUtp) => match utp.connect(peer_address).await {
+                Ok(stream) => Ok(Box::new(stream)),
+                Err(_) => Ok(Box::new(connect_tcp(peer_address).await?)),
+            },
+            _ => Ok(Box::new(connect_tcp(peer_address).await?)),
+        }
+    }
+}
+
+/// Connects over TCP, giving up on peers that don't answer in time.
+pub async fn connect_tcp(peer_address: SocketAddr) -> Result<TcpStream> {
+    timeout(TCP_CONNECT_TIMEOUT, TcpStream::connect(peer_address))
+        .await
+        .map_err(|_| Error::Peer(format!("Connecting to {} timed out", peer_address)))?
+        .map_err(Error::from)
+}
@@ -1,125 +1,164 @@
-pub fn find_e_for_index(s: &str, index: usize) -> usize {
-    let mut count = 1;
-
-    let chars = s.char_indices().skip(index + 1);
-
-    for (i, ch) in chars {
-        if ch == 'e' {
-            count -= 1;
-        } else if ['l', 'd', 'i'].contains(&ch) {
-            count += 1;
-        }
+use crate::{
+    error::{Error, Result},
+    merkle::Hash,
+};
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MagnetLink {
+    /// `btih`, or the truncated `btmh` hash for v2-only links.
+    pub info_hash: [u8; 20],
+    pub info_hash_v2: Option<Hash>,
+    pub display_name: Option<String>,
+    pub trackers: Vec<String>,
+    /// BEP 19 `ws` web seeds.
+    pub web_seeds: Vec<String>,
+}
 
-        if count == 0 {
-            return i;
+// magnet:?xt=urn:btih:{info_hash}&dn={file_name}&tr={tracker_url}&ws={web_seed}
+pub fn decode_magnet_link(magnet_link: &str) -> Result<MagnetLink> {
+    let query = magnet_link
+        .strip_prefix("magnet:?")
+        .ok_or_else(|| Error::Metainfo(format!("Not a magnet link: {}", magnet_link)))?;
+    let params = serde_urlencoded::from_str::<Vec<(String, String)>>(query)
+        .map_err(|e| Error::Metainfo(format!("Invalid magnet link: {}", e)))?;
+
+    let mut info_hash = None;
+    let mut magnet = MagnetLink::default();
+    for (key, value) in params {
+        match key.as_str() {
+            "xt" => {
+                if let Some(btih) = value.strip_prefix("urn:btih:") {
+                    info_hash = Some(decode_btih(btih)?);
+                } else if let Some(btmh) = value.strip_prefix("urn:btmh:1220") {
+                    let hash: Hash = hex::decode(btmh)
+                        .ok()
+                        .and_then(|hash| hash.try_into().ok())
+                        .ok_or_else(|| Error::Metainfo(format!("Invalid btmh hash: {}", btmh)))?;
+                    magnet.info_hash_v2 = Some(hash);
+                }
+            }
+            "dn" => magnet.display_name = Some(value),
+            "tr" => magnet.trackers.push(value),
+            "ws" => magnet.web_seeds.push(value),
+            _ => {}
         }
     }
 
-    return 0;
+    magnet.info_hash = match (info_hash, magnet.info_hash_v2) {
+        (Some(info_hash), _) => info_hash,
+        (None, Some(info_hash_v2)) => info_hash_v2[..20].try_into().unwrap(),
+        (None, None) => return Err(Error::Metainfo("Magnet link has no info hash".into())),
+    };
+    Ok(magnet)
 }
 
-pub fn decode_bencoded_value(encoded_value: &str, index: usize) -> (serde_json::Value, usize) {
-    // println!("encoded_value: {}", encoded_value);
-    if encoded_value.chars().nth(index).unwrap().is_digit(10) {
-        let parts: Vec<&str> = encoded_value[index..].split(":").collect();
-        let num_string = parts[0].to_string();
-        let num_integer = num_string.parse::<i32>().unwrap();
-
-        let start = index + num_string.len() + 1;
-        let end = start + num_integer as usize;
-
-        // println!("start {}, end {}, len {}", start, end, encoded_value.len());
-        if end > encoded_value.len() {
-            return (
-                serde_json::Value::String("".to_string()),
-                encoded_value.len(),
-            );
-        }
-
-        let decoded_string = &encoded_value[start..end];
-
-        // println!("decoded string {}, end {}", decoded_string, end);
-        return (serde_json::Value::String(decoded_string.to_string()), end);
-    } else if encoded_value.chars().nth(index).unwrap() == 'i' {
-        let e_position = find_e_for_index(encoded_value, index);
-
-        let parsed_value = &encoded_value[index + 1..e_position];
-
-        // println!("decoded string {}, end {}", parsed_value, e_position + 1);
-
-        return (
-            serde_json::Value::Number(parsed_value.parse::<i64>().unwrap().into()),
-            e_position + 1,
-        );
-    } else if encoded_value.chars().nth(index).unwrap() == 'l' {
-        let mut i = index + 1;
-
-        // println!("i : {}", i);
-
-        let mut lst: Vec<serde_json::Value> = Vec::new();
+/// `btih` hashes come as 40 hex or 32 base32 characters.
+fn decode_btih(btih: &str) -> Result<[u8; 20]> {
+    let bytes = match btih.len() {
+        40 => hex::decode(btih).ok(),
+        32 => decode_base32(btih),
+        _ => None,
+    };
+    bytes
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| Error::Metainfo(format!("Invalid btih hash: {}", btih)))
+}
 
-        while i < encoded_value.len() {
-            if encoded_value.chars().nth(i).unwrap() == 'e' {
-                break;
-            } else {
-                let (decoded_value, new_index) = decode_bencoded_value(encoded_value, i);
-                // println!(
-                //     "decoded_value {}, new_index {}",
-                //     decoded_value.to_string(),
-                //     new_index
-                // );
-                lst.push(decoded_value);
-                i = new_index;
-            }
+fn decode_base32(input: &str) -> Option<Vec<u8>> {
+    let mut bits = 0u64;
+    let mut bit_count = 0;
+    let mut out = Vec::new();
+    for c in input.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        bits = (bits << 5) | value as u64;
+        bit_count += 5;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            out.push((bits >> bit_count) as u8);
         }
+    }
+    Some(out)
+}
 
-        // println!("decoded list {:?}, end {}", lst, i + 1);
-        return (serde_json::Value::Array(lst), i + 1);
-    } else if encoded_value.chars().nth(index).unwrap() == 'd' {
-        // println!(" hello dict, index: {}, len {}", index, encoded_value.len());
-        let mut i = index + 1;
-
-        let mut dict: serde_json::Map<String, serde_json::Value> = serde_json::Map::new();
-
-        while i < encoded_value.len() {
-            if encoded_value.chars().nth(i).unwrap() == 'e' {
-                break;
-            } else {
-                let (decoded_key, new_index) = decode_bencoded_value(encoded_value, i);
-                let (decoded_value, new_index) = decode_bencoded_value(encoded_value, new_index);
-                dict.insert(decoded_key.as_str().unwrap().to_string(), decoded_value);
-                i = new_index;
-            }
-        }
-        // println!("End dict {:?}, end {}", dict, i + 1);
-        return (serde_json::Value::Object(dict), i + 1);
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
     } else {
-        panic!("Not implemented")
+        format!("{:.1} {}", value, UNITS[unit])
     }
 }
 
-pub fn decode_magnet_link(magnet_link: &str) -> (String, String, String) {
-    let split_values = magnet_link
-        .split('?')
-        .nth(1)
-        .unwrap()
-        .split('&')
-        .collect::<Vec<_>>();
-
-    let info_hash = split_values[0]
-        .split("=")
-        .nth(1)
-        .unwrap()
-        .split(":")
-        .nth(2)
-        .unwrap();
-    let file_name = split_values[1].split("=").nth(1).unwrap();
-    let tracker = split_values[2].split("=").nth(1).unwrap();
-
-    let tracker_decoded = urlencoding::decode(tracker).unwrap();
-    (
-        tracker_decoded.to_string(),
-        info_hash.to_string(),
-        file_name.to_string(),
+/// Formats seconds since the Unix epoch as `YYYY-MM-DD HH:MM:SS UTC`.
+pub fn format_unix_timestamp(timestamp: i64) -> String {
+    let days = timestamp.div_euclid(86_400);
+    let seconds = timestamp.rem_euclid(86_400);
+
+    // Civil-from-days conversion for the proleptic Gregorian calendar.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_bytes_and_timestamps() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1024), "1.0 KiB");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(1024 * 1024), "1.0 MiB");
+        assert_eq!(format_bytes(5 << 40), "5.0 TiB");
+        assert_eq!(format_bytes(2048 << 40), "2048.0 TiB");
+
+        assert_eq!(format_unix_timestamp(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_unix_timestamp(-1), "1969-12-31 23:59:59 UTC");
+        // Leap days, including the 400-year rule
+        assert_eq!(
+            format_unix_timestamp(951_782_400),
+            "2000-02-29 00:00:00 UTC"
+        );
+        assert_eq!(
+            format_unix_timestamp(1_709_251_199),
+            "2024-02-29 23:59:59 UTC"
+        );
+        assert_eq!(
+            format_unix_timestamp(1_709_251_200),
+            "2024-03-01 00:00:00 UTC"
+        );
+    }
+}
@@ -0,0 +1,829 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf, WriteHalf},
+    net::UdpSocket,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        watch,
+    },
+    time::{sleep_until, timeout, Instant},
+};
+
+use crate::error::{Error, Result};
+
+const VERSION: u8 = 1;
+const HEADER_LENGTH: usize = 20;
+const MAX_PAYLOAD: usize = 1200;
+const RECEIVE_WINDOW: u32 = 1024 * 1024;
+const STREAM_BUFFER: usize = 256 * 1024;
+
+/// LEDBAT aims to add no more than this much queueing delay to the path.
+const TARGET_DELAY_MICROS: f64 = 100_000.0;
+const MAX_WINDOW_GAIN: f64 = 3000.0;
+const MIN_WINDOW: f64 = 2.0 * MAX_PAYLOAD as f64;
+const BASE_DELAY_LIFETIME: Duration = Duration::from_secs(120);
+
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// An ST_STATE goes out this often, so a peer with nothing to send, e.g. a
+/// choked one, doesn't hit `IDLE_TIMEOUT`.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(20);
+const MAX_TRANSMISSIONS: u32 = 8;
+/// A SYN is sent this many times, waiting one more `SYN_TIMEOUT` after each,
+/// so `connect` gives up after 3 seconds.
+const SYN_ATTEMPTS: u32 = 3;
+const SYN_TIMEOUT: Duration = Duration::from_millis(500);
+const DUPLICATE_ACKS: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PacketType {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+#[derive(Debug, Clone)]
+struct Packet {
+    kind: PacketType,
+    connection_id: u16,
+    timestamp: u32,
+    timestamp_diff: u32,
+    window: u32,
+    seq_nr: u16,
+    ack_nr: u16,
+    payload: Vec<u8>,
+}
+
+impl Packet {
+    fn parse(bytes: &[u8]) -> Option<Packet> {
+        if bytes.len() < HEADER_LENGTH || bytes[0] & 0x0f != VERSION {
+            return None;
+        }
+        let kind = match bytes[0] >> 4 {
+            0 => PacketType::Data,
+            1 => PacketType::Fin,
+            2 => PacketType::State,
+            3 => PacketType::Reset,
+            4 => PacketType::Syn,
+            _ => return None,
+        };
+        let u16_at = |at: usize| u16::from_be_bytes([bytes[at], bytes[at + 1]]);
+        let u32_at = |at: usize| {
+            u32::from_be_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+        };
+
+        // Extensions (e.g. selective acks) are skipped
+        let mut position = HEADER_LENGTH;
+        let mut extension = bytes[1];
+        while extension != 0 {
+            let header = bytes.get(position..position + 2)?;
+            extension = header[0];
+            position += 2 + header[1] as usize;
+        }
+
+        Some(Packet {
+            kind,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_diff: u32_at(8),
+            window: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            payload: bytes.get(position..)?.to_vec(),
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LENGTH + self.payload.len());
+        bytes.push((self.kind as u8) << 4 | VERSION);
+        bytes.push(0);
+        bytes.extend(self.connection_id.to_be_bytes());
+        bytes.extend(self.timestamp.to_be_bytes());
+        bytes.extend(self.timestamp_diff.to_be_bytes());
+        bytes.extend(self.window.to_be_bytes());
+        bytes.extend(self.seq_nr.to_be_bytes());
+        bytes.extend(self.ack_nr.to_be_bytes());
+        bytes.extend(&self.payload);
+        bytes
+    }
+}
+
+fn now_micros() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_micros() as u32)
+        .unwrap_or(0)
+}
+
+/// Unmaps IPv4 addresses a dual-stack socket reports as `::ffff:a.b.c.d`.
+fn canonical(address: SocketAddr) -> SocketAddr {
+    SocketAddr::new(address.ip().to_canonical(), address.port())
+}
+
+/// Whether `seq_nr` is at or before `ack_nr`, allowing for wraparound.
+fn is_acked(seq_nr: u16, ack_nr: u16) -> bool {
+    ack_nr.wrapping_sub(seq_nr) < 0x8000
+}
+
+struct Shared {
+    socket: Arc<UdpSocket>,
+    /// Dropped with the last handle or connection, which ends the receive
+    /// loop.
+    _alive: watch::Sender<()>,
+    connections: Mutex<HashMap<(SocketAddr, u16), Sender<Packet>>>,
+    listening: AtomicBool,
+    incoming_tx: Sender<(UtpStream, SocketAddr)>,
+    incoming_rx: tokio::sync::Mutex<Receiver<(UtpStream, SocketAddr)>>,
+}
+
+impl Shared {
+    async fn send(&self, remote: SocketAddr, packet: &Packet) {
+        // A dual-stack socket only takes IPv6 addresses, IPv4 ones mapped
+        let remote = match remote {
+            SocketAddr::V4(v4) if self.socket.local_addr().is_ok_and(|local| local.is_ipv6()) => {
+                SocketAddr::from((v4.ip().to_ipv6_mapped(), v4.port()))
+            }
+            _ => remote,
+        };
+        let _ = self.socket.send_to(&packet.to_bytes(), remote).await;
+    }
+
+    fn register(
+        self: &Arc<Self>,
+        remote: SocketAddr,
+        connection_id: u16,
+    ) -> Option<(Registration, Receiver<Packet>)> {
+        let mut connections = self.connections.lock().unwrap();
+        if connections.contains_key(&(remote, connection_id)) {
+            return None;
+        }
+        let (packet_tx, packet_rx) = mpsc::channel(256);
+        connections.insert((remote, connection_id), packet_tx);
+        let registration = Registration {
+            shared: self.clone(),
+            key: (remote, connection_id),
+        };
+        Some((registration, packet_rx))
+    }
+}
+
+/// A connection's entry in [`Shared::connections`], removed when this is
+/// dropped: when the connection ends, or when `connect` fails or is
+/// cancelled mid-handshake.
+struct Registration {
+    shared: Arc<Shared>,
+    key: (SocketAddr, u16),
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.shared.connections.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// A UDP socket multiplexing any number of uTP connections.
+#[derive(Clone)]
+pub struct UtpSocket {
+    shared: Arc<Shared>,
+}
+
+impl UtpSocket {
+    pub async fn bind(address: SocketAddr) -> Result<UtpSocket> {
+        Self::from_socket(UdpSocket::bind(address).await?)
+    }
+
+    /// Binds `[::]:port` accepting both IPv6 and IPv4 peers, or only IPv4 on
+    /// hosts without IPv6.
+    pub fn bind_dual_stack(port: u16) -> Result<UtpSocket> {
+        let socket = match Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP)) {
+            Ok(socket) => {
+                socket.set_only_v6(false)?;
+                socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+                socket
+            }
+            Err(_) => {
+                let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+                socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)).into())?;
+                socket
+            }
+        };
+        socket.set_nonblocking(true)?;
+        Self::from_socket(UdpSocket::from_std(socket.into())?)
+    }
+
+    fn from_socket(socket: UdpSocket) -> Result<UtpSocket> {
+        let (incoming_tx, incoming_rx) = mpsc::channel(32);
+        let socket = Arc::new(socket);
+        let (alive_tx, alive_rx) = watch::channel(());
+        let shared = Arc::new(Shared {
+            socket: socket.clone(),
+            _alive: alive_tx,
+            connections: Mutex::new(HashMap::new()),
+            listening: AtomicBool::new(false),
+            incoming_tx,
+            incoming_rx: tokio::sync::Mutex::new(incoming_rx),
+        });
+        tokio::spawn(receive_loop(socket, Arc::downgrade(&shared), alive_rx));
+        Ok(UtpSocket { shared })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.shared.socket.local_addr()?)
+    }
+
+    pub async fn connect(&self, remote: SocketAddr) -> Result<UtpStream> {
+        let remote = canonical(remote);
+        let (receive_id, registration, mut packet_rx) = loop {
+            let receive_id = rand::random::<u16>();
+            if let Some((registration, packet_rx)) = self.shared.register(remote, receive_id) {
+                break (receive_id, registration, packet_rx);
+            }
+        };
+        let mut connection = Connection::new(
+            self.shared.clone(),
+            remote,
+            receive_id.wrapping_add(1),
+            1,
+            0,
+        );
+
+        let syn = connection.packet(PacketType::Syn, Vec::new());
+        let syn = Packet {
+            connection_id: receive_id,
+            ..syn
+        };
+        let mut state = None;
+        for attempt in 0..SYN_ATTEMPTS {
+            self.shared.send(remote, &syn).await;
+            let wait = SYN_TIMEOUT * (attempt + 1);
+            match timeout(wait, packet_rx.recv()).await {
+                Ok(Some(packet)) if packet.kind == PacketType::State => {
+                    state = Some(packet);
+                    break;
+                }
+                Ok(Some(_)) | Ok(None) => break,
+                Err(_) => continue,
+            }
+        }
+        let Some(state) = state else {
+            return Err(Error::Peer(format!("No uTP response from {}", remote)));
+        };
+
+        connection.seq_nr = syn.seq_nr.wrapping_add(1);
+        connection.ack_nr = state.seq_nr.wrapping_sub(1);
+        connection.peer_window = state.window;
+        Ok(connection.spawn(packet_rx, registration))
+    }
+
+    /// Starts answering incoming SYNs; until then they are reset.
+    pub fn listen(&self) {
+        self.shared.listening.store(true, Ordering::Relaxed);
+    }
+
+    /// Waits for the next incoming uTP connection.
+    pub async fn accept(&self) -> Result<(UtpStream, SocketAddr)> {
+        self.listen();
+        self.shared
+            .incoming_rx
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| Error::Peer("uTP socket closed".into()))
+    }
+}
+
+async fn receive_loop(
+    socket: Arc<UdpSocket>,
+    shared: Weak<Shared>,
+    mut alive: watch::Receiver<()>,
+) {
+    let mut buffer = vec![0u8; 65536];
+    loop {
+        let received = tokio::select! {
+            received = socket.recv_from(&mut buffer) => received,
+            _ = alive.changed() => return,
+        };
+        let Ok((length, remote)) = received else {
+            continue;
+        };
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        let remote = canonical(remote);
+        let Some(packet) = Packet::parse(&buffer[..length]) else {
+            continue;
+        };
+
+        let key = match packet.kind {
+            // A repeated SYN belongs to the connection it already created
+            PacketType::Syn => (remote, packet.connection_id.wrapping_add(1)),
+            _ => (remote, packet.connection_id),
+        };
+        let packet_tx = shared.connections.lock().unwrap().get(&key).cloned();
+        if let Some(packet_tx) = packet_tx {
+            // Like UDP itself, drop packets when the connection falls behind
+            let _ = packet_tx.try_send(packet);
+            continue;
+        }
+
+        if packet.kind == PacketType::Syn && shared.listening.load(Ordering::Relaxed) {
+            accept_syn(&shared, remote, packet).await;
+        } else if packet.kind != PacketType::Reset {
+            let reset = Packet {
+                kind: PacketType::Reset,
+                connection_id: packet.connection_id,
+                timestamp: now_micros(),
+                timestamp_diff: 0,
+                window: 0,
+                seq_nr: 0,
+                ack_nr: packet.seq_nr,
+                payload: Vec::new(),
+            };
+            shared.send(remote, &reset).await;
+        }
+    }
+}
+
+async fn accept_syn(shared: &Arc<Shared>, remote: SocketAddr, syn: Packet) {
+    let receive_id = syn.connection_id.wrapping_add(1);
+    let Some((registration, packet_rx)) = shared.register(remote, receive_id) else {
+        return;
+    };
+    let mut connection = Connection::new(
+        shared.clone(),
+        remote,
+        syn.connection_id,
+        rand::random(),
+        syn.seq_nr,
+    );
+    connection.peer_window = syn.window;
+    connection.reply_micros = now_micros().wrapping_sub(syn.timestamp);
+    connection.send_state().await;
+
+    let stream = connection.spawn(packet_rx, registration);
+    // If nobody keeps up with accepting, dropping the stream closes it
+    let _ = shared.incoming_tx.try_send((stream, remote));
+}
+
+/// One end of a uTP connection. The protocol runs in a background task; this
+/// is the application's side of the byte stream it exchanges data through.
+pub struct UtpStream {
+    inner: DuplexStream,
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+struct InFlight {
+    packet: Packet,
+    sent_at: Instant,
+    transmissions: u32,
+}
+
+enum Event {
+    Packet(Option<Packet>),
+    Outgoing(io::Result<usize>),
+    Timeout,
+    Idle,
+    KeepAlive,
+}
+
+struct Connection {
+    shared: Arc<Shared>,
+    remote: SocketAddr,
+    send_id: u16,
+    /// Sequence number of the next packet we send.
+    seq_nr: u16,
+    /// Last in-order sequence number received.
+    ack_nr: u16,
+    in_flight: VecDeque<InFlight>,
+    /// Received packets not yet written in order, at most a receive window.
+    out_of_order: HashMap<u16, Packet>,
+    out_of_order_bytes: usize,
+    window: f64,
+    peer_window: u32,
+    reply_micros: u32,
+    base_delay: Option<(u32, Instant)>,
+    rtt: Option<(Duration, Duration)>,
+    timeout: Duration,
+    duplicate_acks: u32,
+    fin_sent: bool,
+    fin_received: Option<u16>,
+    remote_closed: bool,
+}
+
+impl Connection {
+    fn new(
+        shared: Arc<Shared>,
+        remote: SocketAddr,
+        send_id: u16,
+        seq_nr: u16,
+        ack_nr: u16,
+    ) -> Self {
+        Connection {
+            shared,
+            remote,
+            send_id,
+            seq_nr,
+            ack_nr,
+            in_flight: VecDeque::new(),
+            out_of_order: HashMap::new(),
+            out_of_order_bytes: 0,
+            window: MIN_WINDOW * 2.0,
+            peer_window: RECEIVE_WINDOW,
+            reply_micros: 0,
+            base_delay: None,
+            rtt: None,
+            timeout: INITIAL_TIMEOUT,
+            duplicate_acks: 0,
+            fin_sent: false,
+            fin_received: None,
+            remote_closed: false,
+        }
+    }
+
+    fn spawn(self, packet_rx: Receiver<Packet>, registration: Registration) -> UtpStream {
+        let (application, ours) = tokio::io::duplex(STREAM_BUFFER);
+        tokio::spawn(async move {
+            let _ = self.run(ours, packet_rx).await;
+            drop(registration);
+        });
+        UtpStream { inner: application }
+    }
+
+    async fn run(mut self, stream: DuplexStream, mut packet_rx: Receiver<Packet>) -> Result<()> {
+        let (mut outgoing, mut incoming) = tokio::io::split(stream);
+        let mut buffer = vec![0u8; MAX_PAYLOAD];
+        let mut outgoing_closed = false;
+        let mut last_received = Instant::now();
+        let mut keepalive_at = Instant::now() + KEEPALIVE_INTERVAL;
+
+        loop {
+            if self.fin_sent && self.in_flight.is_empty() && self.remote_closed {
+                return Ok(());
+            }
+
+            let can_send =
+                !outgoing_closed && self.bytes_in_flight() + MAX_PAYLOAD <= self.send_window();
+            let retransmit_at = self
+                .in_flight
+                .front()
+                .map(|oldest| oldest.sent_at + self.timeout);
+
+            let event = tokio::select! {
+                packet = packet_rx.recv() => Event::Packet(packet),
+                read = outgoing.read(&mut buffer), if can_send => Event::Outgoing(read),
+                _ = sleep_until(retransmit_at.unwrap_or_else(Instant::now)), if retransmit_at.is_some() => Event::Timeout,
+                _ = sleep_until(last_received + IDLE_TIMEOUT) => Event::Idle,
+                _ = sleep_until(keepalive_at) => Event::KeepAlive,
+            };
+
+            match event {
+                Event::Packet(None) | Event::Idle => {
+                    return Err(Error::Peer("uTP connection timed out".into()))
+                }
+                Event::Packet(Some(packet)) => {
+                    last_received = Instant::now();
+                    self.on_packet(packet, &mut incoming).await?;
+                }
+                Event::Outgoing(Ok(0)) | Event::Outgoing(Err(_)) => {
+                    outgoing_closed = true;
+                    self.send_in_flight(PacketType::Fin, Vec::new()).await;
+                    self.fin_sent = true;
+                }
+                Event::Outgoing(Ok(length)) => {
+                    self.send_in_flight(PacketType::Data, buffer[..length].to_vec())
+                        .await;
+                }
+                Event::Timeout => self.on_timeout().await?,
+                Event::KeepAlive => {
+                    keepalive_at = Instant::now() + KEEPALIVE_INTERVAL;
+                    self.send_state().await;
+                }
+            }
+        }
+    }
+
+    fn bytes_in_flight(&self) -> usize {
+        self.in_flight
+            .iter()
+            .map(|sent| sent.packet.payload.len())
+            .sum()
+    }
+
+    fn send_window(&self) -> usize {
+        (self.window as usize).min(self.peer_window as usize)
+    }
+
+    fn packet(&self, kind: PacketType, payload: Vec<u8>) -> Packet {
+        Packet {
+            kind,
+            connection_id: self.send_id,
+            timestamp: now_micros(),
+            timestamp_diff: self.reply_micros,
+            window: RECEIVE_WINDOW,
+            seq_nr: self.seq_nr,
+            ack_nr: self.ack_nr,
+            payload,
+        }
+    }
+
+    async fn send_state(&self) {
+        let state = self.packet(PacketType::State, Vec::new());
+        self.shared.send(self.remote, &state).await;
+    }
+
+    async fn send_in_flight(&mut self, kind: PacketType, payload: Vec<u8>) {
+        let packet = self.packet(kind, payload);
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.shared.send(self.remote, &packet).await;
+        self.in_flight.push_back(InFlight {
+            packet,
+            sent_at: Instant::now(),
+            transmissions: 1,
+        });
+    }
+
+    async fn retransmit_oldest(&mut self) {
+        let timestamp_diff = self.reply_micros;
+        let ack_nr = self.ack_nr;
+        if let Some(oldest) = self.in_flight.front_mut() {
+            oldest.packet.timestamp = now_micros();
+            oldest.packet.timestamp_diff = timestamp_diff;
+            oldest.packet.ack_nr = ack_nr;
+            oldest.sent_at = Instant::now();
+            oldest.transmissions += 1;
+            let packet = oldest.packet.clone();
+            self.shared.send(self.remote, &packet).await;
+        }
+    }
+
+    async fn on_timeout(&mut self) -> Result<()> {
+        if self
+            .in_flight
+            .front()
+            .is_some_and(|oldest| oldest.transmissions >= MAX_TRANSMISSIONS)
+        {
+            return Err(Error::Peer(format!(
+                "uTP peer {} stopped acknowledging",
+                self.remote
+            )));
+        }
+        self.window = MIN_WINDOW;
+        self.timeout = (self.timeout * 2).min(Duration::from_secs(30));
+        self.retransmit_oldest().await;
+        Ok(())
+    }
+
+    async fn on_packet(
+        &mut self,
+        packet: Packet,
+        incoming: &mut WriteHalf<DuplexStream>,
+    ) -> Result<()> {
+        self.peer_window = packet.window;
+        self.reply_micros = now_micros().wrapping_sub(packet.timestamp);
+
+        match packet.kind {
+            PacketType::Reset => {
+                return Err(Error::Peer(format!(
+                    "uTP connection reset by {}",
+                    self.remote
+                )))
+            }
+            PacketType::Syn => {
+                self.send_state().await;
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        self.on_ack(packet.ack_nr, packet.timestamp_diff).await;
+
+        if packet.kind == PacketType::State {
+            return Ok(());
+        }
+        if packet.kind == PacketType::Fin {
+            self.fin_received = Some(packet.seq_nr);
+        }
+        // Packets beyond the window we advertise are dropped; the peer resends
+        // them once it sees our acks
+        let ahead = packet.seq_nr.wrapping_sub(self.ack_nr) as usize;
+        let fits = ahead <= RECEIVE_WINDOW as usize / MAX_PAYLOAD
+            && self.out_of_order_bytes + packet.payload.len() <= RECEIVE_WINDOW as usize;
+        if !is_acked(packet.seq_nr, self.ack_nr) && (ahead == 1 || fits) {
+            self.out_of_order_bytes += packet.payload.len();
+            if let Some(replaced) = self.out_of_order.insert(packet.seq_nr, packet) {
+                self.out_of_order_bytes -= replaced.payload.len();
+            }
+        }
+        while let Some(next) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1)) {
+            self.out_of_order_bytes -= next.payload.len();
+            self.ack_nr = next.seq_nr;
+            // The application may have dropped its end; keep acking regardless
+            let _ = incoming.write_all(&next.payload).await;
+        }
+        if self.fin_received == Some(self.ack_nr) && !self.remote_closed {
+            self.remote_closed = true;
+            let _ = incoming.shutdown().await;
+        }
+        self.send_state().await;
+        Ok(())
+    }
+
+    async fn on_ack(&mut self, ack_nr: u16, delay_micros: u32) {
+        let mut acked_bytes = 0;
+        let mut acked_any = false;
+        while let Some(oldest) = self.in_flight.front() {
+            if !is_acked(oldest.packet.seq_nr, ack_nr) {
+                break;
+            }
+            let oldest = self.in_flight.pop_front().unwrap();
+            if oldest.transmissions == 1 {
+                self.update_rtt(oldest.sent_at.elapsed());
+            }
+            acked_bytes += oldest.packet.payload.len();
+            acked_any = true;
+        }
+
+        if acked_any {
+            self.duplicate_acks = 0;
+            self.update_window(acked_bytes, delay_micros);
+        } else if !self.in_flight.is_empty() {
+            self.duplicate_acks += 1;
+            if self.duplicate_acks == DUPLICATE_ACKS {
+                self.window = (self.window / 2.0).max(MIN_WINDOW);
+                self.retransmit_oldest().await;
+            }
+        }
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        let (rtt, variance) = match self.rtt {
+            None => (sample, sample / 2),
+            Some((rtt, variance)) => {
+                let delta = rtt.abs_diff(sample);
+                (rtt * 7 / 8 + sample / 8, variance * 3 / 4 + delta / 4)
+            }
+        };
+        self.rtt = Some((rtt, variance));
+        self.timeout = (rtt + variance * 4).max(MIN_TIMEOUT);
+    }
+
+    /// LEDBAT: grow the window while queueing delay is under target and
+    /// shrink it as the delay we add to the path exceeds it.
+    fn update_window(&mut self, acked_bytes: usize, delay_micros: u32) {
+        if acked_bytes == 0 || delay_micros == 0 {
+            return;
+        }
+        let base_delay = match self.base_delay {
+            Some((base, since))
+                if base <= delay_micros && since.elapsed() < BASE_DELAY_LIFETIME =>
+            {
+                base
+            }
+            _ => {
+                self.base_delay = Some((delay_micros, Instant::now()));
+                delay_micros
+            }
+        };
+        let queueing_delay = (delay_micros - base_delay) as f64;
+        let off_target = (TARGET_DELAY_MICROS - queueing_delay) / TARGET_DELAY_MICROS;
+        let window_factor = acked_bytes as f64 / self.window.max(acked_bytes as f64);
+        self.window = (self.window + MAX_WINDOW_GAIN * off_target * window_factor).max(MIN_WINDOW);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_utp_transfer() {
+        let server = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let client = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let server_address = server.local_addr().unwrap();
+        server.listen();
+
+        let data = (0..200_000u32).map(|i| i as u8).collect::<Vec<_>>();
+        let expected = data.clone();
+        let echo = tokio::spawn(async move {
+            let (mut stream, _) = server.accept().await.unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            stream.write_all(b"done").await.unwrap();
+            stream.shutdown().await.unwrap();
+            received
+        });
+
+        let mut stream = client.connect(server_address).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await.unwrap();
+
+        assert_eq!(reply, b"done");
+        assert_eq!(echo.await.unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_connect_unregisters() {
+        let client = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        // Bound but never answering, like a peer that only speaks TCP
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let silent_address = silent.local_addr().unwrap();
+
+        let connect = client.connect(silent_address);
+        assert!(timeout(SYN_TIMEOUT / 2, connect).await.is_err());
+        assert!(client.shared.connections.lock().unwrap().is_empty());
+
+        assert!(client.connect(silent_address).await.is_err());
+        assert!(client.shared.connections.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_connection_stays_open() {
+        let server = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let client = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let server_address = server.local_addr().unwrap();
+        server.listen();
+
+        let accept = tokio::spawn(async move { server.accept().await.unwrap().0 });
+        let mut stream = client.connect(server_address).await.unwrap();
+        let mut accepted = accept.await.unwrap();
+        tokio::time::sleep(IDLE_TIMEOUT * 3).await;
+
+        stream.write_all(b"still here").await.unwrap();
+        let mut received = [0; 10];
+        accepted.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"still here");
+    }
+
+    #[tokio::test]
+    async fn test_receive_loop_ends_with_the_socket() {
+        let socket = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let address = socket.local_addr().unwrap();
+        drop(socket);
+        // The port is only free once the loop has let go of the socket
+        timeout(Duration::from_secs(5), async {
+            while UdpSocket::bind(address).await.is_err() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+}
@@ -0,0 +1,432 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use reqwest::{header, Client, StatusCode, Url};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::{
+        mpsc::{Receiver, Sender},
+        Mutex,
+    },
+};
+
+use crate::{
+    error::{Error, Result},
+    events::{Event, EventBus},
+    hasher::bytes_to_hex,
+    parser::TorrentFile,
+    tcp::{PeerRequest, PeerResponse},
+};
+
+/// A web seed gives up after this many pieces in a row fail to download.
+const MAX_CONSECUTIVE_FAILURES: usize = 5;
+/// A web seed is dropped once this many of its pieces fail verification.
+const MAX_BAD_PIECES: usize = 3;
+
+/// A BEP 19 (`url-list`) HTTP or FTP server holding the torrent's files.
+pub struct WebSeed {
+    url: String,
+    client: Client,
+    /// Set once the server answers a range with the whole file; fetching
+    /// every piece from the start of its file would cost far too much.
+    ignores_ranges: AtomicBool,
+}
+
+impl WebSeed {
+    pub fn new(url: String) -> Self {
+        WebSeed {
+            url,
+            client: Client::new(),
+            ignores_ranges: AtomicBool::new(false),
+        }
+    }
+
+    pub fn ignores_ranges(&self) -> bool {
+        self.ignores_ranges.load(Ordering::Relaxed)
+    }
+
+    /// Downloads a piece by fetching the byte range of every file it overlaps.
+    pub async fn fetch_piece(
+        &self,
+        torrent_file: &TorrentFile,
+        piece_index: u32,
+    ) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(torrent_file.piece_length(piece_index) as usize);
+        for span in torrent_file.info.piece_spans(piece_index) {
+            if span.padding {
+                data.resize(data.len() + span.length as usize, 0);
+                continue;
+            }
+            let url = self.file_url(torrent_file.info.is_single_file(), &span.path)?;
+            let bytes = if url.scheme() == "ftp" {
+                ftp_fetch_range(&url, span.offset, span.length).await?
+            } else {
+                self.http_fetch_range(url, span.offset, span.length).await?
+            };
+            data.extend(bytes);
+        }
+        Ok(data)
+    }
+
+    /// BEP 19: single-file torrents use the URL as is unless it ends in `/`;
+    /// multi-file torrents append the torrent name and file path.
+    fn file_url(&self, single_file: bool, path: &[String]) -> Result<Url> {
+        let mut url = self.url.clone();
+        if !single_file || url.ends_with('/') {
+            if !url.ends_with('/') {
+                url.push('/');
+            }
+            let encoded = path
+                .iter()
+                .map(|component| urlencoding::encode(component).into_owned())
+                .collect::<Vec<_>>();
+            url.push_str(&encoded.join("/"));
+        }
+        Url::parse(&url).map_err(|e| Error::Peer(format!("Invalid web seed URL {}: {}", url, e)))
+    }
+
+    async fn http_fetch_range(&self, url: Url, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let mut response = self
+            .client
+            .get(url.clone())
+            .header(
+                header::RANGE,
+                format!("bytes={}-{}", offset, offset + length - 1),
+            )
+            .send()
+            .await
+            .map_err(|e| Error::Peer(format!("{} failed: {}", url, e)))?;
+
+        match response.status() {
+            StatusCode::PARTIAL_CONTENT => {}
+            // The start of the whole file costs no more than the range
+            StatusCode::OK if offset == 0 => {}
+            StatusCode::OK => {
+                self.ignores_ranges.store(true, Ordering::Relaxed);
+                return Err(Error::Peer(format!("{} ignores byte ranges", url)));
+            }
+            status => return Err(Error::Peer(format!("{} answered {}", url, status))),
+        }
+        let mut body = Vec::with_capacity(length as usize);
+        while body.len() < length as usize {
+            let Some(chunk) = response
+                .chunk()
+                .await
+                .map_err(|e| Error::Peer(format!("{} failed: {}", url, e)))?
+            else {
+                break;
+            };
+            let wanted = (length as usize - body.len()).min(chunk.len());
+            body.extend(&chunk[..wanted]);
+        }
+        if body.len() as u64 != length {
+            return Err(Error::Peer(format!("{} returned a short range", url)));
+        }
+        Ok(body)
+    }
+}
+
+/// Fetches `length` bytes from `offset` of an FTP file using passive mode and `REST`.
+async fn ftp_fetch_range(url: &Url, offset: u64, length: u64) -> Result<Vec<u8>> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| Error::Peer(format!("FTP URL without host: {}", url)))?;
+    let control = TcpStream::connect((host, url.port().unwrap_or(21))).await?;
+    let (read_half, mut write_half) = control.into_split();
+    let mut replies = BufReader::new(read_half);
+
+    expect_reply(&mut replies, &[220]).await?;
+    let user = match url.username() {
+        "" => "anonymous",
+        user => user,
+    };
+    write_half
+        .write_all(format!("USER {}\r\n", user).as_bytes())
+        .await?;
+    if expect_reply(&mut replies, &[230, 331]).await?.0 == 331 {
+        let password = url.password().unwrap_or("anonymous@");
+        write_half
+            .write_all(format!("PASS {}\r\n", password).as_bytes())
+            .await?;
+        expect_reply(&mut replies, &[230]).await?;
+    }
+    write_half.write_all(b"TYPE I\r\n").await?;
+    expect_reply(&mut replies, &[200]).await?;
+
+    write_half.write_all(b"PASV\r\n").await?;
+    let (_, message) = expect_reply(&mut replies, &[227]).await?;
+    let data_address = parse_pasv(&message)?;
+    let mut data = TcpStream::connect(data_address).await?;
+
+    write_half
+        .write_all(format!("REST {}\r\n", offset).as_bytes())
+        .await?;
+    expect_reply(&mut replies, &[350]).await?;
+    let path = urlencoding::decode(url.path())
+        .map_err(|e| Error::Peer(format!("Invalid FTP path {}: {}", url.path(), e)))?
+        .into_owned();
+    write_half
+        .write_all(format!("RETR {}\r\n", path).as_bytes())
+        .await?;
+    expect_reply(&mut replies, &[125, 150]).await?;
+
+    let mut buffer = vec![0u8; length as usize];
+    data.read_exact(&mut buffer).await?;
+    Ok(buffer)
+}
+
+async fn expect_reply<R: AsyncBufReadExt + Unpin>(
+    replies: &mut R,
+    expected: &[u16],
+) -> Result<(u16, String)> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if replies.read_line(&mut line).await? == 0 {
+            return Err(Error::Peer("FTP server closed the connection".into()));
+        }
+        // Multi-line replies end with a line of the form "123 text"
+        let bytes = line.as_bytes();
+        if bytes.len() >= 4 && bytes[..3].iter().all(u8::is_ascii_digit) && bytes[3] == b' ' {
+            break;
+        }
+    }
+    let code = line[..3]
+        .parse::<u16>()
+        .map_err(|e| Error::Peer(format!("Invalid FTP reply {}: {}", line.trim_end(), e)))?;
+    if !expected.contains(&code) {
+        return Err(Error::Peer(format!(
+            "Unexpected FTP reply: {}",
+            line.trim_end()
+        )));
+    }
+    Ok((code, line[4..].trim_end().to_string()))
+}
+
+/// Parses `Entering Passive Mode (h1,h2,h3,h4,p1,p2)`.
+fn parse_pasv(message: &str) -> Result<(String, u16)> {
+    let start = message
+        .find('(')
+        .ok_or_else(|| Error::Peer("Bad PASV reply".into()))?;
+    let end = message
+        .find(')')
+        .ok_or_else(|| Error::Peer("Bad PASV reply".into()))?;
+    let numbers = message[start + 1..end]
+        .split(',')
+        .map(|number| number.trim().parse::<u8>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| Error::Peer("Bad PASV reply".into()))?;
+    if numbers.len() != 6 {
+        return Err(Error::Peer("Bad PASV reply".into()));
+    }
+    let host = format!(
+        "{}.{}.{}.{}",
+        numbers[0], numbers[1], numbers[2], numbers[3]
+    );
+    Ok((host, u16::from_be_bytes([numbers[4], numbers[5]])))
+}
+
+/// Serves piece requests from the shared queue alongside the peer workers.
+/// Failed pieces are answered with empty data so the download loop, which
+/// verifies every piece, queues them again for someone else, and why they
+/// failed is sent to `events` as a warning.
+///
+/// The worker stops when the server ignores byte ranges or serves
+/// [`MAX_BAD_PIECES`] pieces that don't verify, since it can't be banned
+/// like a peer.
+pub async fn web_seed_worker(
+    web_seed: WebSeed,
+    torrent_file: Arc<TorrentFile>,
+    response_tx: Sender<PeerResponse>,
+    request_rx: Arc<Mutex<Receiver<PeerRequest>>>,
+    events: EventBus,
+) {
+    let mut consecutive_failures = 0;
+    let mut bad_pieces = 0;
+    while consecutive_failures < MAX_CONSECUTIVE_FAILURES
+        && bad_pieces < MAX_BAD_PIECES
+        && !web_seed.ignores_ranges()
+    {
+        let req = {
+            let mut rx = request_rx.lock().await;
+            match rx.recv().await {
+                Some(req) => req,
+                None => break,
+            }
+        };
+
+        let PeerRequest::DowloadPiece { piece_index, .. } = req;
+        let data = match web_seed.fetch_piece(&torrent_file, piece_index).await {
+            Ok(data) => {
+                consecutive_failures = 0;
+                if !torrent_file.verify_piece(piece_index, &data) {
+                    bad_pieces += 1;
+                }
+                data
+            }
+            Err(e) => {
+                events.emit(Event::Warning {
+                    info_hash: bytes_to_hex(&torrent_file.info_hash()),
+                    message: format!(
+                        "Web seed {} failed piece {}: {}",
+                        web_seed.url, piece_index, e
+                    ),
+                });
+                consecutive_failures += 1;
+                Vec::new()
+            }
+        };
+        let response = PeerResponse {
+            data,
+            piece: piece_index,
+            peer: None,
+        };
+        if response_tx.send(response).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{path::Path, time::Duration};
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::create::{create_torrent, CreateOptions};
+
+    /// Serves the files under `dir` over HTTP, honouring `Range: bytes=a-b`,
+    /// and returns its URL to use as a web seed.
+    pub(crate) async fn spawn_fake_seed(dir: &Path) -> String {
+        spawn_seed(dir, true).await
+    }
+
+    /// Like [`spawn_fake_seed`], but always sends whole files.
+    async fn spawn_fake_seed_ignoring_ranges(dir: &Path) -> String {
+        spawn_seed(dir, false).await
+    }
+
+    async fn spawn_seed(dir: &Path, ranges: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let dir = dir.to_path_buf();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let mut stream = BufReader::new(stream);
+                let mut request = String::new();
+                let _ = stream.read_line(&mut request).await;
+                let mut range = None;
+                let mut header = String::new();
+                while stream.read_line(&mut header).await.unwrap_or(0) > 2 {
+                    let value = header.trim_end().strip_prefix("range: bytes=");
+                    if let Some(value) = value.filter(|_| ranges) {
+                        let (start, end) = value.split_once('-').unwrap();
+                        range = Some((start.parse().unwrap(), end.parse::<usize>().unwrap()));
+                    }
+                    header.clear();
+                }
+                let path = request.split_whitespace().nth(1).unwrap_or_default();
+                let path = urlencoding::decode(&path[1..]).unwrap().into_owned();
+                let body = std::fs::read(dir.join(path)).unwrap_or_default();
+                let (status, body) = match range {
+                    Some((start, end)) => ("206 Partial Content", &body[start..=end]),
+                    None => ("200 OK", &body[..]),
+                };
+                let head = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    body.len()
+                );
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(body).await;
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn test_file_url_and_pasv() {
+        let path = vec![String::from("release"), String::from("a b.txt")];
+        let web_seed = WebSeed::new(String::from("http://mirror/files"));
+        assert_eq!(
+            web_seed.file_url(false, &path).unwrap().as_str(),
+            "http://mirror/files/release/a%20b.txt"
+        );
+
+        let single = vec![String::from("a.iso")];
+        let web_seed = WebSeed::new(String::from("http://mirror/a.iso"));
+        assert_eq!(
+            web_seed.file_url(true, &single).unwrap().as_str(),
+            "http://mirror/a.iso"
+        );
+
+        assert_eq!(
+            parse_pasv("Entering Passive Mode (10,0,0,1,4,1)").unwrap(),
+            (String::from("10.0.0.1"), 1025)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_drops_mirrors_that_ignore_ranges_or_serve_bad_pieces() {
+        let dir = tempfile::tempdir().unwrap();
+        let content = (0..100_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        std::fs::write(dir.path().join("a.bin"), &content).unwrap();
+        let bad = dir.path().join("bad");
+        std::fs::create_dir(&bad).unwrap();
+        std::fs::write(bad.join("a.bin"), vec![0u8; content.len()]).unwrap();
+        let torrent_file =
+            Arc::new(create_torrent(&dir.path().join("a.bin"), &CreateOptions::default()).unwrap());
+
+        // Runs a worker over six queued pieces; returns what it answered
+        // and whether each answer verifies
+        let run = |url: String| {
+            let torrent_file = torrent_file.clone();
+            async move {
+                let (request_tx, request_rx) = tokio::sync::mpsc::channel(10);
+                let (response_tx, mut response_rx) = tokio::sync::mpsc::channel(10);
+                for piece_index in 0..6 {
+                    let piece_length = torrent_file.piece_length(piece_index);
+                    let request = PeerRequest::DowloadPiece {
+                        piece_index,
+                        piece_length,
+                    };
+                    request_tx.send(request).await.unwrap();
+                }
+                drop(request_tx);
+                let worker = web_seed_worker(
+                    WebSeed::new(url),
+                    torrent_file.clone(),
+                    response_tx,
+                    Arc::new(Mutex::new(request_rx)),
+                    EventBus::default(),
+                );
+                tokio::time::timeout(Duration::from_secs(10), worker)
+                    .await
+                    .unwrap();
+                let mut served = Vec::new();
+                while let Ok(response) = response_rx.try_recv() {
+                    served.push((
+                        response.piece,
+                        torrent_file.verify_piece(response.piece, &response.data),
+                    ));
+                }
+                served
+            }
+        };
+
+        let served = run(spawn_fake_seed(dir.path()).await).await;
+        assert_eq!(
+            served,
+            (0..6).map(|piece| (piece, true)).collect::<Vec<_>>()
+        );
+        // The first piece starts the file, so the whole file is fine for it
+        let served = run(spawn_fake_seed_ignoring_ranges(dir.path()).await).await;
+        assert_eq!(served, vec![(0, true), (1, false)]);
+        let served = run(spawn_fake_seed(&bad).await).await;
+        assert_eq!(served, vec![(0, false), (1, false), (2, false)]);
+    }
+}